@@ -32,3 +32,9 @@ pub const CARGO_DIFFTESTS_VERSION_FILENAME: &str = "cargo_difftests_version";
 pub const CARGO_DIFFTESTS_SELF_JSON_FILENAME: &str = "self.json";
 pub const CARGO_DIFFTESTS_TEST_BINARY_FILENAME: &str = "test_binary";
 pub const CARGO_DIFFTESTS_TEST_NAME_FILENAME: &str = "test_name";
+pub const CARGO_DIFFTESTS_TEST_OUTCOME_FILENAME: &str = "test_outcome.json";
+pub const CARGO_DIFFTESTS_GIT_STATE_FILENAME: &str = "git_state.json";
+pub const CARGO_DIFFTESTS_TRACKED_FILES_FILENAME: &str = "tracked_files";
+pub const CARGO_DIFFTESTS_ENV_FINGERPRINT_FILENAME: &str = "env_fingerprint.json";
+pub const CARGO_DIFFTESTS_ALWAYS_RUN_FILENAME: &str = "always_run";
+pub const CARGO_DIFFTESTS_NEXTEST_BINARY_ID_FILENAME: &str = "nextest_binary_id";
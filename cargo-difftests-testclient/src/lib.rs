@@ -28,3 +28,49 @@ pub fn write_desc<T: serde::Serialize>(desc: T) -> std::io::Result<()> {
 
     Ok(())
 }
+
+/// Declares that the current test depends on the given file (like a fixture,
+/// or a file read with `include_str!`), even though it contains no code
+/// that the test executes.
+///
+/// The file is then checked by the analysis just like the source files of
+/// the code the test executed, so changing it makes the test dirty.
+///
+/// Relative paths are relative to the current directory. Does nothing if
+/// the test is not run by `cargo difftests`.
+pub fn track_file<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let Some(tmpdir) = std::env::var_os("CARGO_DIFFTEST_DIR") else {
+        return Ok(());
+    };
+    let dir = std::path::Path::new(&tmpdir);
+
+    let path = std::env::current_dir()?.join(path);
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(cargo_difftests_core::CARGO_DIFFTESTS_TRACKED_FILES_FILENAME))?;
+
+    writeln!(file, "{}", path.display())?;
+
+    Ok(())
+}
+
+/// Marks the current test as one that must always run (like a smoke test,
+/// or a test that depends on something the coverage cannot see, like the
+/// network), so the analysis always considers it dirty, whatever changed.
+///
+/// Does nothing if the test is not run by `cargo difftests`.
+pub fn always_run() -> std::io::Result<()> {
+    let Some(tmpdir) = std::env::var_os("CARGO_DIFFTEST_DIR") else {
+        return Ok(());
+    };
+    let dir = std::path::Path::new(&tmpdir);
+
+    std::fs::write(
+        dir.join(cargo_difftests_core::CARGO_DIFFTESTS_ALWAYS_RUN_FILENAME),
+        "",
+    )
+}
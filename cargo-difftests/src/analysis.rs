@@ -30,6 +30,14 @@
 //! method can be called to finish the analysis and get the result, dropping the
 //! [`AnalysisContext`].
 //!
+//! If the reasons for which the test was considered dirty are also needed, the
+//! [`AnalysisContext::finish_analysis_with_report`] method can be used instead,
+//! which returns an [`AnalysisReport`] with the [`DirtyReason`]s.
+//!
+//! When analyzing many tests with [`DirtyAlgorithm::GitDiff`], the diff can be
+//! collected only once, into a [`GitDiffChangesCache`], by calling
+//! [`AnalysisContext::run_with_git_diff_cache`] for each test instead.
+//!
 //! # Examples
 //!
 //! ## Analyzing a difftest from coverage data
@@ -38,7 +46,8 @@
 //! # use std::path::{PathBuf, Path};
 //! # use cargo_difftests::{
 //! #     difftest::{Difftest, ExportProfdataConfig},
-//! #     analysis::{AnalysisConfig, AnalysisResult, DirtyAlgorithm},
+//! #     analysis::{AlwaysRun, AnalysisConfig, AnalysisResult, DirtyAlgorithm},
+//! #     path_filter::PathFilter,
 //! # };
 //! let mut difftest = Difftest::discover_from(PathBuf::from("difftest"), None)?;
 //! difftest.merge_profraw_files_into_profdata(false)?;
@@ -50,6 +59,9 @@
 //! analysis_context.run(&AnalysisConfig {
 //!     dirty_algorithm: DirtyAlgorithm::FileSystemMtimes,
 //!     error_on_invalid_config: true,
+//!     environment: None,
+//!     path_filter: PathFilter::default(),
+//!     always_run: AlwaysRun::default(),
 //! })?;
 //!
 //! let r = analysis_context.finish_analysis();
@@ -72,7 +84,8 @@
 //! # use cargo_difftests::{
 //! #     difftest::{Difftest, ExportProfdataConfig},
 //! #     index_data::{IndexDataCompilerConfig, TestIndex, IndexSize},
-//! #     analysis::{AnalysisConfig, AnalysisContext, AnalysisResult, DirtyAlgorithm},
+//! #     analysis::{AlwaysRun, AnalysisConfig, AnalysisContext, AnalysisResult, DirtyAlgorithm},
+//! #     path_filter::PathFilter,
 //! # };
 //! // compile the test index first
 //! let mut difftest = Difftest::discover_from(PathBuf::from("difftest"), None)?;
@@ -102,6 +115,9 @@
 //! analysis_context.run(&AnalysisConfig {
 //!     dirty_algorithm: DirtyAlgorithm::FileSystemMtimes,
 //!     error_on_invalid_config: true,
+//!     environment: None,
+//!     path_filter: PathFilter::default(),
+//!     always_run: AlwaysRun::default(),
 //! })?;
 //!
 //! let r = analysis_context.finish_analysis();
@@ -130,11 +146,57 @@
 //! It uses the mtime of one of the files generated by the `cargo_difftests_testclient::init`
 //! function to determine when the test was last ran.
 //!
+//! ## [`DirtyAlgorithm::ContentHash`]
+//!
+//! This algorithm compares the hashes of the contents of the files that were
+//! "touched" by the test, as recorded in the [`TestIndex`] when it was compiled,
+//! with the hashes of the contents of the same files, as they are now.
+//!
+//! Unlike [`DirtyAlgorithm::FileSystemMtimes`], it is not fooled by operations
+//! that only change the mtimes of the files (like `git checkout`, `touch`, or
+//! a fresh clone), and unlike [`DirtyAlgorithm::GitDiff`], it doesn't need a
+//! git repository at all, so it also works with indexes restored from a cache,
+//! on another machine.
+//!
+//! It can only be used with a [`TestIndex`], as the hashes are only computed
+//! when compiling the index.
+//!
+//! ## [`DirtyAlgorithm::RegionFingerprints`]
+//!
+//! This algorithm works at the level of regions instead of files. When the
+//! [`TestIndex`] is compiled with [`IndexSize::Full`], the source text of every
+//! region is fingerprinted (see the [`fingerprint`] module), and this algorithm
+//! checks whether the text of every region executed by the test can still be
+//! found somewhere in its file.
+//!
+//! Because it looks for the text anywhere in the file, it is not affected by
+//! edits elsewhere in the file that shift the lines of the region around, nor
+//! by changes in indentation. The text of a region is anchored in the lines
+//! around it, so that an edited region is not found again just because its
+//! old text also appears somewhere else in the file; editing the line right
+//! before or after a region therefore makes it dirty as well.
+//!
+//! It can only be used with a [`TestIndex`] compiled with [`IndexSize::Full`].
+//!
+//! [`IndexSize::Full`]: crate::index_data::IndexSize::Full
+//! [`fingerprint`]: crate::fingerprint
+//!
 //! ## [`DirtyAlgorithm::GitDiff`]
 //!
 //! For both [`GitDiffStrategy`]ies, this algorithm looks through the git diff
-//! between the working tree and the commit HEAD points to, or the commit
-//! commit in [`DirtyAlgorithm::GitDiff`] `commit` field if it is [`Some`].
+//! between the working tree and the commit in [`DirtyAlgorithm::GitDiff`]
+//! `commit` field if it is [`Some`], or otherwise the commit that was recorded
+//! in the [`GitState`] when the test was run, or if none was recorded, the
+//! commit HEAD points to.
+//!
+//! With [`GitDiffTarget::Head`], the diff is between the tree of that commit
+//! and the tree of HEAD instead, so only the committed changes are taken into
+//! account, and the working tree is ignored.
+//!
+//! Renames (and copies) are detected, so a file that was moved, even to
+//! another crate, is still matched with the path it had when the test was
+//! run, which is the path in the coverage data (see
+//! [`GitDiffFileChanges::indexed_path`]).
 //!
 //! ### With [`GitDiffStrategy::FilesOnly`]
 //!
@@ -142,7 +204,14 @@
 //!
 //! If any of the files that were changed in the diff are files that were
 //! "touched" by the test, then the test is considered dirty, and that is
-//! the result of the analysis.
+//! the result of the analysis. Moving a file counts as changing it.
+//!
+//! ### With [`GitDiffStrategy::Semantic`]
+//!
+//! Like [`GitDiffStrategy::FilesOnly`], but the hunks in Rust files that only
+//! change whitespace or comments (like the ones from running `rustfmt`, or
+//! editing doc comments) are ignored, see [`crate::semantic_diff`]. A file
+//! that is left without any other changes is not considered changed.
 //!
 //! ### With [`GitDiffStrategy::Hunks`]
 //!
@@ -163,12 +232,23 @@
 //! the hunks that were changed in the diff, then the test is considered dirty,
 //! and that is the result of the analysis.
 //!
-//! The way it achieves this is by looking at the ranges given by:
-//! - `(hunk.old_start..hunk.old_start + hunk.old_line_count)` from the hunk
-//! - `(region.l1..=region.l2)` from the region.
+//! The way it achieves this is by building a [`LineMapping`] from all the
+//! hunks of each file, which maps every old line (the line numbers the
+//! coverage data refers to) to the line it moved to, if it is still there.
+//! A region is untouched if all of its lines, and the lines right around
+//! it, moved by the same offset, regardless of the hunks earlier in the
+//! file. In terms of the hunks:
+//! - a hunk that removes or modifies the lines
+//!   `(hunk.old_start..hunk.old_start + hunk.old_line_count)` intersects the
+//!   region if that range intersects `(region.l1..=region.l2)`.
+//! - a hunk that only inserts lines (with an old line count of 0) does so
+//!   after the line `hunk.old_start`, and intersects the region if the lines
+//!   are inserted between two of the lines of the region, or right before or
+//!   right after it (see [`hunk_intersects_lines`] for why).
 //!
-//! If the intersection of these two ranges is not empty, then the region
-//! intersects with the hunk.
+//! The old line numbers in the hunks of a renamed file are the ones in the
+//! file before the rename, so only the parts of a moved file that were
+//! actually changed make the tests that touched them dirty.
 //!
 //! This is pretty error-prone, and in the [introductory blog post] there is
 //! an example of how this algorithm can fail, but if used properly, it has
@@ -176,21 +256,46 @@
 //! changes in specific parts of the code, and in the case of big files that
 //! can help a lot.
 //!
+//! ### With [`GitDiffStrategy::Functions`]
+//!
+//! This algorithm also looks at the hunks in the diff, but instead of the
+//! regions, it intersects them with the line spans of the functions that were
+//! executed by the test, and reports the names of the functions that changed.
+//!
+//! It is coarser than [`GitDiffStrategy::Hunks`], but it only needs a
+//! [`TestIndex`] compiled with [`IndexSize::Functions`], which is much smaller
+//! than a full one.
+//!
+//! [`IndexSize::Functions`]: crate::index_data::IndexSize::Functions
+//!
 //! [introductory blog post]: https://blog.dnbln.dev/posts/cargo-difftests/
+//!
+//! # Tracked files
+//!
+//! Tests can declare files they depend on, but that contain no code (like
+//! fixtures), with `cargo_difftests_testclient::track_file`. Those files
+//! count as "touched" by the test, and all the algorithms check them as a
+//! whole: the ones that look at regions, functions or hunks consider the
+//! test dirty if the file changed at all, with
+//! [`DirtyReason::TrackedFileChanged`].
 
 use std::cell::RefCell;
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt;
-use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::time::SystemTime;
 
-use git2::{DiffDelta, DiffHunk};
+use git2::DiffHunk;
 use log::{debug, info, warn};
 
 use crate::analysis_data::CoverageData;
-use crate::index_data::TestIndex;
+use crate::dependencies::{dependencies_of_files, Dependency};
+use crate::fingerprint::SourceFingerprints;
+use crate::index_data::{function_line_span, function_name, hash_file, IndexRegion, TestIndex};
+use crate::path_filter::{compile_globs, PathFilter};
+use crate::semantic_diff;
+use crate::difftest::{EnvFingerprint, GitState};
 use crate::{Difftest, DifftestsError, DifftestsResult};
 
 enum AnalysisContextInternal<'r> {
@@ -199,7 +304,7 @@ enum AnalysisContextInternal<'r> {
         profdata: CoverageData,
     },
     IndexData {
-        index: TestIndex,
+        index: Box<TestIndex>,
     },
 }
 
@@ -212,15 +317,17 @@ enum AnalysisContextInternal<'r> {
 /// or [`AnalysisContext::with_index_from_difftest`] associated functions.
 pub struct AnalysisContext<'r> {
     internal: AnalysisContextInternal<'r>,
-    result: AnalysisResult,
+    report: AnalysisReport,
 }
 
 impl AnalysisContext<'static> {
     /// Create a new context from a test index.
     pub fn from_index(index: TestIndex) -> Self {
         Self {
-            internal: AnalysisContextInternal::IndexData { index },
-            result: AnalysisResult::Clean,
+            internal: AnalysisContextInternal::IndexData {
+                index: Box::new(index),
+            },
+            report: AnalysisReport::default(),
         }
     }
 
@@ -245,7 +352,7 @@ impl<'r> AnalysisContext<'r> {
     pub(crate) fn new(difftest: &'r mut Difftest, profdata: CoverageData) -> Self {
         Self {
             internal: AnalysisContextInternal::DifftestWithCoverageData { difftest, profdata },
-            result: AnalysisResult::Clean,
+            report: AnalysisReport::default(),
         }
     }
 
@@ -277,9 +384,20 @@ impl<'r> AnalysisContext<'r> {
     ///
     /// This function should be called after [`AnalysisContext::run`].
     pub fn finish_analysis(self) -> AnalysisResult {
-        let r = self.result;
+        self.finish_analysis_with_report().result()
+    }
+
+    /// Finish the analysis, and return the [`AnalysisReport`], containing
+    /// the reasons for which the test is dirty, if it is.
+    ///
+    /// This function should be called after [`AnalysisContext::run`].
+    pub fn finish_analysis_with_report(self) -> AnalysisReport {
+        let r = self.report;
 
-        info!("Analysis finished with result: {r:?}");
+        info!("Analysis finished with result: {:?}", r.result());
+        for reason in &r.reasons {
+            debug!("Dirty because: {reason}");
+        }
 
         r
     }
@@ -294,6 +412,50 @@ impl<'r> AnalysisContext<'r> {
         }
     }
 
+    /// Gets the [`GitState`] of the repository when the test was run,
+    /// if it was recorded.
+    pub fn git_state(&self) -> Option<&GitState> {
+        match &self.internal {
+            AnalysisContextInternal::DifftestWithCoverageData { difftest, .. } => {
+                difftest.git_state()
+            }
+            AnalysisContextInternal::IndexData { index } => index.git_state.as_ref(),
+        }
+    }
+
+    /// Gets the [`EnvFingerprint`] of the environment the test was run in,
+    /// if it was recorded.
+    pub fn env_fingerprint(&self) -> Option<&EnvFingerprint> {
+        match &self.internal {
+            AnalysisContextInternal::DifftestWithCoverageData { difftest, .. } => {
+                difftest.env_fingerprint()
+            }
+            AnalysisContextInternal::IndexData { index } => index.env_fingerprint.as_ref(),
+        }
+    }
+
+    /// Checks whether the test failed the last time it was run.
+    pub fn test_failed(&self) -> bool {
+        match &self.internal {
+            AnalysisContextInternal::DifftestWithCoverageData { difftest, .. } => {
+                difftest.test_failed()
+            }
+            AnalysisContextInternal::IndexData { index } => index.test_failed(),
+        }
+    }
+
+    /// The [`DirtyReason::Forced`] of the test, if it must always run
+    /// (see [`AlwaysRun::forced_reason`]).
+    pub fn forced_reason(&self, always_run: &AlwaysRun) -> DifftestsResult<Option<DirtyReason>> {
+        Ok(match &self.internal {
+            AnalysisContextInternal::DifftestWithCoverageData { difftest, .. } => always_run
+                .forced_reason(&difftest.test_info()?.test_name, difftest.always_run()),
+            AnalysisContextInternal::IndexData { index } => {
+                always_run.forced_reason(&index.test_info.test_name, index.always_run)
+            }
+        })
+    }
+
     /// Gets an iterator over the regions that are covered by the test.
     ///
     /// This iterator does not filter the regions that were not touched, so it
@@ -323,9 +485,50 @@ impl<'r> AnalysisContext<'r> {
         }
     }
 
-    pub fn files(&self, include_registry_files: bool) -> BTreeSet<PathBuf> {
+    /// Gets the functions that were executed by the test.
+    ///
+    /// If using a [`TestIndex`] to run the analysis, it must have been compiled
+    /// with [`IndexSize::Functions`] or [`IndexSize::Full`], otherwise there will
+    /// be no functions.
+    ///
+    /// [`IndexSize::Functions`]: crate::index_data::IndexSize::Functions
+    /// [`IndexSize::Full`]: crate::index_data::IndexSize::Full
+    pub fn functions(&self) -> Vec<AnalysisFunction<'_>> {
         match &self.internal {
             AnalysisContextInternal::DifftestWithCoverageData { profdata, .. } => profdata
+                .data
+                .iter()
+                .flat_map(|it| &it.functions)
+                .filter(|fun| fun.count > 0)
+                .filter_map(|fun| {
+                    let (l1, l2) = function_line_span(fun)?;
+
+                    Some(AnalysisFunction {
+                        name: function_name(&fun.name),
+                        l1,
+                        l2,
+                        execution_count: fun.count,
+                        file_ref: &fun.filenames[0],
+                    })
+                })
+                .collect(),
+            AnalysisContextInternal::IndexData { index } => index
+                .functions
+                .iter()
+                .map(|fun| AnalysisFunction {
+                    name: &fun.name,
+                    l1: fun.l1,
+                    l2: fun.l2,
+                    execution_count: fun.count,
+                    file_ref: &index.files[fun.file_id],
+                })
+                .collect(),
+        }
+    }
+
+    pub fn files(&self, include_registry_files: bool) -> BTreeSet<PathBuf> {
+        match &self.internal {
+            AnalysisContextInternal::DifftestWithCoverageData { difftest, profdata } => profdata
                 .data
                 .iter()
                 .flat_map(|it| {
@@ -341,6 +544,7 @@ impl<'r> AnalysisContext<'r> {
                                 .map(PathBuf::clone)
                         })
                 })
+                .chain(difftest.tracked_files().iter().cloned())
                 .collect(),
             AnalysisContextInternal::IndexData { index } => index
                 .files
@@ -350,6 +554,45 @@ impl<'r> AnalysisContext<'r> {
                 .collect(),
         }
     }
+
+    /// Gets the files the test declared as its inputs, with
+    /// `cargo_difftests_testclient::track_file`.
+    ///
+    /// They are also included in [`AnalysisContext::files`].
+    pub fn tracked_files(&self) -> Vec<&Path> {
+        match &self.internal {
+            AnalysisContextInternal::DifftestWithCoverageData { difftest, .. } => difftest
+                .tracked_files()
+                .iter()
+                .map(PathBuf::as_path)
+                .collect(),
+            AnalysisContextInternal::IndexData { index } => index
+                .tracked_files
+                .iter()
+                .map(|&file_id| index.files[file_id].as_path())
+                .collect(),
+        }
+    }
+
+    /// Gets the dependencies (from the registry or git) the test executed
+    /// code from.
+    ///
+    /// See the [`dependencies`](crate::dependencies) module.
+    pub fn dependencies(&self) -> Vec<Dependency> {
+        match &self.internal {
+            AnalysisContextInternal::DifftestWithCoverageData { profdata, .. } => {
+                dependencies_of_files(profdata.data.iter().flat_map(|it| {
+                    it.functions.iter().flat_map(|fun| {
+                        fun.regions
+                            .iter()
+                            .filter(|region| region.execution_count > 0)
+                            .map(|region| fun.filenames[region.file_id].as_path())
+                    })
+                }))
+            }
+            AnalysisContextInternal::IndexData { index } => index.dependencies.clone(),
+        }
+    }
 }
 
 /// An iterator over the regions that are present in the coverage data of the test.
@@ -465,11 +708,31 @@ pub struct AnalysisRegion<'r> {
     pub file_ref: &'r Path,
 }
 
+/// A function executed by the test.
+pub struct AnalysisFunction<'r> {
+    /// The demangled name of the function.
+    pub name: &'r str,
+    /// The first line of the function.
+    pub l1: usize,
+    /// The last line of the function.
+    pub l2: usize,
+    /// The execution count of the function.
+    pub execution_count: usize,
+    /// The file that the function is in.
+    pub file_ref: &'r Path,
+}
+
 /// The algorithm to use for the analysis.
 #[derive(Debug, Clone)]
 pub enum DirtyAlgorithm {
     /// Use file system mtimes.
     FileSystemMtimes,
+    /// Use the hashes of the contents of the files,
+    /// as recorded in the [`TestIndex`].
+    ContentHash,
+    /// Use the fingerprints of the source text of the regions
+    /// executed by the test, as recorded in the [`TestIndex`].
+    RegionFingerprints,
     /// Use git diff, with the given strategy,
     /// and the base commit to diff with.
     GitDiff {
@@ -479,9 +742,22 @@ pub enum DirtyAlgorithm {
         strategy: GitDiffStrategy,
         /// The commit to diff with.
         commit: Option<git2::Oid>,
+        /// What to compare the commit with.
+        target: GitDiffTarget,
     },
 }
 
+/// What the base commit of a [`DirtyAlgorithm::GitDiff`] is compared with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum GitDiffTarget {
+    /// The working tree, so that uncommitted changes are also considered.
+    #[default]
+    WorkingTree,
+    /// The tree of the current HEAD, so that only the committed changes
+    /// are considered, and the working tree is ignored.
+    Head,
+}
+
 /// The configuration for the analysis.
 #[derive(Debug, Clone)]
 pub struct AnalysisConfig {
@@ -489,6 +765,108 @@ pub struct AnalysisConfig {
     pub dirty_algorithm: DirtyAlgorithm,
 
     pub error_on_invalid_config: bool,
+
+    /// The fingerprint of the current environment, to compare with the one
+    /// recorded when the test was run (if any).
+    ///
+    /// If [`None`], the environment is not checked.
+    pub environment: Option<EnvFingerprint>,
+
+    /// The files whose changes count, and the ones whose changes make all
+    /// the tests dirty (see [`PathFilter`]).
+    pub path_filter: PathFilter,
+
+    /// The tests that are always dirty, besides the ones that marked
+    /// themselves as such (see [`AlwaysRun`]).
+    pub always_run: AlwaysRun,
+}
+
+/// The tests that must always run (like smoke tests, or tests that depend
+/// on things the coverage cannot see), and so are always considered dirty,
+/// with [`DirtyReason::Forced`], whatever changed.
+///
+/// Those are the tests that called `cargo_difftests_testclient::always_run`,
+/// and the ones with a name matching one of the glob patterns.
+///
+/// # Examples
+///
+/// ```
+/// # use cargo_difftests::analysis::AlwaysRun;
+/// let always_run = AlwaysRun::new(&["smoke::*", "tests::test_network"])?;
+///
+/// assert_eq!(always_run.matching_pattern("smoke::startup"), Some("smoke::*"));
+/// assert_eq!(always_run.matching_pattern("tests::test_add"), None);
+///
+/// # Ok::<(), cargo_difftests::DifftestsError>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AlwaysRun {
+    patterns: Vec<glob::Pattern>,
+}
+
+impl AlwaysRun {
+    /// Creates a new [`AlwaysRun`], with the given test name patterns.
+    pub fn new(patterns: &[impl AsRef<str>]) -> DifftestsResult<Self> {
+        Ok(Self {
+            patterns: compile_globs(patterns)?,
+        })
+    }
+
+    /// The first pattern that matches the name of the test, if any.
+    pub fn matching_pattern(&self, test_name: &str) -> Option<&str> {
+        self.patterns
+            .iter()
+            .find(|pattern| pattern.matches(test_name))
+            .map(glob::Pattern::as_str)
+    }
+
+    /// The [`DirtyReason::Forced`] of the test, if it must always run.
+    ///
+    /// `marked` is whether the test marked itself as such.
+    pub fn forced_reason(&self, test_name: &str, marked: bool) -> Option<DirtyReason> {
+        if marked {
+            return Some(DirtyReason::Forced { pattern: None });
+        }
+
+        self.matching_pattern(test_name)
+            .map(|pattern| DirtyReason::Forced {
+                pattern: Some(pattern.to_owned()),
+            })
+    }
+}
+
+/// The reasons why a test is dirty whatever changed since it was run, if
+/// there are any.
+///
+/// They are checked in this order: the test must always run (`forced`, see
+/// [`AlwaysRun`]), it failed the last time it was run, or the environment
+/// changed since then (see [`EnvFingerprint::changes`]). Both
+/// [`AnalysisContext::run`] and
+/// [`IndexDatabase::git_diff_analysis`](crate::index_db::IndexDatabase::git_diff_analysis)
+/// only look at the changes to the code if there are none.
+pub(crate) fn settled_reasons(
+    forced: Option<DirtyReason>,
+    test_failed: bool,
+    environment_changes: impl FnOnce() -> Vec<DirtyReason>,
+) -> Option<Vec<DirtyReason>> {
+    if let Some(reason) = forced {
+        debug!("Test must always run, considering it dirty");
+        return Some(vec![reason]);
+    }
+
+    if test_failed {
+        debug!("Test failed the last time it was run, considering it dirty");
+        return Some(vec![DirtyReason::TestFailed]);
+    }
+
+    let reasons = environment_changes();
+
+    if !reasons.is_empty() {
+        debug!("The environment changed since the test run, considering it dirty");
+        return Some(reasons);
+    }
+
+    None
 }
 
 impl<'r> AnalysisContext<'r> {
@@ -497,72 +875,219 @@ impl<'r> AnalysisContext<'r> {
     /// This should only be called once.
     /// If called multiple times, the output of
     /// the analysis will correspond to the last [`AnalysisContext::run`] call.
+    ///
+    /// If the test must always run (see [`AnalysisConfig::always_run`]), if
+    /// it failed the last time it was run, or if the environment changed
+    /// since then (see [`AnalysisConfig::environment`]), it is considered
+    /// dirty regardless of the [`DirtyAlgorithm`].
     pub fn run(&mut self, config: &AnalysisConfig) -> DifftestsResult {
+        self.run_with_git_diff_cache(config, &mut GitDiffChangesCache::default())
+    }
+
+    /// Runs the analysis, with the given [`AnalysisConfig`], like
+    /// [`AnalysisContext::run`], but if the [`DirtyAlgorithm`] is
+    /// [`DirtyAlgorithm::GitDiff`], it takes the [`GitDiffChanges`]
+    /// from the given [`GitDiffChangesCache`], instead of always
+    /// diffing the tree again.
+    ///
+    /// This is useful when analyzing many tests, as the diff is then
+    /// only computed once for all the tests that diff from the same commit.
+    pub fn run_with_git_diff_cache(
+        &mut self,
+        config: &AnalysisConfig,
+        git_diff_cache: &mut GitDiffChangesCache,
+    ) -> DifftestsResult {
         let AnalysisConfig {
             dirty_algorithm,
             error_on_invalid_config,
+            environment,
+            path_filter,
+            always_run,
         } = config;
 
-        let dirty_algorithm = if let AnalysisContextInternal::IndexData { index } = &self.internal
-            && index.regions.is_empty()
-            && let DirtyAlgorithm::GitDiff {
-                strategy: GitDiffStrategy::Hunks,
-                commit,
-            } = &config.dirty_algorithm
-        {
+        let forced = self.forced_reason(always_run)?;
+
+        let settled = settled_reasons(forced, self.test_failed(), || {
+            match (environment, self.env_fingerprint()) {
+                (Some(current), Some(recorded)) => recorded.changes(current, &self.dependencies()),
+                _ => vec![],
+            }
+        });
+
+        if let Some(reasons) = settled {
+            self.report = AnalysisReport { reasons };
+            return Ok(());
+        }
+
+        let mut dirty_algorithm = dirty_algorithm.clone();
+
+        while let Err(e) = self.validate_dirty_algorithm(&dirty_algorithm) {
             let lvl = if *error_on_invalid_config {
                 log::Level::Error
             } else {
                 log::Level::Warn
             };
-            log::log!(
-                lvl,
-                "cannot use GitDiff with strategy hunks on a test index with no regions"
-            );
-            log::log!(
-                lvl,
-                "hint: you might want to pass the --full-index flag to cargo-difftests"
-            );
-            log::log!(lvl, "when compiling the index");
+            log::log!(lvl, "{e}");
+            for hint in e.hints() {
+                log::log!(lvl, "{hint}");
+            }
 
             if *error_on_invalid_config {
-                return Err(DifftestsError::InvalidConfig(
-                    InvalidConfigError::GitDiffHunksOnTestIndexWithNoRegions,
-                ));
+                return Err(DifftestsError::InvalidConfig(e));
             }
 
-            warn!("failling back to GitDiff with FilesOnly");
+            dirty_algorithm = e.fallback(dirty_algorithm);
 
-            DirtyAlgorithm::GitDiff {
-                strategy: GitDiffStrategy::FilesOnly,
-                commit: *commit,
-            }
-        } else {
-            dirty_algorithm.clone()
-        };
+            warn!("failling back to {dirty_algorithm:?}");
+        }
+
+        let mut git_diff_changes = None;
+
+        let compares_contents = matches!(
+            dirty_algorithm,
+            DirtyAlgorithm::ContentHash | DirtyAlgorithm::RegionFingerprints
+        );
 
-        let r = match dirty_algorithm {
+        let mut r = match dirty_algorithm {
             DirtyAlgorithm::FileSystemMtimes => file_system_mtime_analysis(self)?,
+            DirtyAlgorithm::ContentHash => content_hash_analysis(self)?,
+            DirtyAlgorithm::RegionFingerprints => region_fingerprint_analysis(self)?,
             DirtyAlgorithm::GitDiff {
                 strategy,
-                commit: Some(commit),
-            } => git_diff_analysis_from_commit(self, strategy, commit)?,
-            DirtyAlgorithm::GitDiff {
-                strategy,
-                commit: None,
-            } => git_diff_analysis(self, strategy)?,
+                commit,
+                target,
+            } => {
+                let commit = match commit {
+                    Some(commit) => Some(commit),
+                    None => self.git_state().map(|git_state| {
+                        if git_state.dirty {
+                            warn!(
+                                "the test was run on a working tree with uncommitted changes, \
+                                which will also be considered as changes since {}",
+                                git_state.commit
+                            );
+                        }
+                        git_state.commit
+                    }),
+                };
+
+                let changes = git_diff_cache.get_for_strategy(strategy, commit, target)?;
+
+                let r = git_diff_analysis_from_changes(self, strategy, &changes);
+                git_diff_changes = Some(changes);
+                r
+            }
         };
 
-        self.result = r;
+        r.reasons.retain(|reason| match reason.file() {
+            Some(file) => path_filter.accepts(file),
+            None => true,
+        });
+
+        r.reasons.extend(match git_diff_changes {
+            Some(changes) => always_dirty_git_diff_analysis(path_filter, &changes),
+            None if compares_contents => always_dirty_hash_analysis(self, path_filter),
+            None => always_dirty_mtime_analysis(self, path_filter)?,
+        });
+
+        self.report = r;
 
         Ok(())
     }
+
+    fn validate_dirty_algorithm(
+        &self,
+        dirty_algorithm: &DirtyAlgorithm,
+    ) -> Result<(), InvalidConfigError> {
+        match dirty_algorithm {
+            DirtyAlgorithm::GitDiff {
+                strategy: GitDiffStrategy::Hunks,
+                ..
+            } if self.get_index().is_some_and(|index| index.regions.is_empty()) => {
+                Err(InvalidConfigError::GitDiffHunksOnTestIndexWithNoRegions)
+            }
+            DirtyAlgorithm::GitDiff {
+                strategy: GitDiffStrategy::Functions,
+                ..
+            } if self
+                .get_index()
+                .is_some_and(|index| index.functions.is_empty()) =>
+            {
+                Err(InvalidConfigError::GitDiffFunctionsOnTestIndexWithNoFunctions)
+            }
+            DirtyAlgorithm::ContentHash
+                if !self
+                    .get_index()
+                    .is_some_and(|index| index.files.len() == index.file_hashes.len()) =>
+            {
+                Err(InvalidConfigError::ContentHashWithoutFileHashes)
+            }
+            DirtyAlgorithm::RegionFingerprints
+                if !self.get_index().is_some_and(|index| {
+                    !index.regions.is_empty()
+                        && index.regions.len() == index.region_fingerprints.len()
+                }) =>
+            {
+                Err(InvalidConfigError::RegionFingerprintsWithoutFingerprints)
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, thiserror::Error)]
 pub enum InvalidConfigError {
     #[error("GitDiff with strategy hunks cannot be used on a test index with no regions")]
     GitDiffHunksOnTestIndexWithNoRegions,
+    #[error("GitDiff with strategy functions cannot be used on a test index with no functions")]
+    GitDiffFunctionsOnTestIndexWithNoFunctions,
+    #[error("ContentHash can only be used on a test index with file hashes")]
+    ContentHashWithoutFileHashes,
+    #[error("RegionFingerprints can only be used on a test index with region fingerprints")]
+    RegionFingerprintsWithoutFingerprints,
+}
+
+impl InvalidConfigError {
+    pub(crate) fn hints(&self) -> &'static [&'static str] {
+        match self {
+            InvalidConfigError::GitDiffHunksOnTestIndexWithNoRegions => &[
+                "hint: you might want to pass the --full-index flag to cargo-difftests",
+                "when compiling the index",
+            ],
+            InvalidConfigError::GitDiffFunctionsOnTestIndexWithNoFunctions => &[
+                "hint: you might want to pass the --functions-index or --full-index",
+                "flag to cargo-difftests when compiling the index",
+            ],
+            InvalidConfigError::ContentHashWithoutFileHashes => &[
+                "hint: you might want to use an --index-strategy other than never,",
+                "and recompile the index",
+            ],
+            InvalidConfigError::RegionFingerprintsWithoutFingerprints => &[
+                "hint: you might want to use an --index-strategy other than never,",
+                "and pass the --full-index flag to cargo-difftests when compiling the index",
+            ],
+        }
+    }
+
+    /// The [`DirtyAlgorithm`] to use instead of the invalid one,
+    /// when [`AnalysisConfig::error_on_invalid_config`] is `false`.
+    fn fallback(&self, dirty_algorithm: DirtyAlgorithm) -> DirtyAlgorithm {
+        match (self, dirty_algorithm) {
+            (
+                InvalidConfigError::GitDiffHunksOnTestIndexWithNoRegions
+                | InvalidConfigError::GitDiffFunctionsOnTestIndexWithNoFunctions,
+                DirtyAlgorithm::GitDiff { commit, target, .. },
+            ) => DirtyAlgorithm::GitDiff {
+                strategy: GitDiffStrategy::FilesOnly,
+                commit,
+                target,
+            },
+            (InvalidConfigError::RegionFingerprintsWithoutFingerprints, _) => {
+                DirtyAlgorithm::ContentHash
+            }
+            _ => DirtyAlgorithm::FileSystemMtimes,
+        }
+    }
 }
 
 /// The result of an analysis of a single [`Difftest`] or [`TestIndex`].
@@ -577,195 +1102,595 @@ pub enum AnalysisResult {
     Dirty,
 }
 
-/// Checks whether the file is under the cargo registry.
-///
-/// # Examples
-///
-/// ```
-/// # use std::path::Path;
-/// # use cargo_difftests::analysis::file_is_from_cargo_registry;
-/// # use home::cargo_home;
-///
-/// assert!(file_is_from_cargo_registry(&cargo_home()?.join("registry/src/github.com-1ecc6299db9ec823/serde-1.0.130/src/ser/impls.rs")));
-/// assert!(!file_is_from_cargo_registry(Path::new("src/main.rs")));
+/// The report of an analysis, containing the reasons for which the test
+/// was considered dirty.
 ///
-/// # Ok::<(), std::io::Error>(())
-/// ```
-pub fn file_is_from_cargo_registry(f: &Path) -> bool {
-    let Ok(p) = home::cargo_home() else {
-        return false;
-    };
-    let registry = p.join("registry");
-    f.starts_with(&registry)
-}
-
-/// Returns a [`BTreeSet`] of the files that were touched by the test (have an execution_count > 0),
-/// and ignoring files from the cargo registry if `include_registry_files` = false.
-pub fn test_touched_files(cx: &AnalysisContext, include_registry_files: bool) -> BTreeSet<PathBuf> {
-    cx.files(include_registry_files)
+/// A test is dirty if and only if there is at least one [`DirtyReason`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct AnalysisReport {
+    /// The reasons for which the test is dirty.
+    pub reasons: Vec<DirtyReason>,
 }
 
-/// Performs an analysis of the [`Difftest`], using file system mtimes.
-///
-/// For a comparison of the different algorithms,
-/// see the [module-level documentation](crate::analysis).
-pub fn file_system_mtime_analysis(cx: &AnalysisContext) -> DifftestsResult<AnalysisResult> {
-    let test_run_time = cx.test_run_at()?;
-
-    let test_touched_files = test_touched_files(cx, false);
-
-    for f in &test_touched_files {
-        debug!("Touched file: {}", f.display());
-        let mtime = std::fs::metadata(f)?.modified()?;
-        if mtime > test_run_time {
-            debug!("File {} was modified after test run", f.display());
-            return Ok(AnalysisResult::Dirty);
+impl AnalysisReport {
+    /// The [`AnalysisResult`] corresponding to this report.
+    pub fn result(&self) -> AnalysisResult {
+        if self.reasons.is_empty() {
+            AnalysisResult::Clean
+        } else {
+            AnalysisResult::Dirty
         }
     }
 
-    Ok(AnalysisResult::Clean)
-}
-
-trait LineRangeConstraint {
-    fn validate(start: usize, end: usize) -> bool;
+    /// Whether the test is dirty because it must always run
+    /// (see [`DirtyReason::Forced`]).
+    pub fn forced(&self) -> bool {
+        self.reasons
+            .iter()
+            .any(|reason| matches!(reason, DirtyReason::Forced { .. }))
+    }
 }
 
-struct LineRange<C>
-where
-    C: LineRangeConstraint,
-{
-    start: usize,
-    end: usize,
-    _constraint: PhantomData<C>,
+/// A reason for which a test was considered dirty by the analysis.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum DirtyReason {
+    /// The test failed the last time it was run.
+    TestFailed,
+    /// A file touched by the test was modified after the test was run.
+    ///
+    /// Found by [`DirtyAlgorithm::FileSystemMtimes`].
+    FileModifiedAfterTestRun {
+        /// The file.
+        file: PathBuf,
+        /// How long after the test run the file was modified.
+        mtime_delta: std::time::Duration,
+    },
+    /// A file touched by the test does not exist anymore, so it has no
+    /// modification time to compare.
+    ///
+    /// Found by [`DirtyAlgorithm::FileSystemMtimes`].
+    FileRemoved {
+        /// The file.
+        file: PathBuf,
+    },
+    /// The contents of a file touched by the test changed.
+    ///
+    /// Found by [`DirtyAlgorithm::ContentHash`].
+    FileContentsChanged {
+        /// The file.
+        file: PathBuf,
+    },
+    /// The source text of a region executed by the test changed.
+    ///
+    /// Found by [`DirtyAlgorithm::RegionFingerprints`].
+    RegionChanged {
+        /// The file the region is in.
+        file: PathBuf,
+        /// The region.
+        region: DirtyRegion,
+    },
+    /// A file touched by the test shows up in the git diff.
+    ///
+    /// Found by [`DirtyAlgorithm::GitDiff`] with [`GitDiffStrategy::FilesOnly`]
+    /// or [`GitDiffStrategy::Semantic`].
+    FileChangedInDiff {
+        /// The file.
+        file: PathBuf,
+    },
+    /// A hunk in the git diff intersects a function executed by the test.
+    ///
+    /// Found by [`DirtyAlgorithm::GitDiff`] with [`GitDiffStrategy::Functions`].
+    FunctionChanged {
+        /// The file the function is in.
+        file: PathBuf,
+        /// The name of the function.
+        function: String,
+        /// The hunk.
+        hunk: DirtyHunk,
+    },
+    /// A hunk in the git diff intersects a region executed by the test.
+    ///
+    /// Found by [`DirtyAlgorithm::GitDiff`] with [`GitDiffStrategy::Hunks`].
+    HunkIntersectsRegion {
+        /// The file the hunk and the region are in.
+        file: PathBuf,
+        /// The hunk.
+        hunk: DirtyHunk,
+        /// The region.
+        region: DirtyRegion,
+    },
+    /// The environment the test was run in (like the `rustc` version, or an
+    /// environment variable) changed.
+    ///
+    /// Found regardless of the [`DirtyAlgorithm`], see [`EnvFingerprint`].
+    EnvironmentChanged {
+        /// What changed.
+        what: String,
+        /// The value it had when the test was run.
+        recorded: Option<String>,
+        /// The current value.
+        current: Option<String>,
+    },
+    /// A file the test declared as one of its inputs changed.
+    ///
+    /// Found by the algorithms that look at regions, functions or hunks,
+    /// which cannot tell which parts of the file the test used.
+    TrackedFileChanged {
+        /// The file.
+        file: PathBuf,
+    },
+    /// A dependency the test executed code from is not in `Cargo.lock`
+    /// anymore, because it was bumped, or removed.
+    DependencyChanged {
+        /// The dependency, at the version it had when the test was run.
+        dependency: Dependency,
+    },
+    /// The test must always run (see [`AlwaysRun`]).
+    Forced {
+        /// The pattern that matched the name of the test, or [`None`] if
+        /// the test marked itself as one that must always run.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pattern: Option<String>,
+    },
+    /// A file whose changes make all the tests dirty changed
+    /// (see [`PathFilter::is_always_dirty`]).
+    ///
+    /// Found regardless of the [`DirtyAlgorithm`], with the git diff for
+    /// [`DirtyAlgorithm::GitDiff`], and with the `mtime` for the others.
+    AlwaysDirtyFileChanged {
+        /// The file.
+        file: PathBuf,
+    },
 }
 
-impl<C> Clone for LineRange<C>
-where
-    C: LineRangeConstraint,
-{
-    fn clone(&self) -> Self {
-        Self {
-            start: self.start,
-            end: self.end,
-            _constraint: PhantomData,
+impl DirtyReason {
+    /// The file the reason is about, if any.
+    pub fn file(&self) -> Option<&Path> {
+        match self {
+            DirtyReason::FileModifiedAfterTestRun { file, .. }
+            | DirtyReason::FileRemoved { file }
+            | DirtyReason::FileContentsChanged { file }
+            | DirtyReason::RegionChanged { file, .. }
+            | DirtyReason::FileChangedInDiff { file }
+            | DirtyReason::FunctionChanged { file, .. }
+            | DirtyReason::HunkIntersectsRegion { file, .. }
+            | DirtyReason::TrackedFileChanged { file }
+            | DirtyReason::AlwaysDirtyFileChanged { file } => Some(file),
+            DirtyReason::TestFailed
+            | DirtyReason::Forced { .. }
+            | DirtyReason::EnvironmentChanged { .. }
+            | DirtyReason::DependencyChanged { .. } => None,
         }
     }
 }
 
-impl<C> Copy for LineRange<C> where C: LineRangeConstraint {}
-
-impl<C> fmt::Debug for LineRange<C>
-where
-    C: LineRangeConstraint,
-{
+impl fmt::Display for DirtyReason {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "L{}..L{}", self.start, self.end)
-    }
-}
-
-impl<C> LineRange<C>
-where
-    C: LineRangeConstraint,
-{
-    #[track_caller]
-    fn new(start: usize, end: usize) -> Self {
-        Self::try_new(start, end).unwrap()
-    }
-
-    fn try_new(start: usize, end: usize) -> Option<Self> {
-        C::validate(start, end).then_some(Self {
-            start,
-            end,
-            _constraint: PhantomData,
-        })
+        match self {
+            DirtyReason::TestFailed => write!(f, "the test failed the last time it was run"),
+            DirtyReason::FileModifiedAfterTestRun { file, mtime_delta } => write!(
+                f,
+                "{} was modified {:?} after the test run",
+                file.display(),
+                mtime_delta
+            ),
+            DirtyReason::FileRemoved { file } => {
+                write!(f, "{} was removed after the test run", file.display())
+            }
+            DirtyReason::FileContentsChanged { file } => {
+                write!(f, "the contents of {} changed", file.display())
+            }
+            DirtyReason::RegionChanged { file, region } => {
+                write!(f, "region {}:{} changed", file.display(), region)
+            }
+            DirtyReason::FileChangedInDiff { file } => {
+                write!(f, "{} is in the git diff", file.display())
+            }
+            DirtyReason::FunctionChanged {
+                file,
+                function,
+                hunk,
+            } => write!(
+                f,
+                "`{}` changed (hunk {} in {})",
+                function,
+                hunk,
+                file.display()
+            ),
+            DirtyReason::HunkIntersectsRegion { file, hunk, region } => write!(
+                f,
+                "hunk {} intersects region {}:{}",
+                hunk,
+                file.display(),
+                region
+            ),
+            DirtyReason::EnvironmentChanged {
+                what,
+                recorded,
+                current,
+            } => write!(
+                f,
+                "{} changed since the test run ({} -> {})",
+                what,
+                recorded.as_deref().unwrap_or("unset"),
+                current.as_deref().unwrap_or("unset")
+            ),
+            DirtyReason::TrackedFileChanged { file } => {
+                write!(f, "tracked file {} changed", file.display())
+            }
+            DirtyReason::DependencyChanged { dependency } => {
+                write!(f, "dependency {dependency} is not in Cargo.lock anymore")
+            }
+            DirtyReason::Forced { pattern: None } => {
+                write!(f, "the test is marked to always run")
+            }
+            DirtyReason::Forced {
+                pattern: Some(pattern),
+            } => write!(f, "the test matches the always-run pattern `{pattern}`"),
+            DirtyReason::AlwaysDirtyFileChanged { file } => {
+                write!(f, "{} changed, which makes all the tests dirty", file.display())
+            }
+        }
     }
+}
 
-    fn map_constraint<C2: LineRangeConstraint>(self) -> Result<LineRange<C2>, Self> {
-        LineRange::try_new(self.start, self.end).ok_or(self)
+/// A region, as reported in a [`DirtyReason`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyRegion {
+    /// The first line of the region.
+    pub l1: usize,
+    /// The first column of the region.
+    pub c1: usize,
+    /// The last line of the region.
+    pub l2: usize,
+    /// The last column of the region.
+    pub c2: usize,
+}
+
+impl From<&IndexRegion> for DirtyRegion {
+    fn from(region: &IndexRegion) -> Self {
+        Self {
+            l1: region.l1,
+            c1: region.c1,
+            l2: region.l2,
+            c2: region.c2,
+        }
+    }
+}
+
+impl From<&AnalysisRegion<'_>> for DirtyRegion {
+    fn from(region: &AnalysisRegion<'_>) -> Self {
+        Self {
+            l1: region.l1,
+            c1: region.c1,
+            l2: region.l2,
+            c2: region.c2,
+        }
     }
+}
 
-    fn map_constraint_assert<C2: LineRangeConstraint>(self) -> LineRange<C2> {
-        self.map_constraint::<C2>().unwrap()
+impl fmt::Display for DirtyRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}-{}:{}", self.l1, self.c1, self.l2, self.c2)
     }
+}
+
+/// A git diff hunk, as reported in a [`DirtyReason`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyHunk {
+    /// The first line of the hunk in the old version of the file.
+    pub old_start: u32,
+    /// The number of lines of the hunk in the old version of the file.
+    pub old_lines: u32,
+    /// The first line of the hunk in the new version of the file.
+    pub new_start: u32,
+    /// The number of lines of the hunk in the new version of the file.
+    pub new_lines: u32,
+}
 
-    #[track_caller]
-    fn new_u32(start: u32, end: u32) -> Self {
-        Self::new(start as usize, end as usize)
+impl From<&DiffHunk<'_>> for DirtyHunk {
+    fn from(hunk: &DiffHunk<'_>) -> Self {
+        Self {
+            old_start: hunk.old_start(),
+            old_lines: hunk.old_lines(),
+            new_start: hunk.new_start(),
+            new_lines: hunk.new_lines(),
+        }
     }
+}
 
-    fn intersects<C2: LineRangeConstraint>(&self, other: &LineRange<C2>) -> bool {
-        self.start <= other.start && self.end > other.start
-            || self.start < other.end && self.end >= other.end
+impl fmt::Display for DirtyHunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "-{},{} +{},{}",
+            self.old_start, self.old_lines, self.new_start, self.new_lines
+        )
     }
 }
 
-struct LineRangeEmptyConstraint;
+/// Checks whether the file is under the cargo registry.
+///
+/// # Examples
+///
+/// ```
+/// # use std::path::Path;
+/// # use cargo_difftests::analysis::file_is_from_cargo_registry;
+/// # use home::cargo_home;
+///
+/// assert!(file_is_from_cargo_registry(&cargo_home()?.join("registry/src/github.com-1ecc6299db9ec823/serde-1.0.130/src/ser/impls.rs")));
+/// assert!(!file_is_from_cargo_registry(Path::new("src/main.rs")));
+///
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn file_is_from_cargo_registry(f: &Path) -> bool {
+    let Ok(p) = home::cargo_home() else {
+        return false;
+    };
+    let registry = p.join("registry");
+    f.starts_with(&registry)
+}
+
+/// Returns a [`BTreeSet`] of the files that were touched by the test (have an execution_count > 0),
+/// and ignoring files from the cargo registry if `include_registry_files` = false.
+pub fn test_touched_files(cx: &AnalysisContext, include_registry_files: bool) -> BTreeSet<PathBuf> {
+    cx.files(include_registry_files)
+}
+
+/// Performs an analysis of the [`Difftest`], using file system mtimes.
+///
+/// For a comparison of the different algorithms,
+/// see the [module-level documentation](crate::analysis).
+pub fn file_system_mtime_analysis(cx: &AnalysisContext) -> DifftestsResult<AnalysisReport> {
+    let test_run_time = cx.test_run_at()?;
+
+    let test_touched_files = test_touched_files(cx, false);
+
+    let mut report = AnalysisReport::default();
 
-impl LineRangeConstraint for LineRangeEmptyConstraint {
-    fn validate(start: usize, end: usize) -> bool {
-        start == end
+    for f in test_touched_files {
+        debug!("Touched file: {}", f.display());
+        let mtime = match std::fs::metadata(&f) {
+            Ok(metadata) => metadata.modified()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("File {} was removed after test run", f.display());
+                report.reasons.push(DirtyReason::FileRemoved { file: f });
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if let Ok(mtime_delta) = mtime.duration_since(test_run_time)
+            && !mtime_delta.is_zero()
+        {
+            debug!("File {} was modified after test run", f.display());
+            report.reasons.push(DirtyReason::FileModifiedAfterTestRun {
+                file: f,
+                mtime_delta,
+            });
+        }
     }
+
+    Ok(report)
 }
 
-struct LineRangeNotEmptyConstraint;
+/// Finds the always dirty files (see [`PathFilter::is_always_dirty`]) that
+/// were modified after the test was run, by their `mtime`.
+///
+/// Used with [`DirtyAlgorithm::FileSystemMtimes`], and with the other
+/// algorithms that do not look at the git diff when the hashes of the
+/// files were not recorded (see [`always_dirty_hash_analysis`]).
+pub fn always_dirty_mtime_analysis(
+    cx: &AnalysisContext,
+    path_filter: &PathFilter,
+) -> DifftestsResult<Vec<DirtyReason>> {
+    let files = path_filter.always_dirty_files();
+    if files.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let test_run_time = cx.test_run_at()?;
+
+    let mut reasons = vec![];
 
-impl LineRangeConstraint for LineRangeNotEmptyConstraint {
-    fn validate(start: usize, end: usize) -> bool {
-        start < end
+    for file in files {
+        let mtime = std::fs::metadata(&file)?.modified()?;
+        if mtime
+            .duration_since(test_run_time)
+            .is_ok_and(|mtime_delta| !mtime_delta.is_zero())
+        {
+            debug!("Always dirty file {} was modified after test run", file.display());
+            reasons.push(DirtyReason::AlwaysDirtyFileChanged { file: file.clone() });
+        }
     }
+
+    Ok(reasons)
 }
 
-struct LineRangeValidConstraint;
+/// Finds the always dirty files (see [`PathFilter::is_always_dirty`]) whose
+/// contents changed since the test was run, by comparing their hashes with
+/// the ones recorded then (see [`EnvFingerprint::always_dirty_file_hashes`]).
+/// The files that were added or removed since then count as changed too.
+///
+/// Used with [`DirtyAlgorithm::ContentHash`] and
+/// [`DirtyAlgorithm::RegionFingerprints`], so that a fresh checkout, which
+/// changes the `mtime` of all the files, does not make all the tests dirty.
+/// If the hashes were not recorded, this falls back to
+/// [`always_dirty_mtime_analysis`].
+pub fn always_dirty_hash_analysis(
+    cx: &AnalysisContext,
+    path_filter: &PathFilter,
+) -> DifftestsResult<Vec<DirtyReason>> {
+    let Some(recorded) = cx
+        .env_fingerprint()
+        .and_then(|it| it.always_dirty_file_hashes.as_ref())
+    else {
+        return always_dirty_mtime_analysis(cx, path_filter);
+    };
 
-impl LineRangeConstraint for LineRangeValidConstraint {
-    fn validate(start: usize, end: usize) -> bool {
-        start <= end
+    let current = path_filter
+        .always_dirty_files()
+        .iter()
+        .map(|file| (path_filter.relative_path(file), file))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut reasons = vec![];
+
+    for (&path, &file) in &current {
+        if recorded.get(path) != hash_file(file).as_ref() {
+            debug!(
+                "Always dirty file {} changed after test run",
+                file.display()
+            );
+            reasons.push(DirtyReason::AlwaysDirtyFileChanged { file: file.clone() });
+        }
+    }
+
+    for path in recorded.keys() {
+        if !current.contains_key(path.as_path()) {
+            debug!(
+                "Always dirty file {} was removed after test run",
+                path.display()
+            );
+            reasons.push(DirtyReason::AlwaysDirtyFileChanged { file: path.clone() });
+        }
     }
+
+    Ok(reasons)
 }
 
-#[derive(Debug, Clone, Copy)]
-enum Diff {
-    Added(
-        LineRange<LineRangeEmptyConstraint>,
-        LineRange<LineRangeNotEmptyConstraint>,
-    ),
-    Removed(
-        LineRange<LineRangeNotEmptyConstraint>,
-        LineRange<LineRangeEmptyConstraint>,
-    ),
-    Modified(
-        LineRange<LineRangeNotEmptyConstraint>,
-        LineRange<LineRangeNotEmptyConstraint>,
-    ),
-}
-
-impl Diff {
-    fn from_hunk(hunk: &DiffHunk) -> Self {
-        let start = hunk.new_start();
-        let end = start + hunk.new_lines();
-        let new_range = LineRange::<LineRangeValidConstraint>::new_u32(start, end);
-
-        let start = hunk.old_start();
-        let end = start + hunk.old_lines();
-        let old_range = LineRange::<LineRangeValidConstraint>::new_u32(start, end);
-
-        if hunk.old_lines() == 0 {
-            Self::Added(
-                old_range.map_constraint_assert(),
-                new_range.map_constraint_assert(),
-            )
-        } else if hunk.new_lines() == 0 {
-            Self::Removed(
-                old_range.map_constraint_assert(),
-                new_range.map_constraint_assert(),
-            )
-        } else {
-            Self::Modified(
-                old_range.map_constraint_assert(),
-                new_range.map_constraint_assert(),
-            )
+/// Finds the always dirty files (see [`PathFilter::is_always_dirty`]) in
+/// the git diff.
+pub fn always_dirty_git_diff_analysis(
+    path_filter: &PathFilter,
+    changes: &GitDiffChanges,
+) -> Vec<DirtyReason> {
+    changes
+        .files
+        .iter()
+        .filter_map(GitDiffFileChanges::new_or_old_path)
+        .filter(|path| path_filter.is_always_dirty(path))
+        .map(|path| DirtyReason::AlwaysDirtyFileChanged {
+            file: path.to_path_buf(),
+        })
+        .collect()
+}
+
+/// Performs an analysis of the [`TestIndex`], comparing the hashes of the
+/// files recorded in the index with the hashes of the files on-disk.
+///
+/// For a comparison of the different algorithms,
+/// see the [module-level documentation](crate::analysis).
+///
+/// # Panics
+///
+/// Panics if the [`AnalysisContext`] was not created from a [`TestIndex`].
+pub fn content_hash_analysis(cx: &AnalysisContext) -> DifftestsResult<AnalysisReport> {
+    let index = cx
+        .get_index()
+        .expect("content hash analysis requires a test index");
+
+    let mut report = AnalysisReport::default();
+
+    for (f, hash) in index.files.iter().zip(&index.file_hashes) {
+        if file_is_from_cargo_registry(f) {
+            continue;
+        }
+
+        debug!("Touched file: {}", f.display());
+        let current_hash = hash_file(f);
+        if current_hash != *hash {
+            debug!("File {} has different contents than at test run", f.display());
+            report
+                .reasons
+                .push(DirtyReason::FileContentsChanged { file: f.clone() });
         }
     }
+
+    Ok(report)
+}
+
+/// Performs an analysis of the [`TestIndex`], checking whether the source
+/// text of every region executed by the test can still be found in its file.
+///
+/// For a comparison of the different algorithms,
+/// see the [module-level documentation](crate::analysis).
+///
+/// # Panics
+///
+/// Panics if the [`AnalysisContext`] was not created from a [`TestIndex`].
+pub fn region_fingerprint_analysis(cx: &AnalysisContext) -> DifftestsResult<AnalysisReport> {
+    let index = cx
+        .get_index()
+        .expect("region fingerprint analysis requires a test index");
+
+    let mut sources = BTreeMap::<usize, Option<SourceFingerprints>>::new();
+    let mut report = AnalysisReport::default();
+
+    for (region, fingerprint) in index.regions.iter().zip(&index.region_fingerprints) {
+        if region.count == 0 {
+            continue;
+        }
+
+        let f = &index.files[region.file_id];
+
+        if file_is_from_cargo_registry(f) {
+            continue;
+        }
+
+        let Some(fingerprint) = fingerprint else {
+            debug!("File {} could not be read at test run", f.display());
+            report.reasons.push(DirtyReason::RegionChanged {
+                file: f.clone(),
+                region: DirtyRegion::from(region),
+            });
+            continue;
+        };
+
+        let source = sources.entry(region.file_id).or_insert_with(|| {
+            debug!("Touched file: {}", f.display());
+            std::fs::read_to_string(f)
+                .ok()
+                .map(|source| SourceFingerprints::new(&source))
+        });
+
+        let Some(source) = source else {
+            debug!("File {} cannot be read", f.display());
+            report.reasons.push(DirtyReason::RegionChanged {
+                file: f.clone(),
+                region: DirtyRegion::from(region),
+            });
+            continue;
+        };
+
+        if !source.contains(*fingerprint) {
+            debug!(
+                "Region {}:{}:{}-{}:{} was modified after test run",
+                f.display(),
+                region.l1,
+                region.c1,
+                region.l2,
+                region.c2
+            );
+            report.reasons.push(DirtyReason::RegionChanged {
+                file: f.clone(),
+                region: DirtyRegion::from(region),
+            });
+        }
+    }
+
+    for &file_id in &index.tracked_files {
+        let f = &index.files[file_id];
+
+        if hash_file(f) != index.file_hashes[file_id] {
+            debug!("Tracked file {} changed after test run", f.display());
+            report
+                .reasons
+                .push(DirtyReason::TrackedFileChanged { file: f.clone() });
+        }
+    }
+
+    Ok(report)
 }
 
 /// The git-diff strategy to use for the analysis.
@@ -778,134 +1703,618 @@ pub enum GitDiffStrategy {
     FilesOnly,
     /// Use hunks.
     Hunks,
+    /// Use hunks, mapped to the functions executed by the test.
+    Functions,
+    /// Use files only, like [`GitDiffStrategy::FilesOnly`], but ignore the
+    /// hunks in Rust files that only change whitespace or comments.
+    Semantic,
 }
 
-impl GitDiffStrategy {
-    fn callbacks<'a>(
-        &self,
-        cx: &'a AnalysisContext,
-        analysis_result: Rc<RefCell<AnalysisResult>>,
-    ) -> (
-        Box<dyn FnMut(DiffDelta, f32) -> bool + 'a>,
-        Box<dyn FnMut(DiffDelta, DiffHunk) -> bool + 'a>,
-    ) {
-        match self {
-            Self::FilesOnly => {
-                let file_cb = {
-                    let analysis_result = Rc::clone(&analysis_result);
+/// Checks whether the hunk touches the span of (old) lines `l1..=l2`, the
+/// same way [`GitDiffStrategy::Hunks`] and [`GitDiffStrategy::Functions`] do.
+///
+/// That is, if it removes or modifies any of those lines, or inserts lines
+/// between two of them, or right before `l1` or right after `l2`.
+///
+/// Insertions at the edges count, because the coverage regions of a function
+/// do not cover the lines between them (with recent LLVM versions, most
+/// regions are a single line), so code added there would otherwise go
+/// unnoticed.
+pub fn hunk_intersects_lines(hunk: &DirtyHunk, l1: usize, l2: usize) -> bool {
+    let start = hunk.old_start as usize;
+
+    match hunk.old_lines as usize {
+        // a pure insertion, after the line `start` (0 for the start of the file)
+        0 => l1 <= start + 1 && start <= l2,
+        old_lines => start <= l2 && l1 < start + old_lines,
+    }
+}
 
-                    let test_touched_files = test_touched_files(cx, false);
+/// A mapping between the lines of the old and the new version of a file,
+/// built from all the hunks in the diff of that file.
+#[derive(Debug, Clone, Copy)]
+pub struct LineMapping<'a> {
+    hunks: &'a [DirtyHunk],
+}
 
-                    move |delta: DiffDelta, _progress: f32| {
-                        let Some(path) =
-                            delta.new_file().path().or_else(|| delta.old_file().path())
-                        else {
-                            return true;
-                        };
+impl<'a> LineMapping<'a> {
+    /// Creates a new [`LineMapping`] from the hunks of the diff of a file.
+    ///
+    /// The hunks have to be in the order git gives them in, that is, sorted
+    /// by their old start, and not overlapping.
+    pub fn new(hunks: &'a [DirtyHunk]) -> Self {
+        debug_assert!(hunks.is_sorted_by_key(|hunk| hunk.old_start));
 
-                        let test_modified = test_touched_files.iter().any(|it| it.ends_with(path));
+        Self { hunks }
+    }
 
-                        if test_modified {
-                            *analysis_result.borrow_mut() = AnalysisResult::Dirty;
-                            return false;
-                        }
+    /// The hunks the mapping was built from.
+    pub fn hunks(&self) -> &'a [DirtyHunk] {
+        self.hunks
+    }
 
-                        true
-                    }
-                };
+    /// Maps a line in the old version of the file to the line it moved to
+    /// in the new version.
+    ///
+    /// Returns [`None`] if the line was removed or modified. Line 0 stands
+    /// for the start of the file, and always maps to 0.
+    pub fn map_line(&self, line: usize) -> Option<usize> {
+        let mut new_line = line;
+
+        for hunk in self.hunks {
+            let (start, old_lines, new_lines) = (
+                hunk.old_start as usize,
+                hunk.old_lines as usize,
+                hunk.new_lines as usize,
+            );
 
-                let hunk_cb = { |_delta: DiffDelta, _hunk: DiffHunk| true };
-
-                (Box::new(file_cb), Box::new(hunk_cb))
-            }
-            Self::Hunks => {
-                let file_cb = { |_delta: DiffDelta, _progress: f32| true };
-
-                let hunk_cb = {
-                    let analysis_result = Rc::clone(&analysis_result);
-
-                    move |delta: DiffDelta, hunk: DiffHunk| {
-                        let diff = Diff::from_hunk(&hunk);
-
-                        let intersection_target = match diff {
-                            Diff::Added(old, _new) => {
-                                old.map_constraint_assert::<LineRangeValidConstraint>()
-                            }
-                            Diff::Removed(old, _new) => {
-                                old.map_constraint_assert::<LineRangeValidConstraint>()
-                            }
-                            Diff::Modified(old, _new) => {
-                                old.map_constraint_assert::<LineRangeValidConstraint>()
-                            }
-                        };
-
-                        let Some(path) =
-                            delta.old_file().path().or_else(|| delta.new_file().path())
-                        else {
-                            return true;
-                        };
-
-                        for region in
-                            cx.regions()
-                                .filter(|r| r.execution_count > 0)
-                                .filter(|region| {
-                                    path.ends_with(region.file_ref)
-                                        || region.file_ref.ends_with(path)
-                                })
-                        {
-                            let region_range = LineRange::<LineRangeValidConstraint>::new(
-                                region.l1,
-                                region.l2 + 1, // l2 is inclusive
-                            );
-                            if region_range.intersects(&intersection_target) {
-                                *analysis_result.borrow_mut() = AnalysisResult::Dirty;
-                                return false;
-                            }
-                        }
+            if old_lines == 0 {
+                // lines inserted after `start`
+                if start >= line {
+                    break;
+                }
+            } else if line < start {
+                break;
+            } else if line < start + old_lines {
+                return None;
+            } else {
+                new_line -= old_lines;
+            }
 
-                        true
-                    }
-                };
+            new_line += new_lines;
+        }
+
+        Some(new_line)
+    }
+
+    /// The hunks that touch the span of (old) lines `l1..=l2`, as
+    /// defined by [`hunk_intersects_lines`].
+    ///
+    /// There are some exactly when [`LineMapping::lines_touched`].
+    pub fn hunks_touching(&self, l1: usize, l2: usize) -> impl Iterator<Item = &'a DirtyHunk> {
+        // the hunks that end before `l1` (for insertions, the ones after a
+        // line before `l1 - 1`) cannot touch the span, and neither can the
+        // ones that start after `l2`
+        let start = self.hunks.partition_point(|hunk| {
+            let old_end = match hunk.old_lines as usize {
+                0 => hunk.old_start as usize + 2,
+                old_lines => hunk.old_start as usize + old_lines,
+            };
+
+            old_end <= l1
+        });
+        let end = self
+            .hunks
+            .partition_point(|hunk| hunk.old_start as usize <= l2);
+
+        self.hunks[start..end.max(start)]
+            .iter()
+            .filter(move |hunk| hunk_intersects_lines(hunk, l1, l2))
+    }
+
+    /// Checks whether any of the lines `l1..=l2` were removed or modified,
+    /// or lines were inserted between them, or right before or after them.
+    ///
+    /// That is, whether the lines did not all move together, by the same
+    /// offset, with their neighbours (if those are still there).
+    pub fn lines_touched(&self, l1: usize, l2: usize) -> bool {
+        let Some(mapped) = (l1..=l2)
+            .map(|line| self.map_line(line))
+            .collect::<Option<Vec<_>>>()
+        else {
+            return true;
+        };
+
+        let (Some(&first), Some(&last)) = (mapped.first(), mapped.last()) else {
+            return false;
+        };
+
+        // a removed or modified neighbour does not count, its new lines
+        // are part of the modification, not of an insertion
+        mapped.windows(2).any(|lines| lines[1] != lines[0] + 1)
+            || l1
+                .checked_sub(1)
+                .and_then(|before| self.map_line(before))
+                .is_some_and(|before| before + 1 != first)
+            || self.map_line(l2 + 1).is_some_and(|after| after != last + 1)
+    }
+}
+
+/// The changes in a git diff, collected in a single pass, so that they can
+/// be checked against many tests.
+#[derive(Debug, Clone, Default)]
+pub struct GitDiffChanges {
+    /// The files that changed, with their hunks.
+    pub files: Vec<GitDiffFileChanges>,
+    /// The working directory of the repository, which the paths of the
+    /// files are relative to ([`None`] for bare repositories).
+    pub workdir: Option<PathBuf>,
+}
+
+/// The changes to a single file in a git diff.
+#[derive(Debug, Clone)]
+pub struct GitDiffFileChanges {
+    /// The kind of change ([`git2::Delta::Renamed`], [`git2::Delta::Modified`], ...).
+    pub status: git2::Delta,
+    /// The path of the file before the change, if any.
+    pub old_path: Option<PathBuf>,
+    /// The path of the file after the change, if any.
+    pub new_path: Option<PathBuf>,
+    /// The id of the blob of the file before the change (zero if none).
+    pub old_id: git2::Oid,
+    /// The id of the blob of the file after the change (zero if none,
+    /// or if it was not computed, for files in the working tree).
+    pub new_id: git2::Oid,
+    /// The hunks (with no context lines).
+    ///
+    /// For renames and copies, the old lines are the ones in the file
+    /// that was renamed or copied from.
+    pub hunks: Vec<DirtyHunk>,
+}
+
+impl GitDiffFileChanges {
+    /// The path of the file after the change, or before it,
+    /// if the file was removed.
+    pub fn new_or_old_path(&self) -> Option<&Path> {
+        self.new_path.as_deref().or(self.old_path.as_deref())
+    }
+
+    /// The path of the file before the change, or after it,
+    /// if the file was added.
+    pub fn old_or_new_path(&self) -> Option<&Path> {
+        self.old_path.as_deref().or(self.new_path.as_deref())
+    }
+
+    /// The path of the changed file, as it was when the test was run,
+    /// which is the one in the coverage data and in the indexes.
+    ///
+    /// This is the path before the change (so a renamed file maps to its
+    /// old path), or after it, if the file was added. It is [`None`] for
+    /// copies, as the file that was copied from did not change, and the
+    /// copy itself is a new file.
+    pub fn indexed_path(&self) -> Option<&Path> {
+        match self.status {
+            git2::Delta::Copied => None,
+            _ => self.old_or_new_path(),
+        }
+    }
+
+    /// The [`LineMapping`] of the file, built from its hunks.
+    pub fn line_mapping(&self) -> LineMapping<'_> {
+        LineMapping::new(&self.hunks)
+    }
+
+    /// The contents of the file before the change, if it existed and
+    /// is valid UTF-8.
+    fn old_contents(&self, repo: &git2::Repository) -> DifftestsResult<Option<String>> {
+        if self.old_id.is_zero() {
+            return Ok(None);
+        }
+
+        let blob = repo.find_blob(self.old_id)?;
+
+        Ok(std::str::from_utf8(blob.content()).ok().map(str::to_owned))
+    }
+
+    /// The contents of the file after the change, if it still exists and
+    /// is valid UTF-8, either from the repository, or from the working tree.
+    fn new_contents(&self, repo: &git2::Repository) -> DifftestsResult<Option<String>> {
+        if !self.new_id.is_zero()
+            && let Ok(blob) = repo.find_blob(self.new_id)
+        {
+            return Ok(std::str::from_utf8(blob.content()).ok().map(str::to_owned));
+        }
+
+        let (Some(workdir), Some(new_path)) = (repo.workdir(), &self.new_path) else {
+            return Ok(None);
+        };
+
+        match std::fs::read(workdir.join(new_path)) {
+            Ok(contents) => Ok(String::from_utf8(contents).ok()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl GitDiffChanges {
+    /// Collects the changes between the given tree and the working tree.
+    ///
+    /// Like `git diff <commit>`, the index is used to tell which files in the
+    /// working tree are tracked, so that (staged) renames are detected.
+    pub fn from_tree(repo: &git2::Repository, tree: &git2::Tree) -> DifftestsResult<Self> {
+        let mut diff_options = git2::DiffOptions::new();
+
+        diff_options.context_lines(0);
+
+        let mut diff = repo.diff_tree_to_workdir_with_index(Some(tree), Some(&mut diff_options))?;
+
+        Self::from_diff(repo, &mut diff)
+    }
+
+    /// Collects the changes between the two given trees, ignoring
+    /// the working tree.
+    pub fn from_trees(
+        repo: &git2::Repository,
+        old_tree: &git2::Tree,
+        new_tree: &git2::Tree,
+    ) -> DifftestsResult<Self> {
+        let mut diff_options = git2::DiffOptions::new();
+
+        diff_options.context_lines(0);
+
+        let mut diff =
+            repo.diff_tree_to_tree(Some(old_tree), Some(new_tree), Some(&mut diff_options))?;
+
+        Self::from_diff(repo, &mut diff)
+    }
+
+    fn from_diff(repo: &git2::Repository, diff: &mut git2::Diff) -> DifftestsResult<Self> {
+        let mut find_options = git2::DiffFindOptions::new();
+
+        find_options.renames(true).copies(true);
+
+        diff.find_similar(Some(&mut find_options))?;
+
+        let files = RefCell::new(Vec::<GitDiffFileChanges>::new());
+
+        diff.foreach(
+            &mut |delta, _progress| {
+                // diffing with the index reports files whose changes in the
+                // index were reverted in the working tree as modified, even
+                // though they are the same as in the tree
+                if delta.status() == git2::Delta::Modified
+                    && !delta.old_file().id().is_zero()
+                    && delta.old_file().id() == delta.new_file().id()
+                {
+                    return true;
+                }
+
+                files.borrow_mut().push(GitDiffFileChanges {
+                    status: delta.status(),
+                    old_id: delta.old_file().id(),
+                    new_id: delta.new_file().id(),
+                    old_path: delta.old_file().path().map(Path::to_path_buf),
+                    new_path: delta.new_file().path().map(Path::to_path_buf),
+                    hunks: vec![],
+                });
+                true
+            },
+            None,
+            Some(&mut |_delta, hunk| {
+                if let Some(file) = files.borrow_mut().last_mut() {
+                    file.hunks.push(DirtyHunk::from(&hunk));
+                }
+                true
+            }),
+            None,
+        )?;
+
+        Ok(Self {
+            files: files.into_inner(),
+            workdir: repo.workdir().map(Path::to_path_buf),
+        })
+    }
+
+    /// Collects the changes between the tree of the current HEAD
+    /// and the working tree.
+    pub fn from_head() -> DifftestsResult<Self> {
+        Self::collect(None, GitDiffTarget::WorkingTree)
+    }
+
+    /// Collects the changes between the tree of the given commit
+    /// and the working tree.
+    pub fn from_commit(commit: git2::Oid) -> DifftestsResult<Self> {
+        Self::collect(Some(commit), GitDiffTarget::WorkingTree)
+    }
+
+    /// The paths that the file at `path` in the diff can have in the
+    /// coverage data or in an index: absolute, or relative to the root of
+    /// the repository (with `--flatten-files-to=repo-root`).
+    pub fn indexed_paths(&self, path: &Path) -> impl Iterator<Item = PathBuf> {
+        [
+            Some(path.to_path_buf()),
+            self.workdir.as_ref().map(|workdir| workdir.join(path)),
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    /// Whether `file`, as it is in the coverage data or in an index, is the
+    /// file at `path` in the diff (see [`GitDiffChanges::indexed_paths`]).
+    ///
+    /// Only the exact same file matches, so `src/lib.rs` in the diff does
+    /// not match the `src/lib.rs` of another crate in the workspace.
+    pub fn is_same_file(&self, file: &Path, path: &Path) -> bool {
+        let file = normalize_path(file);
+
+        self.indexed_paths(path).any(|it| it == file)
+    }
 
-                (Box::new(file_cb), Box::new(hunk_cb))
+    /// Returns the same changes, without the hunks in Rust files that only
+    /// change whitespace or comments (see [`crate::semantic_diff`]).
+    ///
+    /// Files that are left without any hunks are removed, but the ones that
+    /// did not have any hunks to begin with (like pure renames) are kept.
+    pub fn without_cosmetic_hunks(&self, repo: &git2::Repository) -> DifftestsResult<Self> {
+        let mut files = Vec::with_capacity(self.files.len());
+
+        for file in &self.files {
+            if file.hunks.is_empty()
+                || !file
+                    .new_or_old_path()
+                    .is_some_and(|path| path.extension().is_some_and(|ext| ext == "rs"))
+            {
+                files.push(file.clone());
+                continue;
+            }
+
+            let (Some(old), Some(new)) = (file.old_contents(repo)?, file.new_contents(repo)?)
+            else {
+                files.push(file.clone());
+                continue;
+            };
+
+            // the hunks git finds do not always line up with the changes to
+            // the tokens, so check the whole file first
+            let hunks = if semantic_diff::only_whitespace_or_comments_changed(&old, &new) {
+                vec![]
+            } else {
+                file.hunks
+                    .iter()
+                    .filter(|hunk| !semantic_diff::hunk_is_cosmetic(&old, &new, hunk))
+                    .copied()
+                    .collect::<Vec<_>>()
+            };
+
+            if hunks.is_empty() {
+                debug!(
+                    "Only whitespace or comments changed in {}",
+                    file.new_or_old_path().unwrap().display()
+                );
+                continue;
+            }
+
+            files.push(GitDiffFileChanges {
+                hunks,
+                ..file.clone()
+            });
+        }
+
+        Ok(Self {
+            files,
+            workdir: self.workdir.clone(),
+        })
+    }
+
+    /// Collects the changes between the given commit (or HEAD, if [`None`])
+    /// and the given [`GitDiffTarget`].
+    pub fn collect(commit: Option<git2::Oid>, target: GitDiffTarget) -> DifftestsResult<Self> {
+        let repo = git2::Repository::open_from_env()?;
+        let tree = match commit {
+            Some(commit) => repo.find_commit(commit)?.tree()?,
+            None => repo.head()?.peel_to_tree()?,
+        };
+
+        match target {
+            GitDiffTarget::WorkingTree => Self::from_tree(&repo, &tree),
+            GitDiffTarget::Head => {
+                let head = repo.head()?.peel_to_tree()?;
+                Self::from_trees(&repo, &tree, &head)
             }
         }
     }
 }
 
-/// Performs a git diff analysis on the diff between the given tree
-/// and the working tree.
+/// A cache of [`GitDiffChanges`], by base commit and [`GitDiffTarget`].
+///
+/// Used to only compute the diff once for all the tests that diff from
+/// the same commit, see [`AnalysisContext::run_with_git_diff_cache`].
+#[derive(Default)]
+pub struct GitDiffChangesCache {
+    changes: HashMap<(Option<git2::Oid>, GitDiffTarget), Rc<GitDiffChanges>>,
+    without_cosmetic_hunks: HashMap<(Option<git2::Oid>, GitDiffTarget), Rc<GitDiffChanges>>,
+}
+
+impl GitDiffChangesCache {
+    /// Gets the changes between the given commit (or HEAD, if [`None`])
+    /// and the given [`GitDiffTarget`], collecting them if they were not
+    /// already.
+    pub fn get(
+        &mut self,
+        commit: Option<git2::Oid>,
+        target: GitDiffTarget,
+    ) -> DifftestsResult<Rc<GitDiffChanges>> {
+        if let Some(changes) = self.changes.get(&(commit, target)) {
+            return Ok(Rc::clone(changes));
+        }
+
+        let changes = Rc::new(GitDiffChanges::collect(commit, target)?);
+        self.changes.insert((commit, target), Rc::clone(&changes));
+
+        Ok(changes)
+    }
+
+    /// Gets the changes the given [`GitDiffStrategy`] looks at: like
+    /// [`GitDiffChangesCache::get`], but for [`GitDiffStrategy::Semantic`],
+    /// without the hunks that only change whitespace or comments.
+    pub fn get_for_strategy(
+        &mut self,
+        strategy: GitDiffStrategy,
+        commit: Option<git2::Oid>,
+        target: GitDiffTarget,
+    ) -> DifftestsResult<Rc<GitDiffChanges>> {
+        if strategy != GitDiffStrategy::Semantic {
+            return self.get(commit, target);
+        }
+
+        if let Some(changes) = self.without_cosmetic_hunks.get(&(commit, target)) {
+            return Ok(Rc::clone(changes));
+        }
+
+        let repo = git2::Repository::open_from_env()?;
+        let changes = Rc::new(self.get(commit, target)?.without_cosmetic_hunks(&repo)?);
+        self.without_cosmetic_hunks
+            .insert((commit, target), Rc::clone(&changes));
+
+        Ok(changes)
+    }
+}
+
+/// Removes the `.` and `..` components of a path, without looking at the
+/// file system (the paths of the tracked files are joined to the current
+/// directory of the test, and may contain them).
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir if normalized.file_name().is_some() => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+
+    normalized
+}
+
+/// Performs a git diff analysis on the given (already collected) changes.
 ///
 /// The analysis is performed using the given strategy.
-pub fn git_diff_analysis_from_tree(
+pub fn git_diff_analysis_from_changes(
     cx: &AnalysisContext,
     strategy: GitDiffStrategy,
-    repo: &git2::Repository,
-    tree: &git2::Tree,
-) -> DifftestsResult<AnalysisResult> {
-    let mut diff_options = git2::DiffOptions::new();
+    changes: &GitDiffChanges,
+) -> AnalysisReport {
+    let mut report = AnalysisReport::default();
 
-    diff_options.context_lines(0);
+    match strategy {
+        GitDiffStrategy::FilesOnly | GitDiffStrategy::Semantic => {
+            let test_touched_files = test_touched_files(cx, false);
 
-    let diff = repo.diff_tree_to_workdir(Some(&tree), Some(&mut diff_options))?;
+            for file_changes in &changes.files {
+                let Some(path) = file_changes.indexed_path() else {
+                    continue;
+                };
 
-    let analysis_result = Rc::new(RefCell::new(AnalysisResult::Clean));
+                if let Some(file) = test_touched_files
+                    .iter()
+                    .find(|it| changes.is_same_file(it, path))
+                {
+                    report
+                        .reasons
+                        .push(DirtyReason::FileChangedInDiff { file: file.clone() });
+                }
+            }
+        }
+        GitDiffStrategy::Hunks => {
+            for file_changes in &changes.files {
+                let Some(path) = file_changes.indexed_path() else {
+                    continue;
+                };
 
-    let (mut file_cb, mut hunk_cb) = strategy.callbacks(cx, Rc::clone(&analysis_result));
+                let line_mapping = file_changes.line_mapping();
+
+                for region in cx
+                    .regions()
+                    .filter(|r| r.execution_count > 0)
+                    .filter(|region| changes.is_same_file(region.file_ref, path))
+                    .filter(|region| line_mapping.lines_touched(region.l1, region.l2))
+                {
+                    for hunk in line_mapping.hunks_touching(region.l1, region.l2) {
+                        report.reasons.push(DirtyReason::HunkIntersectsRegion {
+                            file: region.file_ref.to_path_buf(),
+                            hunk: *hunk,
+                            region: DirtyRegion::from(&region),
+                        });
+                    }
+                }
+            }
+        }
+        GitDiffStrategy::Functions => {
+            let functions = cx.functions();
 
-    let git_r = diff.foreach(&mut *file_cb, None, Some(&mut *hunk_cb), None);
+            for file_changes in &changes.files {
+                let Some(path) = file_changes.indexed_path() else {
+                    continue;
+                };
 
-    if let Err(e) = git_r {
-        if e.code() == git2::ErrorCode::User {
-            debug_assert_eq!(*analysis_result.borrow(), AnalysisResult::Dirty);
-        } else {
-            return Err(DifftestsError::Git(e));
+                let line_mapping = file_changes.line_mapping();
+
+                for function in functions
+                    .iter()
+                    .filter(|function| changes.is_same_file(function.file_ref, path))
+                    .filter(|function| line_mapping.lines_touched(function.l1, function.l2))
+                {
+                    for hunk in line_mapping.hunks_touching(function.l1, function.l2) {
+                        report.reasons.push(DirtyReason::FunctionChanged {
+                            file: function.file_ref.to_path_buf(),
+                            function: function.name.to_owned(),
+                            hunk: *hunk,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    if matches!(strategy, GitDiffStrategy::Hunks | GitDiffStrategy::Functions) {
+        let tracked_files = cx.tracked_files();
+
+        for file_changes in &changes.files {
+            let Some(path) = file_changes.indexed_path() else {
+                continue;
+            };
+
+            if let Some(file) = tracked_files
+                .iter()
+                .find(|it| changes.is_same_file(it, path))
+            {
+                report.reasons.push(DirtyReason::TrackedFileChanged {
+                    file: file.to_path_buf(),
+                });
+            }
         }
     }
 
-    let r = *analysis_result.borrow();
+    report
+}
+
+/// Performs a git diff analysis on the diff between the given tree
+/// and the working tree.
+///
+/// The analysis is performed using the given strategy.
+pub fn git_diff_analysis_from_tree(
+    cx: &AnalysisContext,
+    strategy: GitDiffStrategy,
+    repo: &git2::Repository,
+    tree: &git2::Tree,
+) -> DifftestsResult<AnalysisReport> {
+    let changes = GitDiffChanges::from_tree(repo, tree)?;
 
-    Ok(r)
+    Ok(git_diff_analysis_from_changes(cx, strategy, &changes))
 }
 
 /// Performs a git diff analysis on the diff between the current HEAD
@@ -913,7 +2322,7 @@ pub fn git_diff_analysis_from_tree(
 pub fn git_diff_analysis(
     cx: &AnalysisContext,
     strategy: GitDiffStrategy,
-) -> DifftestsResult<AnalysisResult> {
+) -> DifftestsResult<AnalysisReport> {
     let repo = git2::Repository::open_from_env()?;
     let head = repo.head()?.peel_to_tree()?;
 
@@ -926,7 +2335,7 @@ pub fn git_diff_analysis_from_commit(
     cx: &AnalysisContext,
     strategy: GitDiffStrategy,
     commit: git2::Oid,
-) -> DifftestsResult<AnalysisResult> {
+) -> DifftestsResult<AnalysisReport> {
     let repo = git2::Repository::open_from_env()?;
     let tree = repo.find_commit(commit)?.tree()?;
 
@@ -0,0 +1,119 @@
+/*
+ *        Copyright (c) 2023-2024 Dinu Blanovschi
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        https://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+#![feature(exit_status_error)]
+
+//! A test rerunner that reruns all the dirty tests with a single
+//! `cargo difftests collect-profiling-data --test-runner nextest`,
+//! instead of one `collect-profiling-data` per test.
+//!
+//! The tests are selected by the nextest binary id and the name of each
+//! of them, with `--nextest-test`.
+
+use cargo_difftests::{
+    cargo_difftests_test_rerunner,
+    test_rerunner_core::{TestRerunnerInvocation, TestRunnerInvocationTestCounts},
+};
+
+#[derive(Debug, thiserror::Error)]
+enum Error {
+    #[error("IO error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("exit status error: {0}")]
+    ExitStatusError(#[from] std::process::ExitStatusError),
+    #[error("difftests error: {0}")]
+    DifftestsError(#[from] cargo_difftests::DifftestsError),
+}
+
+struct FailGuard<'invocation>(TestRunnerInvocationTestCounts<'invocation>);
+
+impl<'invocation> Drop for FailGuard<'invocation> {
+    fn drop(&mut self) {
+        self.0.fail_if_running().unwrap();
+    }
+}
+
+fn rerunner(invocation: TestRerunnerInvocation) -> Result<(), Error> {
+    let mut counts = FailGuard(invocation.test_counts());
+    counts.0.initialize_test_counts(invocation.tests().len())?;
+
+    if invocation.is_empty() {
+        counts.0.test_count_done()?;
+        return Ok(());
+    }
+
+    let extra_args = std::env::var("CARGO_DIFFTESTS_EXTRA_ARGS");
+
+    let extra_args = extra_args
+        .as_ref()
+        .map(|extra_args| extra_args.split(',').collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let mut cmd = std::process::Command::new("cargo");
+    cmd.args([
+        "difftests",
+        "collect-profiling-data",
+        "--test-runner",
+        "nextest",
+        "--exact",
+    ]);
+
+    for test in invocation.tests() {
+        // the same test name can be in several test binaries
+        match &test.nextest_binary_id {
+            Some(binary_id) => cmd.args(["--nextest-test", binary_id, &test.test_name]),
+            None => cmd.args(["--filter", &test.test_name]),
+        };
+    }
+
+    let child = cmd
+        .args(&extra_args)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+
+    // all the tests run in this one process, so its output can easily fill
+    // the pipes; it has to be read while waiting for the process to exit
+    let output = child.wait_with_output()?;
+
+    if !output.status.success() {
+        // there is no telling which of the tests failed, so none of them
+        // is reported as successful
+        for test in invocation.tests() {
+            counts.0.start_test(test.test_name.clone())?.test_failed()?;
+        }
+
+        println!("{}", String::from_utf8_lossy(&output.stdout));
+        eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+
+        std::process::exit(1);
+    }
+
+    for test in invocation.tests() {
+        counts
+            .0
+            .start_test(test.test_name.clone())?
+            .test_successful()?;
+    }
+
+    counts.0.test_count_done()?;
+
+    Ok(())
+}
+
+cargo_difftests_test_rerunner!(rerunner);
@@ -0,0 +1,42 @@
+/*
+ *        Copyright (c) 2023-2024 Dinu Blanovschi
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        https://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+//! The target runner that `cargo difftests collect-profiling-data
+//! --test-runner nextest` sets for `cargo nextest run`, see
+//! [`cargo_difftests::nextest`].
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let mut args = std::env::args_os().skip(1);
+    let Some(binary) = args.next() else {
+        eprintln!("error: missing test binary");
+        return ExitCode::FAILURE;
+    };
+    let args = args.collect::<Vec<_>>();
+
+    match cargo_difftests::nextest::run_as_target_runner(&PathBuf::from(binary), &args) {
+        Ok(status) => match status.code() {
+            Some(code) => ExitCode::from(code as u8),
+            None => ExitCode::FAILURE,
+        },
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
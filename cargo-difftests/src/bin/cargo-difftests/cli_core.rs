@@ -3,12 +3,14 @@ use std::{
     fmt::{self, Display, Formatter},
     io::{BufRead, Write},
     path::PathBuf,
+    sync::OnceLock,
 };
 
-use anyhow::bail;
+use anyhow::{bail, Context};
 use cargo_difftests::{
-    analysis::GitDiffStrategy,
+    analysis::{AlwaysRun, GitDiffStrategy, GitDiffTarget},
     difftest::{DiscoverIndexPathResolver, ExportProfdataConfig},
+    path_filter::PathFilter,
     AnalysisVerdict, AnalyzeAllSingleTest, IndexCompareDifferences, TouchSameFilesDifference,
 };
 use clap::{Args, ValueEnum};
@@ -67,6 +69,15 @@ pub struct CompileTestIndexFlags {
     /// the `--full-index` flag will result in an error.
     #[clap(long = "full-index")]
     pub full_index: bool,
+    /// Whether to generate an index that contains the functions that
+    /// were executed by the test (with their line spans), on top of
+    /// the list of files.
+    ///
+    /// This is enough for the `--algo=git-diff-functions` algorithm, and
+    /// is much smaller than a full index. A full index also contains the
+    /// functions.
+    #[clap(long = "functions-index", conflicts_with = "full_index")]
+    pub functions_index: bool,
     /// Windows-only: Whether to replace all backslashes in paths with
     /// normal forward slashes.
     #[cfg(windows)]
@@ -84,6 +95,7 @@ impl Default for CompileTestIndexFlags {
             flatten_files_to: Some(FlattenFilesTarget::RepoRoot),
             remove_bin_path: true,
             full_index: false,
+            functions_index: false,
             #[cfg(windows)]
             path_slash_replace: true,
         }
@@ -128,6 +140,30 @@ impl Display for AnalysisIndexStrategy {
     }
 }
 
+#[derive(ValueEnum, Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum TestRunner {
+    /// Build the tests with `cargo test --no-run`, and run each of them
+    /// directly, with the libtest harness.
+    #[default]
+    #[clap(name = "libtest")]
+    Libtest,
+    /// List the tests with `cargo nextest list`, and run them with
+    /// `cargo nextest run`.
+    ///
+    /// Requires `cargo-nextest` to be installed.
+    #[clap(name = "nextest")]
+    Nextest,
+}
+
+impl Display for TestRunner {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TestRunner::Libtest => write!(f, "libtest"),
+            TestRunner::Nextest => write!(f, "nextest"),
+        }
+    }
+}
+
 #[derive(Args, Debug)]
 pub struct DifftestDir {
     /// The path to the difftest directory.
@@ -210,6 +246,28 @@ impl Display for IndexesTouchSameFilesReportAction {
     }
 }
 
+#[derive(ValueEnum, Debug, Copy, Clone, Default)]
+pub enum WhoCoversFormat {
+    /// Print the names of the tests, one per line, followed by a tab and
+    /// their hit counts (or `-` if the index cannot tell).
+    #[default]
+    #[clap(name = "text")]
+    Text,
+    /// Print a JSON array with the descriptions of the tests and their
+    /// hit counts.
+    #[clap(name = "json")]
+    Json,
+}
+
+impl Display for WhoCoversFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WhoCoversFormat::Text => write!(f, "text"),
+            WhoCoversFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
 /// The algorithm to use for the analysis.
 #[derive(ValueEnum, Debug, Copy, Clone, Default)]
 pub enum DirtyAlgorithm {
@@ -219,6 +277,28 @@ pub enum DirtyAlgorithm {
     #[default]
     #[clap(name = "fs-mtime")]
     FsMtime,
+    /// Compare the hashes of the contents of the files with the ones
+    /// recorded in the test index.
+    ///
+    /// Unlike `fs-mtime`, it is not affected by `git checkout`, `touch`
+    /// or fresh clones, and unlike the `git-diff-*` algorithms, it does not
+    /// need a git repository, so it works on indexes restored from a cache.
+    ///
+    /// Requires an index (`--index-strategy` other than `never`, or
+    /// `analyze-all-from-index`).
+    #[clap(name = "content-hash")]
+    ContentHash,
+    /// Check whether the source text of every region executed by the test
+    /// can still be found in its file, using the fingerprints recorded in
+    /// the test index.
+    ///
+    /// Unlike `content-hash`, edits to the parts of a file that the test
+    /// did not execute, even if they shift the lines around, do not make
+    /// the test dirty.
+    ///
+    /// Requires an index compiled with `--full-index`.
+    #[clap(name = "region-fingerprints")]
+    RegionFingerprints,
     /// Use the list of files from `git diff`.
     ///
     /// This is a bit slower than `fs-mtime`.
@@ -235,19 +315,54 @@ pub enum DirtyAlgorithm {
     /// See the introductory blog post for more details.
     #[clap(name = "git-diff-hunks")]
     GitDiffHunks,
+    /// Use the list of diff hunks from `git diff`, mapped to the functions
+    /// that were executed by the test.
+    ///
+    /// Coarser than `git-diff-hunks`, but it only needs an index compiled
+    /// with `--functions-index` (or `--full-index`), and `--explain` reports
+    /// the names of the functions that changed.
+    #[clap(name = "git-diff-functions")]
+    GitDiffFunctions,
+    /// Like `git-diff-files`, but ignore the changes to Rust files that
+    /// only touch whitespace or comments (including doc comments), like
+    /// formatting runs.
+    #[clap(name = "git-diff-semantic")]
+    GitDiffSemantic,
 }
 
 impl DirtyAlgorithm {
-    pub fn convert(self, commit: Option<git2::Oid>) -> cargo_difftests::analysis::DirtyAlgorithm {
+    pub fn convert(
+        self,
+        commit: Option<git2::Oid>,
+        target: GitDiffTarget,
+    ) -> cargo_difftests::analysis::DirtyAlgorithm {
         match self {
             DirtyAlgorithm::FsMtime => cargo_difftests::analysis::DirtyAlgorithm::FileSystemMtimes,
+            DirtyAlgorithm::ContentHash => cargo_difftests::analysis::DirtyAlgorithm::ContentHash,
+            DirtyAlgorithm::RegionFingerprints => {
+                cargo_difftests::analysis::DirtyAlgorithm::RegionFingerprints
+            }
             DirtyAlgorithm::GitDiffFiles => cargo_difftests::analysis::DirtyAlgorithm::GitDiff {
                 strategy: GitDiffStrategy::FilesOnly,
                 commit,
+                target,
             },
             DirtyAlgorithm::GitDiffHunks => cargo_difftests::analysis::DirtyAlgorithm::GitDiff {
                 strategy: GitDiffStrategy::Hunks,
                 commit,
+                target,
+            },
+            DirtyAlgorithm::GitDiffFunctions => {
+                cargo_difftests::analysis::DirtyAlgorithm::GitDiff {
+                    strategy: GitDiffStrategy::Functions,
+                    commit,
+                    target,
+                }
+            }
+            DirtyAlgorithm::GitDiffSemantic => cargo_difftests::analysis::DirtyAlgorithm::GitDiff {
+                strategy: GitDiffStrategy::Semantic,
+                commit,
+                target,
             },
         }
     }
@@ -257,8 +372,12 @@ impl Display for DirtyAlgorithm {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             DirtyAlgorithm::FsMtime => write!(f, "fs-mtime"),
+            DirtyAlgorithm::ContentHash => write!(f, "content-hash"),
+            DirtyAlgorithm::RegionFingerprints => write!(f, "region-fingerprints"),
             DirtyAlgorithm::GitDiffFiles => write!(f, "git-diff-files"),
             DirtyAlgorithm::GitDiffHunks => write!(f, "git-diff-hunks"),
+            DirtyAlgorithm::GitDiffFunctions => write!(f, "git-diff-functions"),
+            DirtyAlgorithm::GitDiffSemantic => write!(f, "git-diff-semantic"),
         }
     }
 }
@@ -273,14 +392,10 @@ pub struct AnalysisIndex {
     ///
     /// Only used if `--index-strategy` is set to `always`, `always-and-clean`
     /// or `if-available`, otherwise ignored.
-    #[clap(
-        long,
-        required_if_eq_any = [
-            ("index_strategy", "always"),
-            ("index_strategy", "always-and-clean"),
-            ("index_strategy", "if-available"),
-        ]
-    )]
+    ///
+    /// It can also be set in the configuration, so it is not required by
+    /// the command line, but `index_resolver` fails without it.
+    #[clap(long)]
     pub index_root: Option<PathBuf>,
     /// The strategy to use for the analysis index.
     #[clap(long, default_value_t = Default::default())]
@@ -313,7 +428,7 @@ impl AnalysisIndex {
                 let index_root = self
                     .index_root
                     .as_ref()
-                    .ok_or(IndexResolverError::IndexRootIsNone)?; // should be set by clap
+                    .ok_or(IndexResolverError::IndexRootIsNone)?;
 
                 Ok(Some(DiscoverIndexPathResolver::Remap {
                     from: root.ok_or(IndexResolverError::RootIsNone)?,
@@ -324,7 +439,7 @@ impl AnalysisIndex {
                 let index_root = self
                     .index_root
                     .as_ref()
-                    .ok_or(IndexResolverError::IndexRootIsNone)?; // should be set by clap
+                    .ok_or(IndexResolverError::IndexRootIsNone)?;
 
                 Ok(Some(DiscoverIndexPathResolver::Remap {
                     from: root.ok_or(IndexResolverError::RootIsNone)?,
@@ -341,13 +456,76 @@ pub struct AlgoArgs {
     /// The algorithm to use to find the "dirty" files.
     #[clap(long, default_value_t = Default::default())]
     pub algo: DirtyAlgorithm,
-    /// Optionally, if the algorithm is `git-diff-files` or `git-diff-hunks`,
+    /// Optionally, if the algorithm is one of the `git-diff-*` algorithms,
     /// through this option we can specify another commit to use as the base
     /// for the diff.
     ///
-    /// By default, the commit `HEAD` points to will be used.
+    /// Any revision git understands can be used, like a commit hash,
+    /// a branch, a tag, or `HEAD~3`.
+    ///
+    /// By default, the commit the tests were run at will be used, as it was
+    /// recorded in the difftest directory (or in the index) of every test,
+    /// falling back to the commit `HEAD` points to for the tests that were
+    /// run before it was recorded.
     #[clap(long)]
-    pub commit: Option<git2::Oid>,
+    pub commit: Option<String>,
+    /// Use the merge-base of `HEAD` and the given revision (like `origin/main`)
+    /// as the base commit for the diff, instead of `--commit`.
+    ///
+    /// This is what a pull request would be compared with.
+    #[clap(long, conflicts_with = "commit")]
+    pub merge_base_with: Option<String>,
+    /// Only consider the committed changes, by diffing the tree of the base
+    /// commit with the tree of `HEAD`, ignoring the working tree.
+    ///
+    /// Useful on CI, for runs on a clean checkout.
+    #[clap(long)]
+    pub committed_only: bool,
+}
+
+impl AlgoArgs {
+    /// Resolves `--commit` or `--merge-base-with` to the base commit for
+    /// the `git-diff-*` algorithms, if any of them was given.
+    pub fn base_commit(&self) -> CargoDifftestsResult<Option<git2::Oid>> {
+        let resolve = |repo: &git2::Repository, rev: &str| -> CargoDifftestsResult<git2::Oid> {
+            let commit = repo
+                .revparse_single(rev)
+                .and_then(|obj| obj.peel_to_commit())
+                .with_context(|| format!("could not resolve {rev} to a commit"))?;
+            Ok(commit.id())
+        };
+
+        if let Some(rev) = &self.commit {
+            let repo = git2::Repository::open_from_env()?;
+            Ok(Some(resolve(&repo, rev)?))
+        } else if let Some(rev) = &self.merge_base_with {
+            let repo = git2::Repository::open_from_env()?;
+            let head = resolve(&repo, "HEAD")?;
+            let other = resolve(&repo, rev)?;
+            let merge_base = repo
+                .merge_base(head, other)
+                .with_context(|| format!("could not find the merge-base of HEAD and {rev}"))?;
+            Ok(Some(merge_base))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The [`DirtyAlgorithm`] to use for the analysis, with the base commit
+    /// resolved.
+    ///
+    /// [`DirtyAlgorithm`]: cargo_difftests::analysis::DirtyAlgorithm
+    pub fn dirty_algorithm(
+        &self,
+    ) -> CargoDifftestsResult<cargo_difftests::analysis::DirtyAlgorithm> {
+        let target = if self.committed_only {
+            GitDiffTarget::Head
+        } else {
+            GitDiffTarget::WorkingTree
+        };
+
+        Ok(self.algo.convert(self.base_commit()?, target))
+    }
 }
 
 #[derive(Args, Debug, Clone)]
@@ -380,6 +558,202 @@ pub struct IgnoreRegistryFilesFlag {
     pub ignore_registry_files: bool,
 }
 
+#[derive(Args, Debug, Clone)]
+pub struct PathFilterArgs {
+    /// Only index (and look for changes in) the files matching this glob,
+    /// relative to the root of the workspace, like `src/**`.
+    ///
+    /// Can be passed multiple times. By default, all the files are included.
+    #[clap(long)]
+    pub include: Vec<String>,
+    /// Never index (or look for changes in) the files matching this glob,
+    /// relative to the root of the workspace, like `vendor/**`.
+    ///
+    /// Can be passed multiple times, and takes precedence over `--include`.
+    #[clap(long)]
+    pub exclude: Vec<String>,
+    /// Consider all the tests dirty if a file matching this glob, relative
+    /// to the root of the workspace (like `**/build.rs`), changed.
+    ///
+    /// Can be passed multiple times.
+    #[clap(long)]
+    pub always_dirty: Vec<String>,
+}
+
+impl PathFilterArgs {
+    pub fn path_filter(&self) -> CargoDifftestsResult<PathFilter> {
+        Ok(PathFilter::new(
+            get_workspace_root()?,
+            &self.include,
+            &self.exclude,
+            &self.always_dirty,
+        )?)
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct AlwaysRunArgs {
+    /// Always consider the tests with a name matching this glob (like
+    /// `smoke::*`) dirty, whatever changed.
+    ///
+    /// Can be passed multiple times. Tests can also mark themselves as such,
+    /// with `cargo_difftests_testclient::always_run`.
+    #[clap(long)]
+    pub always_run: Vec<String>,
+}
+
+impl AlwaysRunArgs {
+    pub fn always_run(&self) -> CargoDifftestsResult<AlwaysRun> {
+        Ok(AlwaysRun::new(&self.always_run)?)
+    }
+}
+
+/// Which packages, features and test targets to build the tests of,
+/// passed through to `cargo test` (or `cargo nextest`).
+#[derive(Args, Debug, Clone, Default)]
+pub struct PackageSelectionArgs {
+    /// Only collect the tests of this package.
+    ///
+    /// Can be passed multiple times.
+    #[clap(short = 'p', long = "package", value_name = "SPEC")]
+    pub packages: Vec<String>,
+    /// Collect the tests of all the packages in the workspace.
+    #[clap(long)]
+    pub workspace: bool,
+    /// Leave this package out, with `--workspace` (cargo's `--exclude`,
+    /// as `--exclude` is the path filter here).
+    ///
+    /// Can be passed multiple times.
+    #[clap(long = "exclude-package", value_name = "SPEC", requires = "workspace")]
+    pub exclude_packages: Vec<String>,
+    /// The features to activate, comma or space separated.
+    ///
+    /// Can be passed multiple times.
+    #[clap(short = 'F', long)]
+    pub features: Vec<String>,
+    /// Activate all the available features.
+    #[clap(long)]
+    pub all_features: bool,
+    /// Do not activate the `default` feature.
+    #[clap(long)]
+    pub no_default_features: bool,
+    /// Only collect the unit tests of the library (which are otherwise
+    /// not collected).
+    #[clap(long)]
+    pub lib: bool,
+    /// Collect the tests of all the integration test targets.
+    #[clap(long)]
+    pub tests: bool,
+    /// Only collect the tests of this integration test target.
+    ///
+    /// Can be passed multiple times.
+    #[clap(long = "test", value_name = "NAME")]
+    pub test_targets: Vec<String>,
+    /// Build the tests for this target triple.
+    #[clap(long, value_name = "TRIPLE")]
+    pub target: Option<String>,
+}
+
+impl PackageSelectionArgs {
+    /// The arguments to pass to cargo.
+    pub fn cargo_args(&self) -> Vec<String> {
+        let mut args = vec![];
+
+        for package in &self.packages {
+            args.extend(["--package".to_owned(), package.clone()]);
+        }
+
+        if self.workspace {
+            args.push("--workspace".to_owned());
+        }
+
+        for package in &self.exclude_packages {
+            args.extend(["--exclude".to_owned(), package.clone()]);
+        }
+
+        if self.lib {
+            args.push("--lib".to_owned());
+        }
+
+        if self.tests {
+            args.push("--tests".to_owned());
+        }
+
+        for test in &self.test_targets {
+            args.extend(["--test".to_owned(), test.clone()]);
+        }
+
+        args.extend(self.feature_args());
+
+        args
+    }
+
+    /// The arguments to pass to cargo that select the features and the
+    /// target, but neither the packages nor the test targets.
+    pub fn feature_args(&self) -> Vec<String> {
+        let mut args = self.feature_flags();
+
+        if let Some(target) = &self.target {
+            args.extend(["--target".to_owned(), target.clone()]);
+        }
+
+        args
+    }
+
+    /// The arguments to pass to cargo that only select the features
+    /// (`--features`, `--all-features` and `--no-default-features`).
+    pub fn feature_flags(&self) -> Vec<String> {
+        let mut args = vec![];
+
+        for features in &self.features {
+            args.extend(["--features".to_owned(), features.clone()]);
+        }
+
+        if self.all_features {
+            args.push("--all-features".to_owned());
+        }
+
+        if self.no_default_features {
+            args.push("--no-default-features".to_owned());
+        }
+
+        args
+    }
+
+    /// Whether the package is selected, like cargo would: the packages
+    /// given with `--package` (by name), or all of them with `--workspace`
+    /// (except for `--exclude-package`), or the default members of the
+    /// workspace otherwise.
+    pub fn selects_package(&self, name: &str, is_default_member: bool) -> bool {
+        if !self.packages.is_empty() {
+            self.packages.iter().any(|package| package == name)
+        } else if self.workspace {
+            !self.exclude_packages.iter().any(|package| package == name)
+        } else {
+            is_default_member
+        }
+    }
+
+    /// Whether the tests of a test binary of this kind are collected.
+    ///
+    /// The unit tests of the library are only collected with `--lib`.
+    pub fn collects_kind(&self, kind: &str) -> bool {
+        match kind {
+            "test" => true,
+            "lib" | "rlib" | "dylib" | "cdylib" | "staticlib" | "proc-macro" => self.lib,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Args, Debug, Clone, Copy)]
+pub struct ExplainFlag {
+    /// Whether to also output the reasons for which the tests
+    /// were considered dirty (which files, hunks or regions changed).
+    #[clap(long)]
+    pub explain: bool,
+}
+
 #[derive(Args, Debug, Clone)]
 pub struct ExportProfdataConfigFlags {
     #[clap(flatten)]
@@ -404,6 +778,8 @@ pub enum AnalyzeAllActionKind {
     /// Assert that all the tests are clean.
     ///
     /// If any of them is dirty, the program will exit with a non-zero exit code.
+    /// The tests that must always run (see `--always-run`) are not taken
+    /// into account, as they can never be clean.
     #[clap(name = "assert-clean")]
     AssertClean,
     /// Rerun all the dirty tests.
@@ -449,7 +825,9 @@ impl AnalyzeAllActionArgs {
                 println!("{out_json}");
             }
             AnalyzeAllActionKind::AssertClean => {
-                let dirty = results.iter().any(|r| r.verdict == AnalysisVerdict::Dirty);
+                let dirty = results
+                    .iter()
+                    .any(|r| r.verdict == AnalysisVerdict::Dirty && !r.forced);
 
                 if dirty {
                     bail!("some tests are dirty")
@@ -471,7 +849,7 @@ pub struct DifftestsRoot {
     /// to the index files, and is therefore only required if the
     /// `--index-strategy` is `always`, `always-and-clean`, or
     /// `if-available`.
-    #[clap(long, env = "CARGO_DIFFTESTS_ROOT", default_value = get_default_difftests_dir().unwrap())]
+    #[clap(long, env = "CARGO_DIFFTESTS_ROOT", default_value = get_default_difftests_dir())]
     pub root: Option<PathBuf>,
 }
 
@@ -483,14 +861,47 @@ pub struct DifftestsRootRequired {
     /// to the index files, and is therefore only required if the
     /// `--index-strategy` is `always`, `always-and-clean`, or
     /// `if-available`.
-    #[clap(long, env = "CARGO_DIFFTESTS_ROOT", default_value = get_default_difftests_dir().unwrap())]
+    #[clap(long, env = "CARGO_DIFFTESTS_ROOT", default_value = get_default_difftests_dir())]
     pub root: PathBuf,
 }
 
-pub fn get_target_dir() -> CargoDifftestsResult<PathBuf> {
-    #[derive(serde::Deserialize)]
-    struct Meta {
-        target_directory: PathBuf,
+#[derive(serde::Deserialize)]
+pub struct CargoMetadata {
+    pub target_directory: PathBuf,
+    pub workspace_root: PathBuf,
+    /// The packages of the workspace.
+    pub packages: Vec<CargoMetadataPackage>,
+    /// The ids of the default members of the workspace (empty with
+    /// versions of cargo before 1.71).
+    #[serde(default)]
+    pub workspace_default_members: Vec<String>,
+    /// The `[workspace.metadata]` section of the `Cargo.toml`.
+    pub metadata: Option<serde_json::Value>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct CargoMetadataPackage {
+    pub id: String,
+    pub name: String,
+    pub manifest_path: PathBuf,
+    pub targets: Vec<CargoMetadataTarget>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct CargoMetadataTarget {
+    /// Whether `cargo test --doc` runs the doctests of the target.
+    pub doctest: bool,
+}
+
+/// Runs `cargo metadata` for the workspace in the current directory.
+///
+/// The metadata does not change while `cargo-difftests` runs, so it is only
+/// queried once; the later calls return the same metadata.
+pub fn cargo_metadata() -> CargoDifftestsResult<&'static CargoMetadata> {
+    static METADATA: OnceLock<CargoMetadata> = OnceLock::new();
+
+    if let Some(metadata) = METADATA.get() {
+        return Ok(metadata);
     }
 
     let o = std::process::Command::new(cargo_bin_path())
@@ -505,13 +916,26 @@ pub fn get_target_dir() -> CargoDifftestsResult<PathBuf> {
         bail!("cargo metadata failed: {}", stderr);
     }
 
-    let meta: Meta = serde_json::from_slice(&o.stdout)?;
-    Ok(meta.target_directory)
+    let metadata = serde_json::from_slice(&o.stdout)?;
+    Ok(METADATA.get_or_init(|| metadata))
+}
+
+pub fn get_target_dir() -> CargoDifftestsResult<PathBuf> {
+    Ok(cargo_metadata()?.target_directory.clone())
+}
+
+pub fn get_workspace_root() -> CargoDifftestsResult<PathBuf> {
+    Ok(cargo_metadata()?.workspace_root.clone())
 }
 
-fn get_default_difftests_dir() -> CargoDifftestsResult<OsString> {
-    let target_dir = get_target_dir()?;
-    Ok(target_dir.join("tmp").join("difftests").into_os_string())
+/// The default root of the difftests, in the target directory of the
+/// workspace.
+///
+/// Outside of a workspace, falls back to `target/tmp/difftests`, so that
+/// the command line can still be parsed (e.g. for `--help`).
+fn get_default_difftests_dir() -> OsString {
+    let target_dir = get_target_dir().unwrap_or_else(|_| PathBuf::from("target"));
+    target_dir.join("tmp").join("difftests").into_os_string()
 }
 
 #[derive(Args, Debug, Clone)]
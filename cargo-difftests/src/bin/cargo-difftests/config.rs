@@ -0,0 +1,311 @@
+/*
+ *        Copyright (c) 2023-2024 Dinu Blanovschi
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        https://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+//! The project configuration, from a `difftests.toml` file in the root of
+//! the workspace, or from the `[workspace.metadata.difftests]` section of
+//! its `Cargo.toml`.
+//!
+//! Every key is the default value of the command line argument with the
+//! same name, so the arguments (and environment variables) still take
+//! precedence over it.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+use clap::builder::ArgPredicate;
+use clap::{Arg, Command, ValueEnum};
+use log::debug;
+
+use crate::cli_core::{cargo_metadata, DirtyAlgorithm, TestRunner};
+use crate::CargoDifftestsResult;
+
+pub const CONFIG_FILE_NAME: &str = "difftests.toml";
+
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DifftestsConfig {
+    /// `--root`
+    root: Option<PathBuf>,
+    /// `--index-root`
+    index_root: Option<PathBuf>,
+    /// `--algo`
+    algo: Option<String>,
+    /// `--commit`
+    commit: Option<String>,
+    /// `--merge-base-with`
+    merge_base_with: Option<String>,
+    /// `--committed-only`
+    committed_only: Option<bool>,
+    /// `--full-index`
+    full_index: Option<bool>,
+    /// `--functions-index`
+    functions_index: Option<bool>,
+    /// `--bin`, as many times as needed
+    #[serde(default)]
+    bin: Vec<PathBuf>,
+    /// `--runner`
+    runner: Option<PathBuf>,
+    /// `--include`, as many times as needed
+    #[serde(default)]
+    include: Vec<String>,
+    /// `--exclude`, as many times as needed
+    #[serde(default)]
+    exclude: Vec<String>,
+    /// `--always-dirty`, as many times as needed
+    #[serde(default)]
+    always_dirty: Vec<String>,
+    /// `--always-run`, as many times as needed
+    #[serde(default)]
+    always_run: Vec<String>,
+    /// `--test-runner`
+    test_runner: Option<String>,
+    /// `--nextest-profile`
+    nextest_profile: Option<String>,
+}
+
+impl DifftestsConfig {
+    /// Loads the configuration of the workspace in the current directory,
+    /// if it has one.
+    ///
+    /// Outside of a workspace (or if `cargo metadata` fails otherwise), there
+    /// is no configuration to load, and the default one is used; only a
+    /// configuration that exists but is invalid is an error.
+    ///
+    /// Relative paths in it are resolved from the root of the workspace.
+    pub fn load() -> CargoDifftestsResult<Self> {
+        let metadata = match cargo_metadata() {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                debug!("no workspace to load the configuration from: {e}");
+                return Ok(Self::default());
+            }
+        };
+
+        let file = metadata.workspace_root.join(CONFIG_FILE_NAME);
+        let section = metadata
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.get("difftests"));
+
+        let config = match (file.is_file(), section) {
+            (true, Some(_)) => bail!(
+                "both {} and [workspace.metadata.difftests] exist, only one of them can be used",
+                file.display()
+            ),
+            (true, None) => {
+                let s = std::fs::read_to_string(&file)?;
+                toml::from_str::<Self>(&s).map_err(|e| {
+                    anyhow::anyhow!("invalid configuration in {}: {e}", file.display())
+                })?
+            }
+            (false, Some(section)) => Self::deserialize(section).map_err(|e| {
+                anyhow::anyhow!("invalid configuration in [workspace.metadata.difftests]: {e}")
+            })?,
+            (false, None) => return Ok(Self::default()),
+        };
+
+        config.validate()?;
+
+        Ok(config.resolve_paths(&metadata.workspace_root))
+    }
+
+    fn deserialize(value: &serde_json::Value) -> serde_json::Result<Self> {
+        serde::Deserialize::deserialize(value)
+    }
+
+    fn validate(&self) -> CargoDifftestsResult {
+        if let Some(algo) = self
+            .algo
+            .as_ref()
+            .filter(|algo| DirtyAlgorithm::from_str(algo, false).is_err())
+        {
+            let possible = DirtyAlgorithm::value_variants()
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+
+            bail!(
+                "invalid configuration: unknown algo `{algo}`, expected one of {}",
+                possible.join(", ")
+            );
+        }
+
+        if let Some(test_runner) = self
+            .test_runner
+            .as_ref()
+            .filter(|test_runner| TestRunner::from_str(test_runner, false).is_err())
+        {
+            let possible = TestRunner::value_variants()
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+
+            bail!(
+                "invalid configuration: unknown test-runner `{test_runner}`, expected one of {}",
+                possible.join(", ")
+            );
+        }
+
+        if self.commit.is_some() && self.merge_base_with.is_some() {
+            bail!("invalid configuration: `commit` and `merge-base-with` cannot be used together");
+        }
+
+        for pattern in self
+            .include
+            .iter()
+            .chain(&self.exclude)
+            .chain(&self.always_dirty)
+            .chain(&self.always_run)
+        {
+            if let Err(e) = glob::Pattern::new(pattern) {
+                bail!("invalid configuration: invalid glob pattern `{pattern}`: {e}");
+            }
+        }
+
+        if self.full_index == Some(true) && self.functions_index == Some(true) {
+            bail!(
+                "invalid configuration: `full-index` and `functions-index` cannot be used together"
+            );
+        }
+
+        Ok(())
+    }
+
+    fn resolve_paths(mut self, workspace_root: &Path) -> Self {
+        let resolve = |path: &mut PathBuf| {
+            if path.is_relative() {
+                *path = workspace_root.join(&*path);
+            }
+        };
+
+        self.root
+            .iter_mut()
+            .chain(self.index_root.iter_mut())
+            .chain(self.bin.iter_mut())
+            .for_each(resolve);
+
+        // a bare name is looked up in `PATH`
+        if let Some(runner) = self
+            .runner
+            .as_mut()
+            .filter(|runner| runner.components().count() > 1)
+        {
+            resolve(runner);
+        }
+
+        self
+    }
+
+    /// Makes the values in the configuration the defaults of the arguments
+    /// of the command and all of its subcommands.
+    pub fn apply(&self, cmd: Command) -> Command {
+        let cmd = cmd.mut_args(|arg| self.apply_to_arg(arg));
+
+        let subcommands = cmd
+            .get_subcommands()
+            .map(|it| it.get_name().to_owned())
+            .collect::<Vec<_>>();
+
+        subcommands.into_iter().fold(cmd, |cmd, name| {
+            cmd.mut_subcommand(name, |sub| self.apply(sub))
+        })
+    }
+
+    fn apply_to_arg(&self, arg: Arg) -> Arg {
+        fn path(p: &Path) -> String {
+            p.to_string_lossy().into_owned()
+        }
+
+        let id = arg.get_id().clone();
+
+        match id.as_str() {
+            "root" => match &self.root {
+                Some(root) => arg.default_value(path(root)),
+                None => arg,
+            },
+            // it is required by some of the commands
+            "index_root" => match &self.index_root {
+                Some(index_root) => arg.default_value(path(index_root)).required(false),
+                None => arg,
+            },
+            "algo" => match &self.algo {
+                Some(algo) => arg.default_value(algo.clone()),
+                None => arg,
+            },
+            // `--commit` and `--merge-base-with` conflict with each other, so
+            // the one from the configuration is dropped if the other one is given
+            "commit" => match &self.commit {
+                Some(commit) => arg.default_value(commit.clone()).default_value_if(
+                    "merge_base_with",
+                    ArgPredicate::IsPresent,
+                    None::<&str>,
+                ),
+                None => arg,
+            },
+            "merge_base_with" => match &self.merge_base_with {
+                Some(rev) => arg.default_value(rev.clone()).default_value_if(
+                    "commit",
+                    ArgPredicate::IsPresent,
+                    None::<&str>,
+                ),
+                None => arg,
+            },
+            "committed_only" => match self.committed_only {
+                Some(committed_only) => arg.default_value(committed_only.to_string()),
+                None => arg,
+            },
+            // same for `--full-index` and `--functions-index`
+            "full_index" => match self.full_index {
+                Some(full_index) => arg.default_value(full_index.to_string()).default_value_if(
+                    "functions_index",
+                    "true",
+                    None::<&str>,
+                ),
+                None => arg,
+            },
+            "functions_index" => match self.functions_index {
+                Some(functions_index) => arg
+                    .default_value(functions_index.to_string())
+                    .default_value_if("full_index", "true", None::<&str>),
+                None => arg,
+            },
+            "other_binaries" if !self.bin.is_empty() => {
+                arg.default_values(self.bin.iter().map(|it| path(it)))
+            }
+            "runner" => match &self.runner {
+                Some(runner) => arg.default_value(path(runner)),
+                None => arg,
+            },
+            "include" if !self.include.is_empty() => arg.default_values(self.include.clone()),
+            "exclude" if !self.exclude.is_empty() => arg.default_values(self.exclude.clone()),
+            "always_dirty" if !self.always_dirty.is_empty() => {
+                arg.default_values(self.always_dirty.clone())
+            }
+            "always_run" if !self.always_run.is_empty() => {
+                arg.default_values(self.always_run.clone())
+            }
+            "test_runner" => match &self.test_runner {
+                Some(test_runner) => arg.default_value(test_runner.clone()),
+                None => arg,
+            },
+            "nextest_profile" => match &self.nextest_profile {
+                Some(profile) => arg.default_value(profile.clone()),
+                None => arg,
+            },
+            _ => arg,
+        }
+    }
+}
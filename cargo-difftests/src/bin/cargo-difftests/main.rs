@@ -17,11 +17,13 @@
 #![feature(exit_status_error)]
 
 use cargo_difftests::bin_context::CargoDifftestsContext;
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches};
+use config::DifftestsConfig;
 use ops::CargoApp;
 use prodash::render::line;
 
 mod cli_core;
+mod config;
 mod ops;
 
 pub type CargoDifftestsResult<T = ()> = anyhow::Result<T>;
@@ -43,7 +45,10 @@ fn main_impl() -> CargoDifftestsResult {
         .auto_configure(line::StreamKind::Stderr),
     );
 
-    let CargoApp::Difftests { app } = CargoApp::parse();
+    let config = DifftestsConfig::load()?;
+    let matches = config.apply(CargoApp::command()).get_matches();
+    let CargoApp::Difftests { app } =
+        CargoApp::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
     app.run(&ctxt)?;
 
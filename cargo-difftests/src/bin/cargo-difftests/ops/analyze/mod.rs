@@ -1,16 +1,18 @@
 use std::path::PathBuf;
 
-use cargo_difftests::{bin_context::CargoDifftestsContext, difftest::Difftest};
+use cargo_difftests::{analysis::{AlwaysRun, DirtyAlgorithm, GitDiffChangesCache}, bin_context::CargoDifftestsContext, difftest::{CurrentEnvFingerprints, Difftest}, path_filter::PathFilter};
 use clap::Parser;
 
 use crate::{
     cli_core::{
-        AlgoArgs, AnalysisIndex, DifftestDir, DifftestsRoot, DirtyAlgorithm, ExportProfdataConfigFlags, IgnoreRegistryFilesFlag
+        AlgoArgs, AlwaysRunArgs, AnalysisIndex, DifftestDir, DifftestsRoot, ExplainFlag, ExportProfdataConfigFlags, IgnoreRegistryFilesFlag, PathFilterArgs
     },
     CargoDifftestsResult,
 };
 
-use crate::ops::core::{analyze_single_test, display_analysis_result};
+use crate::ops::core::{
+    analyze_single_test, display_analysis_report, display_analysis_result,
+};
 
 #[derive(Parser, Debug)]
 pub struct AnalyzeCommand {
@@ -34,6 +36,15 @@ pub struct AnalyzeCommand {
 
     #[clap(flatten)]
     ignore_registry_files: IgnoreRegistryFilesFlag,
+
+    #[clap(flatten)]
+    path_filter: PathFilterArgs,
+
+    #[clap(flatten)]
+    always_run: AlwaysRunArgs,
+
+    #[clap(flatten)]
+    explain: ExplainFlag,
 }
 
 impl AnalyzeCommand {
@@ -42,12 +53,14 @@ impl AnalyzeCommand {
             ctxt,
             self.dir.dir,
             self.force,
-            self.algo.algo,
-            self.algo.commit,
+            self.algo.dirty_algorithm()?,
             self.export_profdata_config_flags,
             self.root.root,
             self.analysis_index,
             self.ignore_registry_files,
+            self.path_filter.path_filter()?,
+            self.always_run.always_run()?,
+            self.explain,
         )
     }
 }
@@ -56,12 +69,14 @@ fn run_analyze(
     ctxt: &CargoDifftestsContext,
     dir: PathBuf,
     force: bool,
-    algo: DirtyAlgorithm,
-    commit: Option<git2::Oid>,
+    dirty_algorithm: DirtyAlgorithm,
     export_profdata_config_flags: ExportProfdataConfigFlags,
     root: Option<PathBuf>,
     analysis_index: AnalysisIndex,
     ignore_registry_files: IgnoreRegistryFilesFlag,
+    path_filter: PathFilter,
+    always_run: AlwaysRun,
+    explain: ExplainFlag,
 ) -> CargoDifftestsResult {
     let resolver = analysis_index.index_resolver(root)?;
 
@@ -70,15 +85,22 @@ fn run_analyze(
     let r = analyze_single_test(
         &mut difftest,
         force,
-        algo,
-        commit,
+        &dirty_algorithm,
         export_profdata_config_flags,
         &analysis_index,
         resolver.as_ref(),
         ignore_registry_files,
+        &path_filter,
+        &always_run,
+        &mut CurrentEnvFingerprints::default(),
+        &mut GitDiffChangesCache::default(),
     )?;
 
-    display_analysis_result(r);
+    if explain.explain {
+        display_analysis_report(&r);
+    } else {
+        display_analysis_result(r.result());
+    }
 
     Ok(())
 }
@@ -1,13 +1,19 @@
 use std::path::PathBuf;
 
-use cargo_difftests::{bin_context::CargoDifftestsContext, AnalyzeAllSingleTest};
+use cargo_difftests::{
+    analysis::{AlwaysRun, DirtyAlgorithm, GitDiffChangesCache},
+    bin_context::CargoDifftestsContext,
+    difftest::CurrentEnvFingerprints,
+    path_filter::PathFilter,
+    AnalyzeAllSingleTest,
+};
 use clap::Parser;
 use prodash::unit;
 
 use crate::{
     cli_core::{
-        AlgoArgs, AnalysisIndex, AnalyzeAllActionArgs, DifftestsRootDir, DirtyAlgorithm,
-        ExportProfdataConfigFlags, IgnoreRegistryFilesFlag,
+        AlgoArgs, AlwaysRunArgs, AnalysisIndex, AnalyzeAllActionArgs, DifftestsRootDir, ExplainFlag,
+        ExportProfdataConfigFlags, IgnoreRegistryFilesFlag, PathFilterArgs,
     },
     CargoDifftestsResult,
 };
@@ -20,6 +26,10 @@ pub struct AnalyzeAllCommand {
     dir: DifftestsRootDir,
     #[clap(flatten)]
     ignore_registry_files: IgnoreRegistryFilesFlag,
+    #[clap(flatten)]
+    path_filter: PathFilterArgs,
+    #[clap(flatten)]
+    always_run: AlwaysRunArgs,
     /// Whether to force the generation of intermediary files.
     ///
     /// Without this flag, if the intermediary files are already present,
@@ -41,6 +51,8 @@ pub struct AnalyzeAllCommand {
     ignore_incompatible: bool,
     #[clap(flatten)]
     action_args: AnalyzeAllActionArgs,
+    #[clap(flatten)]
+    explain: ExplainFlag,
 }
 
 impl AnalyzeAllCommand {
@@ -49,13 +61,15 @@ impl AnalyzeAllCommand {
             ctxt,
             self.dir.dir,
             self.force,
-            self.algo.algo,
-            self.algo.commit,
+            self.algo.dirty_algorithm()?,
             self.export_profdata_config_flags,
             self.analysis_index,
             self.ignore_incompatible,
             self.action_args,
             self.ignore_registry_files,
+            self.path_filter.path_filter()?,
+            self.always_run.always_run()?,
+            self.explain,
         )
     }
 }
@@ -64,18 +78,23 @@ fn run_analyze_all(
     ctxt: &CargoDifftestsContext,
     dir: PathBuf,
     force: bool,
-    algo: DirtyAlgorithm,
-    commit: Option<git2::Oid>,
+    dirty_algorithm: DirtyAlgorithm,
     export_profdata_config_flags: ExportProfdataConfigFlags,
     analysis_index: AnalysisIndex,
     ignore_incompatible: bool,
     action_args: AnalyzeAllActionArgs,
     ignore_registry_files: IgnoreRegistryFilesFlag,
+    path_filter: PathFilter,
+    always_run: AlwaysRun,
+    explain: ExplainFlag,
 ) -> CargoDifftestsResult {
     let resolver = analysis_index.index_resolver(Some(dir.clone()))?;
     let discovered =
         discover_difftests(dir, analysis_index.index_root.clone(), ignore_incompatible)?;
 
+    let mut environment = CurrentEnvFingerprints::default();
+    let mut git_diff_cache = GitDiffChangesCache::default();
+
     let mut results = vec![];
 
     let mut pb = ctxt.new_child("Analyzing tests");
@@ -85,18 +104,24 @@ fn run_analyze_all(
         let r = analyze_single_test(
             &mut difftest,
             force,
-            algo,
-            commit,
+            &dirty_algorithm,
             export_profdata_config_flags.clone(),
             &analysis_index,
             resolver.as_ref(),
             ignore_registry_files,
+            &path_filter,
+            &always_run,
+            &mut environment,
+            &mut git_diff_cache,
         )?;
 
         let result = AnalyzeAllSingleTest {
             test_info: difftest.test_info()?,
+            outcome: difftest.outcome().cloned(),
             difftest: Some(difftest),
-            verdict: r.into(),
+            verdict: r.result().into(),
+            forced: r.forced(),
+            report: explain.explain.then_some(r),
         };
 
         results.push(result);
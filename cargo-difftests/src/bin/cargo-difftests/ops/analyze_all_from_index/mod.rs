@@ -1,15 +1,18 @@
-use std::{ffi::OsString, path::PathBuf};
+use std::path::PathBuf;
 
 use cargo_difftests::{
-    analysis::{AnalysisConfig, AnalysisContext},
+    analysis::{AlwaysRun, AnalysisConfig, AnalysisContext, AnalysisReport, DirtyAlgorithm, GitDiffChangesCache},
     bin_context::CargoDifftestsContext,
+    difftest::{CurrentEnvFingerprints, TestInfo, TestOutcome},
+    index_db::{IndexDatabase, IndexDbAnalysisConfig, INDEX_DB_FILE_NAME},
+    path_filter::PathFilter,
     AnalyzeAllSingleTest,
 };
 use clap::Parser;
 use prodash::unit;
 
 use crate::{
-    cli_core::{AlgoArgs, AnalysisIndex, AnalyzeAllActionArgs, DifftestsRootRequired, DirtyAlgorithm, ExportProfdataConfigFlags, IgnoreRegistryFilesFlag},
+    cli_core::{AlgoArgs, AlwaysRunArgs, AnalyzeAllActionArgs, ExplainFlag, PathFilterArgs},
     ops::core::discover_indexes_to_vec,
     CargoDifftestsResult,
 };
@@ -23,6 +26,19 @@ pub struct AnalyzeAllFromIndexCommand {
     pub(crate) algo: AlgoArgs,
     #[clap(flatten)]
     pub(crate) action_args: AnalyzeAllActionArgs,
+    #[clap(flatten)]
+    pub(crate) explain: ExplainFlag,
+    #[clap(flatten)]
+    pub(crate) path_filter: PathFilterArgs,
+    #[clap(flatten)]
+    pub(crate) always_run: AlwaysRunArgs,
+    /// Whether to use (and update) the index database in the index root,
+    /// which aggregates all the indexes in a single file.
+    ///
+    /// With the git-diff algorithms, the diff is then computed only once,
+    /// and checked against all the tests at the same time.
+    #[clap(long)]
+    pub(crate) index_db: bool,
 }
 
 impl AnalyzeAllFromIndexCommand {
@@ -30,9 +46,12 @@ impl AnalyzeAllFromIndexCommand {
         run_analyze_all_from_index(
             &ctxt,
             self.index_root,
-            self.algo.algo,
-            self.algo.commit,
+            self.algo.dirty_algorithm()?,
             self.action_args,
+            self.explain,
+            self.path_filter.path_filter()?,
+            self.always_run.always_run()?,
+            self.index_db,
         )
     }
 }
@@ -40,11 +59,53 @@ impl AnalyzeAllFromIndexCommand {
 fn run_analyze_all_from_index(
     ctxt: &CargoDifftestsContext,
     index_root: PathBuf,
-    algo: DirtyAlgorithm,
-    commit: Option<git2::Oid>,
+    dirty_algorithm: DirtyAlgorithm,
     action_args: AnalyzeAllActionArgs,
+    explain: ExplainFlag,
+    path_filter: PathFilter,
+    always_run: AlwaysRun,
+    index_db: bool,
 ) -> CargoDifftestsResult {
-    let indexes = {
+    let mut environment = CurrentEnvFingerprints::default();
+    let mut git_diff_cache = GitDiffChangesCache::default();
+
+    let indexes = if index_db {
+        let db = IndexDatabase::open(&index_root)?;
+        db.write_to_file(&index_root.join(INDEX_DB_FILE_NAME))?;
+
+        if let DirtyAlgorithm::GitDiff {
+            strategy,
+            commit,
+            target,
+        } = dirty_algorithm
+        {
+            let config = IndexDbAnalysisConfig {
+                strategy,
+                commit,
+                target,
+                environment: Some(&mut environment),
+                path_filter: &path_filter,
+                always_run: &always_run,
+            };
+
+            let reports = db.git_diff_analysis(config, &mut git_diff_cache)?;
+
+            let results = db
+                .tests
+                .into_iter()
+                .zip(reports)
+                .map(|(test, r)| {
+                    single_test_result(test.index.test_info, test.index.outcome, r, explain)
+                })
+                .collect::<Vec<_>>();
+
+            action_args.perform_for(ctxt, &results)?;
+
+            return Ok(());
+        }
+
+        db.into_test_indexes()
+    } else {
         let mut indexes = vec![];
         discover_indexes_to_vec(&index_root, &mut indexes)?;
         indexes
@@ -57,23 +118,28 @@ fn run_analyze_all_from_index(
 
     for index in indexes {
         let test_desc = index.test_info.clone();
+        let outcome = index.outcome.clone();
+
+        let current_environment = index
+            .env_fingerprint
+            .as_ref()
+            .and_then(|recorded| environment.matching(recorded))
+            .cloned();
 
         let r = {
             let mut analysis_cx = AnalysisContext::from_index(index);
-            analysis_cx.run(&AnalysisConfig {
-                dirty_algorithm: algo.convert(commit),
+            let config = AnalysisConfig {
+                dirty_algorithm: dirty_algorithm.clone(),
                 error_on_invalid_config: true,
-            })?;
-            analysis_cx.finish_analysis()
-        };
-
-        let result = AnalyzeAllSingleTest {
-            test_info: test_desc,
-            difftest: None,
-            verdict: r.into(),
+                environment: current_environment,
+                path_filter: path_filter.clone(),
+                always_run: always_run.clone(),
+            };
+            analysis_cx.run_with_git_diff_cache(&config, &mut git_diff_cache)?;
+            analysis_cx.finish_analysis_with_report()
         };
 
-        results.push(result);
+        results.push(single_test_result(test_desc, outcome, r, explain));
         pb.inc();
     }
 
@@ -83,3 +149,19 @@ fn run_analyze_all_from_index(
 
     Ok(())
 }
+
+fn single_test_result(
+    test_info: TestInfo,
+    outcome: Option<TestOutcome>,
+    r: AnalysisReport,
+    explain: ExplainFlag,
+) -> AnalyzeAllSingleTest {
+    AnalyzeAllSingleTest {
+        test_info,
+        difftest: None,
+        verdict: r.result().into(),
+        forced: r.forced(),
+        outcome,
+        report: explain.explain.then_some(r),
+    }
+}
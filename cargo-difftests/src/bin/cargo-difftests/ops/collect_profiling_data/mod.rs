@@ -1,15 +1,42 @@
-use std::path::PathBuf;
+use std::{
+    collections::BTreeSet,
+    io::Write,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
 
-use cargo_difftests::{bin_context::CargoDifftestsContext, difftest::Difftest};
+use anyhow::bail;
+use cargo_difftests::{
+    bin_context::CargoDifftestsContext,
+    difftest::{
+        Difftest, DiscoverIndexPathResolver, EnvFingerprint, ExportProfdataConfig, GitState,
+        TestOutcome, TestStatus,
+    },
+    index_data::{hash_file, TestIndex},
+    nextest::NextestTest,
+    path_filter::PathFilter,
+};
 use clap::Parser;
+use log::warn;
 use prodash::unit;
 
 use crate::{
-    cli_core::{AnalysisIndex, DifftestsRoot, DifftestsRootRequired, ExportProfdataConfigFlags, IgnoreRegistryFilesFlag},
+    cli_core::{
+        get_target_dir, get_workspace_root, AnalysisIndex, DifftestsRoot, DifftestsRootRequired,
+        ExportProfdataConfigFlags, IgnoreRegistryFilesFlag, PackageSelectionArgs, PathFilterArgs,
+        TestRunner,
+    },
     CargoDifftestsResult,
 };
 
-use super::core::{collect_test_harnesses, compile_test_index_config};
+use super::core::{
+    build_doctests, collect_doctests, collect_nextest_tests, collect_test_harnesses,
+    compile_test_index_config, run_nextest, BuiltDoctests, ListedTest,
+};
 
 #[derive(Parser, Debug)]
 pub struct CollectProfilingDataCommand {
@@ -25,154 +52,773 @@ pub struct CollectProfilingDataCommand {
     #[clap(flatten)]
     ignore_registry_files: IgnoreRegistryFilesFlag,
 
+    #[clap(flatten)]
+    path_filter: PathFilterArgs,
+
+    #[clap(flatten)]
+    package_selection: PackageSelectionArgs,
+
+    /// Only run the tests whose name contains this (or is equal to it,
+    /// with `--exact`).
+    ///
+    /// Can be passed multiple times, to run the tests matching any of them.
     #[clap(long)]
-    filter: Option<String>,
+    filter: Vec<String>,
 
     #[clap(long)]
     exact: bool,
+
+    /// Only run the test with this name in the test binary with this
+    /// nextest binary id (like `my-crate::tests`).
+    ///
+    /// Can be passed multiple times, and along with `--filter`, to run all
+    /// the tests matching any of them. Only supported with
+    /// `--test-runner nextest`.
+    #[clap(long, num_args = 2, value_names = ["BINARY_ID", "TEST"])]
+    nextest_test: Vec<String>,
+
+    /// What to list and run the tests with.
+    #[clap(long, default_value_t = Default::default())]
+    test_runner: TestRunner,
+
+    /// The nextest profile to run the tests with (like `ci`), for its
+    /// retries, timeouts or JUnit output.
+    ///
+    /// Only used with `--test-runner nextest`.
+    #[clap(long)]
+    nextest_profile: Option<String>,
+
+    /// The number of tests to run in parallel (1 by default).
+    ///
+    /// Every test still runs in its own process, with its own difftest
+    /// directory and `.profraw` files, so the profiling data of
+    /// different tests does not get mixed up. Merging the profiling data
+    /// and compiling the index is also done by the worker that ran the test.
+    ///
+    /// With `--test-runner nextest`, this is passed to nextest as
+    /// `--test-threads`; without it, nextest runs as many tests in parallel
+    /// as its profile says.
+    #[clap(short = 'j', long)]
+    jobs: Option<NonZeroUsize>,
+
+    /// Run all the tests, regardless of failure.
+    ///
+    /// Without this flag, the first test that fails stops the whole run.
+    ///
+    /// With it, every failure is recorded, the profiling data and indexes
+    /// of the other tests are still collected, and the failed tests are
+    /// marked so that they are always considered dirty by the analysis.
+    /// A summary of all the failures is printed at the end.
+    #[clap(long)]
+    no_fail_fast: bool,
+
+    /// Trace the files each test opens for reading, and track the ones in
+    /// the workspace (outside of the target directory) as inputs of the
+    /// test, like `cargo_difftests_testclient::track_file` does.
+    ///
+    /// Only supported on Linux, where the tests are run under `ptrace`.
+    #[clap(long)]
+    trace_file_access: bool,
+
+    /// An environment variable whose value is recorded along with the
+    /// profiling data, in addition to `RUSTFLAGS` and
+    /// `CARGO_ENCODED_RUSTFLAGS`.
+    ///
+    /// If the value of any of them changes, the test is considered dirty,
+    /// as do changes to the `rustc` version, the `Cargo.lock` and the
+    /// enabled features.
+    #[clap(long = "fingerprint-env", value_name = "VAR")]
+    fingerprint_env: Vec<String>,
+
+    /// Instrument the dependencies of the workspace for coverage too, and
+    /// record which of them (and at which version) each test executed code
+    /// from.
+    ///
+    /// When `Cargo.lock` changes, only the tests that executed code from
+    /// the dependencies that changed are then considered dirty, instead
+    /// of all of them.
+    #[clap(long)]
+    instrument_dependencies: bool,
+
+    /// Also collect the profiling data of the doctests of the libraries of
+    /// the selected packages.
+    ///
+    /// The doctests of each package are compiled at once, with
+    /// `cargo test --doc` (and the `--no-run` and `--persist-doctests`
+    /// flags of rustdoc, which need a nightly toolchain). Every doctest is
+    /// then run on its own, and its binary is kept in its difftest
+    /// directory, under `<root>/<package>/doctests/`.
+    #[clap(long)]
+    doctests: bool,
 }
 
 impl CollectProfilingDataCommand {
     pub fn run(self, ctxt: &CargoDifftestsContext) -> CargoDifftestsResult {
-        run_collect_profiling_data(
-            ctxt,
-            self.root.root,
-            self.export_profdata_args,
-            self.index_compilation_args.compile_index,
-            self.index_compilation_args.and_clean,
-            self.index_compilation_args,
-            self.ignore_registry_files,
-            self.filter,
-            self.exact,
-        )
+        run_collect_profiling_data(ctxt, self)
     }
 }
 
 fn run_collect_profiling_data(
     ctxt: &CargoDifftestsContext,
-    root: PathBuf,
-    export_profdata_args: ExportProfdataConfigFlags,
-    compile_index: bool,
-    and_clean: bool,
-    index_compilation_args: AnalysisIndex,
-    ignore_registry_files: IgnoreRegistryFilesFlag,
-    filter: Option<String>,
-    exact: bool,
+    cmd: CollectProfilingDataCommand,
 ) -> CargoDifftestsResult {
+    let CollectProfilingDataCommand {
+        root: DifftestsRootRequired { root },
+        export_profdata_args,
+        index_compilation_args,
+        ignore_registry_files,
+        path_filter,
+        package_selection,
+        filter,
+        exact,
+        nextest_test,
+        test_runner,
+        nextest_profile,
+        jobs,
+        no_fail_fast,
+        trace_file_access,
+        fingerprint_env,
+        instrument_dependencies,
+        doctests,
+    } = cmd;
+
+    let compile_index = index_compilation_args.compile_index;
+    let and_clean = index_compilation_args.and_clean;
+    let path_filter = path_filter.path_filter()?;
+    let package_selection = &package_selection;
+
     let index_resolver = index_compilation_args.index_resolver(Some(root.clone()))?;
 
     let mut pb = ctxt.new_child("Collecting profiling data for tests");
     pb.init(Some(1), None);
 
-    let test_harnesses = collect_test_harnesses()?;
+    if test_runner == TestRunner::Nextest && trace_file_access {
+        bail!("--trace-file-access is not supported with --test-runner nextest");
+    }
+
+    if doctests && trace_file_access {
+        bail!("--trace-file-access is not supported with --doctests");
+    }
+
+    if test_runner != TestRunner::Nextest && !nextest_test.is_empty() {
+        bail!("--nextest-test is only supported with --test-runner nextest");
+    }
+
+    let mut tests = match test_runner {
+        TestRunner::Libtest => {
+            let test_harnesses =
+                collect_test_harnesses(instrument_dependencies, package_selection)?;
+
+            let mut test_harnesses_pb = pb.add_child("Collecting tests");
+            test_harnesses_pb.init(
+                Some(test_harnesses.len()),
+                Some(unit::label("test harnesses")),
+            );
+
+            let mut tests = vec![];
 
-    let mut test_harnesses_pb = pb.add_child("Collecting tests");
-    test_harnesses_pb.init(
-        Some(test_harnesses.len()),
-        Some(unit::label("test harnesses")),
-    );
+            for test_harness in test_harnesses {
+                tests.extend(test_harness.list_tests()?);
 
-    let mut tests = vec![];
+                test_harnesses_pb.inc();
+            }
+
+            test_harnesses_pb.done("done");
+
+            tests
+        }
+        TestRunner::Nextest => collect_nextest_tests(instrument_dependencies, package_selection)?,
+    };
+
+    // nextest does not support doctests, so they are always run with
+    // `cargo test --doc`
+    if doctests {
+        tests.extend(collect_doctests(
+            instrument_dependencies,
+            package_selection,
+        )?);
+    }
 
-    for test_harness in test_harnesses {
-        let mut t = test_harness.list_tests()?;
+    // (binary id, test name) pairs
+    let nextest_tests = nextest_test
+        .chunks(2)
+        .map(|it| (it[0].as_str(), it[1].as_str()))
+        .collect::<Vec<_>>();
 
-        if let Some(filter) = filter.as_ref() {
-            t.retain(|it| {
+    if !filter.is_empty() || !nextest_tests.is_empty() {
+        tests.retain(|it| {
+            let matches_filter = filter.iter().any(|filter| {
                 if exact {
                     it.get_name() == filter
                 } else {
                     it.get_name().contains(filter)
                 }
             });
-        }
-
-        tests.extend(t);
 
-        test_harnesses_pb.inc();
+            matches_filter
+                || nextest_tests.iter().any(|&(binary_id, name)| {
+                    it.get_nextest_binary_id() == Some(binary_id) && it.get_name() == name
+                })
+        });
     }
 
-    test_harnesses_pb.done("done");
-
     let mut tests_pb = pb.add_child("Collecting profiling data");
 
     tests_pb.init(Some(tests.len()), Some(unit::label("tests")));
 
     let export_profdata_config = export_profdata_args.config(ignore_registry_files);
 
-    for test in tests {
-        let harness_name = test.get_harness_name().clone();
-        let name = test.get_name().clone();
+    // the commit the tests are run at, which the git-diff algorithms
+    // will diff from by default
+    let git_state = GitState::current()?;
+
+    // and the environment they are run in, with the always dirty files,
+    // which the analyses that compare contents compare by hash
+    let always_dirty_file_hashes = path_filter
+        .always_dirty_files()
+        .iter()
+        .filter_map(|file| {
+            let hash = hash_file(file)?;
+            Some((path_filter.relative_path(file).to_path_buf(), hash))
+        })
+        .collect();
+
+    let env_fingerprint = EnvFingerprint {
+        instrumented_dependencies: instrument_dependencies,
+        always_dirty_file_hashes: Some(always_dirty_file_hashes),
+        ..EnvFingerprint::current(&fingerprint_env, &package_selection.feature_flags())?
+    };
+
+    let traced_files_filter = if trace_file_access {
+        let workspace_root = get_workspace_root()?;
+
+        Some(TracedFilesFilter {
+            excluded: vec![get_target_dir()?, root.clone(), workspace_root.join(".git")],
+            workspace_root,
+        })
+    } else {
+        None
+    };
+
+    let config = CollectProfilingDataConfig {
+        root: &root,
+        compile_index,
+        and_clean,
+        index_resolver: index_resolver.as_ref(),
+        index_compilation_args: &index_compilation_args,
+        export_profdata_config: &export_profdata_config,
+        ignore_registry_files,
+        path_filter: &path_filter,
+        no_fail_fast,
+        git_state: git_state.as_ref(),
+        env_fingerprint: &env_fingerprint,
+        traced_files_filter: traced_files_filter.as_ref(),
+    };
+
+    let built_doctests = build_doctests(&tests, instrument_dependencies, package_selection)?;
+
+    let collect_doctest = |test: &ListedTest| {
+        collect_profiling_data_for_doctest(
+            test,
+            built_doctests.get(test.get_package_name()),
+            instrument_dependencies,
+            package_selection,
+            &config,
+        )
+    };
+
+    // nextest is only given the number of test threads if it was given
+    let test_threads = jobs;
+    let jobs = jobs.unwrap_or(NonZeroUsize::MIN);
 
-        let difftest_dir = root.join(&harness_name).join(&name);
+    match test_runner {
+        TestRunner::Libtest => {
+            for_each_test(&tests, jobs, no_fail_fast, &mut pb, &mut tests_pb, |test| {
+                if test.is_doctest() {
+                    collect_doctest(test)
+                } else {
+                    collect_profiling_data_for_test(test, &config)
+                }
+            })
+        }
+        TestRunner::Nextest => {
+            // the difftest directories are prepared outside of the root (so
+            // they are not discovered as difftests), and only replace the
+            // old ones once nextest runs the tests
+            std::fs::create_dir_all(&root)?;
+            let prepared_root = tempfile::tempdir_in(root.parent().unwrap_or(&root))?;
+
+            let nextest_dirs = tests
+                .iter()
+                .filter(|test| !test.is_doctest())
+                .map(|test| {
+                    let prepared_dir = difftest_dir(test, prepared_root.path());
+                    prepare_difftest_dir(test, &prepared_dir, &config)?;
+
+                    let nextest_test = NextestTest {
+                        difftest_dir: difftest_dir(test, &root),
+                        prepared_dir,
+                    };
+
+                    Ok((test, nextest_test))
+                })
+                .collect::<CargoDifftestsResult<Vec<_>>>()?;
+
+            if !nextest_dirs.is_empty() {
+                run_nextest(
+                    &nextest_dirs,
+                    instrument_dependencies,
+                    package_selection,
+                    nextest_profile.as_deref(),
+                    test_threads,
+                    no_fail_fast,
+                )?;
+            }
 
-        let mut test_pb = tests_pb.add_child(&format!("{}::{}", harness_name, name));
-        test_pb.init(Some(1), Some(unit::label("test")));
+            for_each_test(&tests, jobs, no_fail_fast, &mut pb, &mut tests_pb, |test| {
+                if test.is_doctest() {
+                    collect_doctest(test)
+                } else {
+                    finish_nextest_test(test, prepared_root.path(), &config)
+                }
+            })
+        }
+    }
+}
 
-        if difftest_dir.exists() {
-            std::fs::remove_dir_all(&difftest_dir)?;
+/// Calls `f` for each of the tests, on `jobs` threads.
+///
+/// Without `no_fail_fast`, it stops at the first error, otherwise it
+/// goes through all the tests and reports all the errors at the end.
+///
+/// Each test runs entirely on the worker thread that picked it up, and
+/// this is required by `--trace-file-access`: the ptrace tracer (see
+/// [`output_with_file_tracing`]) waits with `waitpid(-1, __WALL | __WNOTHREAD)`,
+/// which only reports the children of the calling thread, so a test has to be
+/// spawned and traced on the same thread, while the other workers trace theirs.
+///
+/// [`output_with_file_tracing`]: cargo_difftests::file_tracing::output_with_file_tracing
+fn for_each_test(
+    tests: &[ListedTest],
+    jobs: NonZeroUsize,
+    no_fail_fast: bool,
+    pb: &mut prodash::tree::Item,
+    tests_pb: &mut prodash::tree::Item,
+    f: impl Fn(&ListedTest) -> CargoDifftestsResult + Sync,
+) -> CargoDifftestsResult {
+    let jobs = jobs.get().min(tests.len().max(1));
+
+    let next_test = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let first_error = Mutex::new(None);
+    let failures = Mutex::new(vec![]);
+
+    let mut workers_pb = (0..jobs)
+        .map(|worker| tests_pb.add_child(format!("Worker {worker}")))
+        .collect::<Vec<_>>();
+
+    std::thread::scope(|s| {
+        for worker_pb in &mut workers_pb {
+            let f = &f;
+            let tests_pb = &*tests_pb;
+            let next_test = &next_test;
+            let failed = &failed;
+            let first_error = &first_error;
+            let failures = &failures;
+
+            s.spawn(move || {
+                // stop picking up new tests as soon as one of them failed,
+                // but let the ones already running finish.
+                while !failed.load(Ordering::SeqCst) {
+                    let test_idx = next_test.fetch_add(1, Ordering::SeqCst);
+                    let Some(test) = tests.get(test_idx) else {
+                        break;
+                    };
+
+                    let mut test_pb = worker_pb.add_child(format!(
+                        "{}::{}",
+                        test.get_harness_name(),
+                        test.get_name()
+                    ));
+                    test_pb.init(Some(1), Some(unit::label("test")));
+
+                    match f(test) {
+                        Ok(()) => {
+                            test_pb.done("done");
+                        }
+                        Err(e) if no_fail_fast => {
+                            test_pb.fail(format!("Failed to run test: {}", e));
+                            failures.lock().unwrap().push(TestFailure {
+                                harness_name: test.get_harness_name().clone(),
+                                name: test.get_name().clone(),
+                                error: e,
+                            });
+                        }
+                        Err(e) => {
+                            test_pb.fail(format!("Failed to run test: {}", e));
+                            failed.store(true, Ordering::SeqCst);
+                            first_error.lock().unwrap().get_or_insert(e);
+                            break;
+                        }
+                    }
+
+                    tests_pb.inc();
+                }
+            });
+        }
+    });
+
+    drop(workers_pb);
+
+    if let Some(e) = first_error.into_inner().unwrap() {
+        tests_pb.fail("Failed to run tests");
+        pb.fail("Failed");
+        return Err(e);
+    }
+
+    let failures = failures.into_inner().unwrap();
+
+    if !failures.is_empty() {
+        tests_pb.fail("Some tests failed");
+        pb.fail("Failed");
+
+        let mut summary = format!("{} of {} tests failed:", failures.len(), tests.len());
+        for failure in &failures {
+            summary.push_str(&format!(
+                "\n    {}::{}: {}",
+                failure.harness_name, failure.name, failure.error
+            ));
         }
 
-        std::fs::create_dir_all(&difftest_dir)?;
+        bail!(summary);
+    }
+
+    Ok(())
+}
+
+struct TestFailure {
+    harness_name: String,
+    name: String,
+    error: anyhow::Error,
+}
+
+struct CollectProfilingDataConfig<'a> {
+    root: &'a Path,
+    compile_index: bool,
+    and_clean: bool,
+    index_resolver: Option<&'a DiscoverIndexPathResolver>,
+    index_compilation_args: &'a AnalysisIndex,
+    export_profdata_config: &'a ExportProfdataConfig,
+    ignore_registry_files: IgnoreRegistryFilesFlag,
+    path_filter: &'a PathFilter,
+    no_fail_fast: bool,
+    git_state: Option<&'a GitState>,
+    env_fingerprint: &'a EnvFingerprint,
+    traced_files_filter: Option<&'a TracedFilesFilter>,
+}
+
+/// Which of the files opened by a test are tracked as its inputs,
+/// with `--trace-file-access`.
+struct TracedFilesFilter {
+    workspace_root: PathBuf,
+    excluded: Vec<PathBuf>,
+}
 
+impl TracedFilesFilter {
+    fn accepts(&self, file: &Path) -> bool {
+        file.starts_with(&self.workspace_root)
+            && !self.excluded.iter().any(|it| file.starts_with(it))
+            && file.is_file()
+    }
+}
+
+/// The difftest directory of the test, `<root>/<package>/<harness>/<test>`,
+/// so that the test targets with the same name in different packages of
+/// the workspace do not collide.
+fn difftest_dir(test: &ListedTest, root: &Path) -> PathBuf {
+    root.join(test.get_package_name())
+        .join(test.get_harness_name())
+        .join(test.get_name())
+}
+
+/// Creates the difftest directory of the test at `difftest_dir`, with
+/// everything but the profiling data and the outcome of the test.
+fn prepare_difftest_dir(
+    test: &ListedTest,
+    difftest_dir: &Path,
+    config: &CollectProfilingDataConfig,
+) -> CargoDifftestsResult {
+    let name = test.get_name();
+
+    if difftest_dir.exists() {
+        std::fs::remove_dir_all(difftest_dir)?;
+    }
+
+    std::fs::create_dir_all(difftest_dir)?;
+
+    // the binary of a doctest is only known once it ran
+    if !test.is_doctest() {
         std::fs::write(
             difftest_dir.join(cargo_difftests_core::CARGO_DIFFTESTS_TEST_BINARY_FILENAME),
             test.get_harness_path().to_str().unwrap(),
         )?;
+    }
 
+    if let Some(binary_id) = test.get_nextest_binary_id() {
         std::fs::write(
-            difftest_dir.join(cargo_difftests_core::CARGO_DIFFTESTS_TEST_NAME_FILENAME),
-            &name,
+            difftest_dir.join(cargo_difftests_core::CARGO_DIFFTESTS_NEXTEST_BINARY_ID_FILENAME),
+            binary_id,
         )?;
+    }
 
-        std::fs::write(
-            difftest_dir.join(cargo_difftests_core::CARGO_DIFFTESTS_VERSION_FILENAME),
-            env!("CARGO_PKG_VERSION"),
+    std::fs::write(
+        difftest_dir.join(cargo_difftests_core::CARGO_DIFFTESTS_TEST_NAME_FILENAME),
+        name,
+    )?;
+
+    std::fs::write(
+        difftest_dir.join(cargo_difftests_core::CARGO_DIFFTESTS_VERSION_FILENAME),
+        env!("CARGO_PKG_VERSION"),
+    )?;
+
+    if let Some(git_state) = config.git_state {
+        git_state.write_to_file(
+            &difftest_dir.join(cargo_difftests_core::CARGO_DIFFTESTS_GIT_STATE_FILENAME),
         )?;
+    }
 
-        match test.run_test_and_collect_profiling_data(&difftest_dir) {
-            Ok(_) => {
-                if compile_index {
-                    if let Some(index_resolver) = index_resolver.as_ref() {
-                        let mut difftest =
-                            Difftest::discover_from(difftest_dir.clone(), Some(index_resolver))?;
-
-                        difftest.merge_profraw_files_into_profdata(false)?;
-                        let index_data_compiler_config = compile_test_index_config(
-                            index_compilation_args.compile_test_index_flags.clone(),
-                            ignore_registry_files,
-                        )?;
-                        let index_data = difftest.compile_test_index_data(
-                            export_profdata_config.clone(),
-                            index_data_compiler_config,
-                        )?;
-
-                        if let Some(path) = index_resolver.resolve(&difftest_dir) {
-                            if let Some(p) = path.parent() {
-                                if !p.exists() {
-                                    std::fs::create_dir_all(p)?;
-                                }
-                            }
-                            index_data.write_to_file(&path)?;
-                        }
+    config.env_fingerprint.write_to_file(
+        &difftest_dir.join(cargo_difftests_core::CARGO_DIFFTESTS_ENV_FINGERPRINT_FILENAME),
+    )?;
 
-                        if and_clean {
-                            difftest.clean()?;
-                        }
-                    }
-                }
+    Ok(())
+}
+
+fn collect_profiling_data_for_test(
+    test: &ListedTest,
+    config: &CollectProfilingDataConfig,
+) -> CargoDifftestsResult {
+    let difftest_dir = difftest_dir(test, config.root);
+    prepare_difftest_dir(test, &difftest_dir, config)?;
+
+    let (outcome, opened_files) = test
+        .run_test_and_collect_profiling_data(&difftest_dir, config.traced_files_filter.is_some())?;
+
+    outcome.write_to_file(
+        &difftest_dir.join(cargo_difftests_core::CARGO_DIFFTESTS_TEST_OUTCOME_FILENAME),
+    )?;
+
+    finish_difftest_dir(test, &difftest_dir, outcome, opened_files, config)
+}
+
+fn collect_profiling_data_for_doctest(
+    test: &ListedTest,
+    built_doctests: Option<&BuiltDoctests>,
+    instrument_dependencies: bool,
+    package_selection: &PackageSelectionArgs,
+    config: &CollectProfilingDataConfig,
+) -> CargoDifftestsResult {
+    let difftest_dir = difftest_dir(test, config.root);
+    prepare_difftest_dir(test, &difftest_dir, config)?;
+
+    let (outcome, binary) = test.run_doctest_and_collect_profiling_data(
+        &difftest_dir,
+        built_doctests,
+        instrument_dependencies,
+        package_selection,
+    )?;
+
+    match binary {
+        Some(binary) => {
+            std::fs::write(
+                difftest_dir.join(cargo_difftests_core::CARGO_DIFFTESTS_TEST_BINARY_FILENAME),
+                binary.to_str().unwrap(),
+            )?;
+        }
+        // it failed to compile, which is handled like any other failure,
+        // without a binary
+        None if outcome.status == TestStatus::Failed => {
+            std::fs::write(
+                difftest_dir.join(cargo_difftests_core::CARGO_DIFFTESTS_TEST_BINARY_FILENAME),
+                "",
+            )?;
+        }
+        // an ignored doctest is not compiled, so there is nothing to collect
+        None => {
+            std::fs::remove_dir_all(&difftest_dir)?;
+            return Ok(());
+        }
+    }
+
+    outcome.write_to_file(
+        &difftest_dir.join(cargo_difftests_core::CARGO_DIFFTESTS_TEST_OUTCOME_FILENAME),
+    )?;
+
+    finish_difftest_dir(test, &difftest_dir, outcome, BTreeSet::new(), config)
+}
+
+/// Collects the profiling data of a test that was run by [`run_nextest`],
+/// which recorded its outcome.
+///
+/// If nextest did not run the test (its prepared directory in
+/// `prepared_root` is still there), it keeps its old difftest directory,
+/// and is skipped.
+fn finish_nextest_test(
+    test: &ListedTest,
+    prepared_root: &Path,
+    config: &CollectProfilingDataConfig,
+) -> CargoDifftestsResult {
+    if difftest_dir(test, prepared_root).exists() {
+        warn!(
+            "{}::{} was not run by nextest, keeping its old profiling data",
+            test.get_harness_name(),
+            test.get_name()
+        );
+        return Ok(());
+    }
+
+    let difftest_dir = difftest_dir(test, config.root);
 
-                test_pb.done("done");
+    let outcome_file =
+        difftest_dir.join(cargo_difftests_core::CARGO_DIFFTESTS_TEST_OUTCOME_FILENAME);
+    if !outcome_file.exists() {
+        bail!("test was not run by nextest");
+    }
+
+    let outcome = TestOutcome::read_from_file(&outcome_file)?;
+
+    finish_difftest_dir(test, &difftest_dir, outcome, BTreeSet::new(), config)
+}
+
+/// Tracks the files the test opened, and compiles the index of the test,
+/// or fails if the test failed.
+fn finish_difftest_dir(
+    test: &ListedTest,
+    difftest_dir: &Path,
+    outcome: TestOutcome,
+    opened_files: BTreeSet<PathBuf>,
+    config: &CollectProfilingDataConfig,
+) -> CargoDifftestsResult {
+    let harness_name = test.get_harness_name();
+    let name = test.get_name();
+
+    if let Some(filter) = config.traced_files_filter {
+        let mut tracked_files = std::fs::OpenOptions::new().create(true).append(true).open(
+            difftest_dir.join(cargo_difftests_core::CARGO_DIFFTESTS_TRACKED_FILES_FILENAME),
+        )?;
+
+        for file in opened_files.iter().filter(|file| filter.accepts(file)) {
+            writeln!(tracked_files, "{}", file.display())?;
+        }
+    }
+
+    if outcome.status == TestStatus::Failed {
+        if !config.no_fail_fast {
+            bail!("test failed");
+        }
+
+        // The test may have failed before writing any profiling data, in which
+        // case there is nothing to index, but the old index (if any) must not
+        // be kept around as-is, as it would make the test look clean.
+        if let Err(index_err) = compile_index_for_test(difftest_dir, config) {
+            warn!(
+                "could not compile index for failed test {}::{}: {}",
+                harness_name, name, index_err
+            );
+
+            write_index_without_coverage(difftest_dir, config)?;
+        }
+
+        bail!("test failed");
+    }
+
+    compile_index_for_test(difftest_dir, config)?;
+
+    Ok(())
+}
+
+/// Replaces the index of a failed test that could not be compiled with one
+/// without any coverage data (see [`TestIndex::without_coverage`]), which
+/// still makes the analysis consider the test dirty.
+///
+/// If even that fails, the old index is removed.
+fn write_index_without_coverage(
+    difftest_dir: &Path,
+    config: &CollectProfilingDataConfig,
+) -> CargoDifftestsResult {
+    if !config.compile_index {
+        return Ok(());
+    }
+
+    let Some(path) = config
+        .index_resolver
+        .and_then(|resolver| resolver.resolve(difftest_dir))
+    else {
+        return Ok(());
+    };
+
+    let remove_bin_path = config
+        .index_compilation_args
+        .compile_test_index_flags
+        .remove_bin_path;
+    let index_data = Difftest::discover_from(difftest_dir.to_path_buf(), config.index_resolver)
+        .and_then(|difftest| TestIndex::without_coverage(&difftest, remove_bin_path));
+
+    match index_data {
+        Ok(index_data) => {
+            if let Some(p) = path.parent() {
+                std::fs::create_dir_all(p)?;
             }
-            Err(e) => {
-                test_pb.fail(&format!("Failed to run test: {}", e));
-                tests_pb.fail("Failed to run tests");
-                pb.fail("Failed");
-                return Err(e);
+            index_data.write_to_file(&path)?;
+        }
+        Err(e) => {
+            warn!("could not write index for failed test: {}", e);
+
+            if path.exists() {
+                std::fs::remove_file(&path)?;
             }
         }
+    }
+
+    Ok(())
+}
+
+fn compile_index_for_test(
+    difftest_dir: &Path,
+    config: &CollectProfilingDataConfig,
+) -> CargoDifftestsResult {
+    if !config.compile_index {
+        return Ok(());
+    }
+
+    let Some(index_resolver) = config.index_resolver else {
+        return Ok(());
+    };
+
+    let mut difftest = Difftest::discover_from(difftest_dir.to_path_buf(), Some(index_resolver))?;
+
+    difftest.merge_profraw_files_into_profdata(false)?;
+    let index_data_compiler_config = compile_test_index_config(
+        config.index_compilation_args.compile_test_index_flags,
+        config.ignore_registry_files,
+        config.path_filter.clone(),
+    )?;
+    let index_data = difftest.compile_test_index_data(
+        config.export_profdata_config.clone(),
+        index_data_compiler_config,
+    )?;
+
+    if let Some(path) = index_resolver.resolve(difftest_dir) {
+        if let Some(p) = path.parent() {
+            if !p.exists() {
+                std::fs::create_dir_all(p)?;
+            }
+        }
+        index_data.write_to_file(&path)?;
+    }
 
-        tests_pb.inc();
+    if config.and_clean {
+        difftest.clean()?;
     }
 
     Ok(())
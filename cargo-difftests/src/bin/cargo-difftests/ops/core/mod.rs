@@ -1,16 +1,28 @@
 use std::{
+    collections::{BTreeMap, BTreeSet},
     ffi::{OsStr, OsString},
     fs,
     io::{BufRead, Write},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
+    time::Instant,
 };
 
 use anyhow::{bail, Context};
 use cargo_difftests::{
-    analysis::{file_is_from_cargo_registry, AnalysisConfig, AnalysisContext, AnalysisResult},
+    analysis::{
+        file_is_from_cargo_registry, AlwaysRun, AnalysisConfig, AnalysisContext, AnalysisReport,
+        AnalysisResult, DirtyAlgorithm, GitDiffChangesCache,
+    },
     bin_context::CargoDifftestsContext,
-    difftest::{Difftest, DiscoverIndexPathResolver},
+    difftest::{
+        CurrentEnvFingerprints, Difftest, DiscoverIndexPathResolver, TestOutcome, TestStatus,
+    },
+    file_tracing,
     index_data::{IndexDataCompilerConfig, IndexSize, TestIndex},
+    index_db::INDEX_DB_FILE_NAME,
+    nextest::{self, NextestTest, NextestTests},
+    path_filter::PathFilter,
     AnalysisVerdict,
 };
 use log::{error, info, warn};
@@ -18,8 +30,9 @@ use prodash::unit;
 
 use crate::{
     cli_core::{
-        AnalysisIndex, AnalysisIndexStrategy, CompileTestIndexFlags, DirtyAlgorithm,
-        ExportProfdataConfigFlags, FlattenFilesTarget, IgnoreRegistryFilesFlag, RerunRunner,
+        cargo_metadata, AnalysisIndex, AnalysisIndexStrategy, CompileTestIndexFlags,
+        ExportProfdataConfigFlags, FlattenFilesTarget, IgnoreRegistryFilesFlag,
+        PackageSelectionArgs, RerunRunner,
     },
     CargoDifftestsResult,
 };
@@ -29,13 +42,16 @@ use cargo_difftests::test_rerunner_core::State as TestRunnerState;
 pub fn analyze_single_test(
     difftest: &mut Difftest,
     force: bool,
-    algo: DirtyAlgorithm,
-    commit: Option<git2::Oid>,
+    dirty_algorithm: &DirtyAlgorithm,
     export_profdata_config_flags: ExportProfdataConfigFlags,
     analysis_index: &AnalysisIndex,
     resolver: Option<&DiscoverIndexPathResolver>,
     ignore_registry_files: IgnoreRegistryFilesFlag,
-) -> CargoDifftestsResult<AnalysisResult> {
+    path_filter: &PathFilter,
+    always_run: &AlwaysRun,
+    environment: &mut CurrentEnvFingerprints,
+    git_diff_cache: &mut GitDiffChangesCache,
+) -> CargoDifftestsResult<AnalysisReport> {
     let mut analysis_cx = match analysis_index.index_strategy {
         AnalysisIndexStrategy::Never => {
             difftest.merge_profraw_files_into_profdata(force)?;
@@ -54,6 +70,7 @@ pub fn analyze_single_test(
                 let config = compile_test_index_config(
                     analysis_index.compile_test_index_flags.clone(),
                     ignore_registry_files,
+                    path_filter.clone(),
                 )?;
 
                 let test_index_data = difftest.compile_test_index_data(
@@ -84,6 +101,7 @@ pub fn analyze_single_test(
                 let config = compile_test_index_config(
                     analysis_index.compile_test_index_flags.clone(),
                     ignore_registry_files,
+                    path_filter.clone(),
                 )?;
 
                 let test_index_data = difftest.compile_test_index_data(
@@ -119,12 +137,20 @@ pub fn analyze_single_test(
         }
     };
 
-    analysis_cx.run(&AnalysisConfig {
-        dirty_algorithm: algo.convert(commit),
+    let config = AnalysisConfig {
+        dirty_algorithm: dirty_algorithm.clone(),
         error_on_invalid_config: true,
-    })?;
+        environment: analysis_cx
+            .env_fingerprint()
+            .and_then(|recorded| environment.matching(recorded))
+            .cloned(),
+        path_filter: path_filter.clone(),
+        always_run: always_run.clone(),
+    };
+
+    analysis_cx.run_with_git_diff_cache(&config, git_diff_cache)?;
 
-    let r = analysis_cx.finish_analysis();
+    let r = analysis_cx.finish_analysis_with_report();
 
     Ok(r)
 }
@@ -139,7 +165,7 @@ pub fn discover_indexes_to_vec(
 
         if path.is_dir() {
             discover_indexes_to_vec(&path, indexes)?;
-        } else {
+        } else if path.file_name() != Some(INDEX_DB_FILE_NAME.as_ref()) {
             let index = TestIndex::read_from_file(&path)?;
             indexes.push(index);
         }
@@ -151,6 +177,7 @@ pub fn discover_indexes_to_vec(
 pub fn compile_test_index_config(
     compile_test_index_flags: CompileTestIndexFlags,
     ignore_registry_files: IgnoreRegistryFilesFlag,
+    path_filter: PathFilter,
 ) -> CargoDifftestsResult<IndexDataCompilerConfig> {
     let flatten_root = match compile_test_index_flags.flatten_files_to {
         Some(FlattenFilesTarget::RepoRoot) => {
@@ -189,10 +216,12 @@ pub fn compile_test_index_config(
                 return false;
             }
 
-            true
+            path_filter.accepts(path)
         }),
         index_size: if compile_test_index_flags.full_index {
             IndexSize::Full
+        } else if compile_test_index_flags.functions_index {
+            IndexSize::Functions
         } else {
             IndexSize::Tiny
         },
@@ -241,6 +270,14 @@ pub fn display_analysis_result(r: AnalysisResult) {
     println!("{res}");
 }
 
+pub fn display_analysis_report(report: &AnalysisReport) {
+    display_analysis_result(report.result());
+
+    for reason in &report.reasons {
+        println!("    {reason}");
+    }
+}
+
 pub fn cargo_bin_path() -> PathBuf {
     let cargo = std::env::var_os("CARGO").unwrap_or_else(|| OsString::from("cargo"));
     let cargo = PathBuf::from(cargo);
@@ -248,13 +285,24 @@ pub fn cargo_bin_path() -> PathBuf {
 }
 
 #[derive(Clone, Debug)]
-pub struct TestHarness(PathBuf, String);
+pub struct TestHarness {
+    /// The test binary (empty for the doctests, which are only compiled
+    /// when they are run).
+    path: PathBuf,
+    name: String,
+    /// The name of the package the test target is in.
+    package: String,
+    /// The id of the test binary, if the tests were listed by nextest.
+    nextest_binary_id: Option<String>,
+    /// Whether these are the doctests of the library of the package.
+    doctests: bool,
+}
 
 impl TestHarness {
     pub fn list_tests(&self) -> CargoDifftestsResult<Vec<ListedTest>> {
         let mut tests = vec![];
 
-        let output = std::process::Command::new(&self.0)
+        let output = std::process::Command::new(&self.path)
             .args(&["--list", "--format=terse"])
             .stdout(std::process::Stdio::piped())
             .env("LLVM_PROFILE_FILE", temp_dir_profile_file())
@@ -286,54 +334,387 @@ pub fn temp_dir_profile_file() -> PathBuf {
 
 impl ListedTest {
     pub fn get_harness_name(&self) -> &String {
-        &self.0 .1
+        &self.0.name
+    }
+
+    pub fn get_package_name(&self) -> &String {
+        &self.0.package
     }
 
     pub fn get_harness_path(&self) -> &PathBuf {
-        &self.0 .0
+        &self.0.path
     }
 
     pub fn get_name(&self) -> &String {
         &self.1
     }
 
+    pub fn is_doctest(&self) -> bool {
+        self.0.doctests
+    }
+
+    pub fn get_nextest_binary_id(&self) -> Option<&str> {
+        self.0.nextest_binary_id.as_deref()
+    }
+
+    /// Runs the test, and returns its [`TestOutcome`], along with the files
+    /// it opened for reading, if `trace_files` is set (see
+    /// [`cargo_difftests::file_tracing`]).
+    ///
+    /// A test that fails is not an error here; the caller decides
+    /// what to do with it based on the [`TestStatus`] of the outcome.
     pub fn run_test(
         &self,
         extra: impl FnOnce(&mut std::process::Command) -> &mut std::process::Command,
-    ) -> CargoDifftestsResult {
-        let output = extra(
-            std::process::Command::new(&self.0 .0)
-                .args(&["--exact", &self.1, "--nocapture"])
+        trace_files: bool,
+    ) -> CargoDifftestsResult<(TestOutcome, BTreeSet<PathBuf>)> {
+        let start = Instant::now();
+
+        let mut cmd = std::process::Command::new(&self.0.path);
+        extra(
+            cmd.args(&["--exact", &self.1, "--nocapture"])
                 .stdout(std::process::Stdio::piped())
                 .stderr(std::process::Stdio::piped()),
+        );
+
+        let (output, opened_files) = if trace_files {
+            file_tracing::output_with_file_tracing(&mut cmd)?
+        } else {
+            (cmd.output()?, BTreeSet::new())
+        };
+
+        let outcome = TestOutcome {
+            status: test_status(&output, &self.1)?,
+            duration: start.elapsed(),
+            exit_code: output.status.code(),
+        };
+
+        Ok((outcome, opened_files))
+    }
+
+    pub fn run_test_and_collect_profiling_data(
+        &self,
+        difftest_dir: &Path,
+        trace_files: bool,
+    ) -> CargoDifftestsResult<(TestOutcome, BTreeSet<PathBuf>)> {
+        self.run_test(
+            |cmd| {
+                cmd.env("CARGO_DIFFTEST_DIR", &difftest_dir)
+                    .env("LLVM_PROFILE_FILE", difftest_dir.join("%p_%m.profraw"))
+                    .env("RUSTC_WORKSPACE_WRAPPER", "rustc-wrapper-difftests")
+            },
+            trace_files,
         )
-        .output()?;
+    }
+
+    /// Runs the doctest, compiled with `-C instrument-coverage`, and returns
+    /// its [`TestOutcome`], along with the doctest binary, which is copied
+    /// to the difftest directory.
+    ///
+    /// If the doctest was already compiled by [`build_doctests`], its binary
+    /// is run directly. Otherwise (or if it fails, as it may be a
+    /// `should_panic` doctest), it is run with `cargo test --doc`.
+    ///
+    /// The binary is [`None`] if the doctest did not run (like when it
+    /// is ignored).
+    pub fn run_doctest_and_collect_profiling_data(
+        &self,
+        difftest_dir: &Path,
+        built_doctests: Option<&BuiltDoctests>,
+        instrument_dependencies: bool,
+        package_selection: &PackageSelectionArgs,
+    ) -> CargoDifftestsResult<(TestOutcome, Option<PathBuf>)> {
+        let persist_dir = difftest_dir.join(DOCTESTS_PERSIST_DIR);
+
+        if let Some(built_doctests) = built_doctests
+            && let Some(built_binary) =
+                persisted_doctest_binary(built_doctests.persist_dir.path(), &self.1)?
+        {
+            fs::create_dir_all(&persist_dir)?;
+            let binary = persist_dir.join(built_binary.file_name().unwrap());
+            fs::copy(&built_binary, &binary)?;
+
+            let start = Instant::now();
+
+            // like `cargo test` does, in the root of the package
+            let output = std::process::Command::new(&binary)
+                .current_dir(&built_doctests.package_root)
+                .env("CARGO_DIFFTEST_DIR", difftest_dir)
+                .env("LLVM_PROFILE_FILE", difftest_dir.join("%p_%m.profraw"))
+                .stdout(std::process::Stdio::piped())
+                .stderr(std::process::Stdio::piped())
+                .output()?;
+
+            if output.status.success() {
+                let outcome = TestOutcome {
+                    status: TestStatus::Passed,
+                    duration: start.elapsed(),
+                    exit_code: output.status.code(),
+                };
+
+                return Ok((outcome, Some(binary)));
+            }
+
+            // only rustdoc knows whether it should have panicked
+            fs::remove_dir_all(&persist_dir)?;
+            for e in fs::read_dir(difftest_dir)? {
+                let p = e?.path();
+                if p.extension() == Some(OsStr::new("profraw")) {
+                    fs::remove_file(p)?;
+                }
+            }
+        }
+
+        let start = Instant::now();
+
+        let output = std::process::Command::new(cargo_bin_path())
+            .args(["test", "--doc", "--package", self.get_package_name()])
+            .args(package_selection.feature_args())
+            .args(["--", "--exact", &self.1, "--nocapture"])
+            .env(
+                rustc_wrapper_var(instrument_dependencies),
+                "rustc-wrapper-difftests",
+            )
+            .env_remove("RUSTDOCFLAGS")
+            .env(
+                "CARGO_ENCODED_RUSTDOCFLAGS",
+                encoded_rustdocflags(&[
+                    "-C",
+                    "instrument-coverage",
+                    "-Z",
+                    "unstable-options",
+                    "--persist-doctests",
+                    persist_dir.to_str().unwrap(),
+                ]),
+            )
+            .env("CARGO_DIFFTEST_DIR", difftest_dir)
+            .env("LLVM_PROFILE_FILE", difftest_dir.join("%p_%m.profraw"))
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .output()?;
+
+        let outcome = TestOutcome {
+            status: test_status(&output, &self.1)?,
+            duration: start.elapsed(),
+            exit_code: output.status.code(),
+        };
+
+        let binary = if persist_dir.exists() {
+            persisted_doctest_binary(&persist_dir, &self.1)?
+        } else {
+            None
+        };
+
+        Ok((outcome, binary))
+    }
+}
+
+/// The [`TestStatus`] of the test with the given name, from the output of
+/// the test harness that ran it (on its own).
+fn test_status(output: &std::process::Output, name: &str) -> CargoDifftestsResult<TestStatus> {
+    let stdout = std::str::from_utf8(&output.stdout)?;
+
+    let status = if !output.status.success() {
+        println!("stdout:\n");
+        println!("{}", stdout);
+        let stderr = std::str::from_utf8(&output.stderr)?;
+        error!("stderr:\n");
+        error!("{}", stderr);
+        TestStatus::Failed
+    } else if stdout.contains(&format!("test {} ... ignored", name)) {
+        TestStatus::Ignored
+    } else {
+        TestStatus::Passed
+    };
+
+    Ok(status)
+}
+
+/// The directory in the difftest directory of a doctest that its binary is
+/// persisted to (with `--persist-doctests`).
+const DOCTESTS_PERSIST_DIR: &str = "doctest_bin";
+
+/// The binary of the doctest with the given name (like
+/// `src/lib.rs - add (line 5)`) in the directory the doctests were
+/// persisted to, if it is there.
+///
+/// rustdoc persists every doctest to `<file>_<line>_<n>/rust_out`, where
+/// `<file>` has all the characters but the alphanumeric ones replaced by
+/// `_`, and `<n>` tells apart the doctests on the same line (which are
+/// therefore not found here).
+fn persisted_doctest_binary(
+    persist_dir: &Path,
+    name: &str,
+) -> CargoDifftestsResult<Option<PathBuf>> {
+    fn sanitize(s: &str) -> String {
+        s.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect()
+    }
+
+    let Some((file, rest)) = name.split_once(" - ") else {
+        return Ok(None);
+    };
+    let Some((_, line)) = rest
+        .strip_suffix(')')
+        .and_then(|rest| rest.rsplit_once("(line "))
+    else {
+        return Ok(None);
+    };
+
+    let prefix = format!("{}_{line}_", sanitize(file));
+
+    let mut dirs = vec![];
+    for e in fs::read_dir(persist_dir)? {
+        let dir = e?.path();
+        if dir
+            .file_name()
+            .and_then(OsStr::to_str)
+            .is_some_and(|dir_name| sanitize(dir_name).starts_with(&prefix))
+        {
+            dirs.push(dir);
+        }
+    }
+
+    let [dir] = &dirs[..] else {
+        return Ok(None);
+    };
+
+    let binary = dir.join(format!("rust_out{}", std::env::consts::EXE_SUFFIX));
+
+    Ok(Some(binary).filter(|binary| binary.is_file()))
+}
+
+/// The doctests of a package, compiled (but not run) by [`build_doctests`].
+pub struct BuiltDoctests {
+    /// The directory the binaries of the doctests were persisted to.
+    persist_dir: tempfile::TempDir,
+    /// The root of the package, which the doctests are run in.
+    package_root: PathBuf,
+}
+
+/// Compiles all the doctests of the packages of the given doctests at once,
+/// with `-C instrument-coverage`, but without running them, so they can
+/// then be run one by one without a `cargo test --doc` for each of them.
+///
+/// This needs the (unstable) `--no-run` and `--persist-doctests` flags of
+/// rustdoc. The doctests that it does not compile are run with
+/// `cargo test --doc` instead.
+pub fn build_doctests(
+    tests: &[ListedTest],
+    instrument_dependencies: bool,
+    package_selection: &PackageSelectionArgs,
+) -> CargoDifftestsResult<BTreeMap<String, BuiltDoctests>> {
+    let packages = tests
+        .iter()
+        .filter(|test| test.is_doctest())
+        .map(|test| test.get_package_name().as_str())
+        .collect::<BTreeSet<_>>();
+
+    if packages.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+
+    let metadata = cargo_metadata()?;
+
+    let mut built = BTreeMap::new();
+
+    for package in packages {
+        let package_root = metadata
+            .packages
+            .iter()
+            .find(|it| it.name == package)
+            .and_then(|it| it.manifest_path.parent())
+            .with_context(|| format!("unknown package {package}"))?
+            .to_path_buf();
+
+        let persist_dir = tempfile::tempdir()?;
+
+        let output = std::process::Command::new(cargo_bin_path())
+            .args(["test", "--doc", "--package", package])
+            .args(package_selection.feature_args())
+            .env(
+                rustc_wrapper_var(instrument_dependencies),
+                "rustc-wrapper-difftests",
+            )
+            .env_remove("RUSTDOCFLAGS")
+            .env(
+                "CARGO_ENCODED_RUSTDOCFLAGS",
+                encoded_rustdocflags(&[
+                    "-C",
+                    "instrument-coverage",
+                    "-Z",
+                    "unstable-options",
+                    "--persist-doctests",
+                    persist_dir.path().to_str().unwrap(),
+                    "--no-run",
+                ]),
+            )
+            .env("LLVM_PROFILE_FILE", temp_dir_profile_file())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .output()?;
 
         if !output.status.success() {
-            let stdout = String::from_utf8(output.stdout)?;
-            println!("stdout:\n");
-            println!("{}", stdout);
-            let stderr = String::from_utf8(output.stderr)?;
-            error!("stderr:\n");
-            error!("{}", stderr);
-            bail!("test failed");
+            // the ones that do not compile fail again when they are run
+            warn!("could not compile all the doctests of {package}");
         }
 
-        Ok(())
+        built.insert(
+            package.to_owned(),
+            BuiltDoctests {
+                persist_dir,
+                package_root,
+            },
+        );
     }
 
-    pub fn run_test_and_collect_profiling_data(&self, difftest_dir: &Path) -> CargoDifftestsResult {
-        self.run_test(|cmd| {
-            cmd.env("CARGO_DIFFTEST_DIR", &difftest_dir)
-                .env("LLVM_PROFILE_FILE", difftest_dir.join("%p_%m.profraw"))
-                .env("RUSTC_WORKSPACE_WRAPPER", "rustc-wrapper-difftests")
-        })
+    Ok(built)
+}
+
+/// The `CARGO_ENCODED_RUSTDOCFLAGS` to run the doctests with: the ones
+/// from the environment, followed by `extra`.
+///
+/// The encoded form is used because the paths in `extra` may contain
+/// spaces, like the names of the doctests.
+fn encoded_rustdocflags(extra: &[&str]) -> String {
+    let mut flags: Vec<String> = match std::env::var("CARGO_ENCODED_RUSTDOCFLAGS") {
+        Ok(flags) if !flags.is_empty() => flags.split('\x1f').map(str::to_owned).collect(),
+        _ => std::env::var("RUSTDOCFLAGS")
+            .map(|flags| flags.split_whitespace().map(str::to_owned).collect())
+            .unwrap_or_else(|_| vec![]),
+    };
+
+    flags.extend(extra.iter().map(|it| (*it).to_owned()));
+
+    flags.join("\x1f")
+}
+
+/// The environment variable to set `rustc-wrapper-difftests` in, when
+/// building the tests.
+fn rustc_wrapper_var(instrument_dependencies: bool) -> &'static str {
+    // `RUSTC_WRAPPER` applies to the dependencies too
+    if instrument_dependencies {
+        "RUSTC_WRAPPER"
+    } else {
+        "RUSTC_WORKSPACE_WRAPPER"
     }
 }
 
-pub fn collect_test_harnesses() -> CargoDifftestsResult<Vec<TestHarness>> {
+pub fn collect_test_harnesses(
+    instrument_dependencies: bool,
+    package_selection: &PackageSelectionArgs,
+) -> CargoDifftestsResult<Vec<TestHarness>> {
     let mut harnesses = vec![];
 
+    let wrapper = rustc_wrapper_var(instrument_dependencies);
+
+    let package_names = cargo_metadata()?
+        .packages
+        .iter()
+        .map(|package| (package.id.clone(), package.name.clone()))
+        .collect::<BTreeMap<_, _>>();
+
     let mut proc = std::process::Command::new(cargo_bin_path())
         .args(&[
             "test",
@@ -341,7 +722,8 @@ pub fn collect_test_harnesses() -> CargoDifftestsResult<Vec<TestHarness>> {
             "--message-format",
             "json-render-diagnostics",
         ])
-        .env("RUSTC_WORKSPACE_WRAPPER", "rustc-wrapper-difftests")
+        .args(package_selection.cargo_args())
+        .env(wrapper, "rustc-wrapper-difftests")
         .env("LLVM_PROFILE_FILE", temp_dir_profile_file())
         .stdout(std::process::Stdio::piped())
         .spawn()?;
@@ -353,6 +735,7 @@ pub fn collect_test_harnesses() -> CargoDifftestsResult<Vec<TestHarness>> {
     enum Message {
         #[serde(rename = "compiler-artifact")]
         CompilerArtifact {
+            package_id: String,
             target: TargetSpec,
             executable: Option<PathBuf>,
         },
@@ -381,9 +764,33 @@ pub fn collect_test_harnesses() -> CargoDifftestsResult<Vec<TestHarness>> {
                     bail!("cargo test failed");
                 }
             }
-            Message::CompilerArtifact { target, executable } => {
-                if target.kind.contains(&"test".to_string()) {
-                    harnesses.push(TestHarness(executable.unwrap(), target.name));
+            Message::CompilerArtifact {
+                package_id,
+                target,
+                executable,
+            } => {
+                // the library itself is built too, but only its test
+                // binary has an executable
+                let Some(executable) = executable else {
+                    continue;
+                };
+
+                if target
+                    .kind
+                    .iter()
+                    .any(|kind| package_selection.collects_kind(kind))
+                {
+                    let package = package_names
+                        .get(&package_id)
+                        .with_context(|| format!("unknown package {package_id}"))?;
+
+                    harnesses.push(TestHarness {
+                        path: executable,
+                        name: target.name,
+                        package: package.clone(),
+                        nextest_binary_id: None,
+                        doctests: false,
+                    });
                 }
             }
             Message::BuildScriptExecuted {} => {}
@@ -393,6 +800,361 @@ pub fn collect_test_harnesses() -> CargoDifftestsResult<Vec<TestHarness>> {
     Ok(harnesses)
 }
 
+/// Builds the tests and lists them with `cargo nextest list`, like
+/// [`collect_test_harnesses`] and [`TestHarness::list_tests`] do with
+/// `cargo test`.
+///
+/// Ignored tests are left out, as nextest does not run them.
+pub fn collect_nextest_tests(
+    instrument_dependencies: bool,
+    package_selection: &PackageSelectionArgs,
+) -> CargoDifftestsResult<Vec<ListedTest>> {
+    let output = std::process::Command::new(cargo_bin_path())
+        .args(&["nextest", "list", "--message-format", "json"])
+        .args(package_selection.cargo_args())
+        .env(
+            rustc_wrapper_var(instrument_dependencies),
+            "rustc-wrapper-difftests",
+        )
+        .env("LLVM_PROFILE_FILE", temp_dir_profile_file())
+        .stdout(std::process::Stdio::piped())
+        .output()?;
+
+    if !output.status.success() {
+        bail!("cargo nextest list failed");
+    }
+
+    #[derive(serde::Deserialize, Debug)]
+    #[serde(rename_all = "kebab-case")]
+    struct TestList {
+        rust_suites: BTreeMap<String, RustSuite>,
+    }
+
+    #[derive(serde::Deserialize, Debug)]
+    #[serde(rename_all = "kebab-case")]
+    struct RustSuite {
+        binary_id: String,
+        binary_name: String,
+        binary_path: PathBuf,
+        package_name: String,
+        kind: String,
+        testcases: BTreeMap<String, TestCase>,
+    }
+
+    #[derive(serde::Deserialize, Debug)]
+    struct TestCase {
+        ignored: bool,
+    }
+
+    let list = serde_json::from_slice::<TestList>(&output.stdout)?;
+
+    let mut tests = vec![];
+
+    for suite in list.rust_suites.into_values() {
+        if !package_selection.collects_kind(&suite.kind) {
+            continue;
+        }
+
+        let harness = TestHarness {
+            path: suite.binary_path,
+            name: suite.binary_name,
+            package: suite.package_name,
+            nextest_binary_id: Some(suite.binary_id),
+            doctests: false,
+        };
+
+        for (name, test_case) in suite.testcases {
+            if test_case.ignored {
+                continue;
+            }
+
+            tests.push(ListedTest(harness.clone(), name));
+        }
+    }
+
+    Ok(tests)
+}
+
+/// The maximum length of the filterset passed to a single
+/// `cargo nextest run`, to stay well within the limits on the length of
+/// the command line (of 32 KiB on Windows).
+const MAX_NEXTEST_FILTERSET_LEN: usize = 16 * 1024;
+
+/// Runs the given tests, with their (prepared) difftest directories, with
+/// `cargo nextest run` (see [`cargo_difftests::nextest`]).
+///
+/// The tests are selected with a filterset, and if there are too many of
+/// them to fit in one, they are run in several batches, with one
+/// `cargo nextest run` each.
+///
+/// Each of the tests that nextest runs replaces its difftest directory with
+/// the prepared one, and records its [`TestOutcome`] there; the prepared
+/// directories of the others are left as they are (as nextest did not run
+/// them, because of an earlier failure, without `no_fail_fast`).
+pub fn run_nextest(
+    tests: &[(&ListedTest, NextestTest)],
+    instrument_dependencies: bool,
+    package_selection: &PackageSelectionArgs,
+    profile: Option<&str>,
+    jobs: Option<NonZeroUsize>,
+    no_fail_fast: bool,
+) -> CargoDifftestsResult {
+    // the exit code of nextest when some of the tests failed
+    const TEST_RUN_FAILED: i32 = 100;
+
+    let mut nextest_tests = NextestTests::default();
+
+    for (test, nextest_test) in tests {
+        nextest_tests
+            .dirs
+            .entry(test.get_harness_path().clone())
+            .or_default()
+            .insert(test.get_name().clone(), nextest_test.clone());
+    }
+
+    let nextest_tests_file = tempfile::NamedTempFile::new()?;
+    nextest_tests.write_to_file(nextest_tests_file.path())?;
+
+    let target = target_triple(package_selection.target.as_deref())?;
+    let runner_var = nextest::target_runner_var(&target);
+    let runner = configured_target_runner(&runner_var, &target)?;
+
+    for filterset in nextest_filtersets(tests) {
+        let mut cmd = std::process::Command::new(cargo_bin_path());
+        cmd.args(["nextest", "run", "-E", &filterset])
+            .args(package_selection.cargo_args());
+
+        if let Some(jobs) = jobs {
+            cmd.args(["--test-threads", &jobs.to_string()]);
+        }
+
+        if let Some(profile) = profile {
+            cmd.args(["--profile", profile]);
+        }
+
+        if no_fail_fast {
+            cmd.arg("--no-fail-fast");
+        }
+
+        if let Some(runner) = &runner {
+            cmd.env(nextest::NEXTEST_RUNNER_ENV, serde_json::to_string(runner)?);
+        }
+
+        let status = cmd
+            .env(
+                rustc_wrapper_var(instrument_dependencies),
+                "rustc-wrapper-difftests",
+            )
+            .env("LLVM_PROFILE_FILE", temp_dir_profile_file())
+            .env(&runner_var, nextest::NEXTEST_WRAPPER_BIN)
+            .env(nextest::NEXTEST_TESTS_ENV, nextest_tests_file.path())
+            .status()?;
+
+        if status.code() == Some(TEST_RUN_FAILED) {
+            if !no_fail_fast {
+                break;
+            }
+        } else if !status.success() {
+            bail!("cargo nextest run failed: {status}");
+        }
+    }
+
+    Ok(())
+}
+
+/// The nextest filtersets that select the given tests, each of them at
+/// most [`MAX_NEXTEST_FILTERSET_LEN`] long (unless a single test does not
+/// fit), like
+/// `(binary_id(=pkg::tests) & (test(=test_a) | test(=test_b))) | ...`.
+fn nextest_filtersets(tests: &[(&ListedTest, NextestTest)]) -> Vec<String> {
+    let mut batches = vec![BTreeMap::<&str, Vec<&str>>::new()];
+    let mut batch_len = 0;
+
+    for (test, _) in tests {
+        let binary_id = test.get_nextest_binary_id().unwrap();
+        let name = test.get_name().as_str();
+
+        // an upper bound of what the test adds to the filterset
+        let len = binary_id.len() + name.len() + 40;
+
+        if batch_len + len > MAX_NEXTEST_FILTERSET_LEN && batch_len != 0 {
+            batches.push(BTreeMap::new());
+            batch_len = 0;
+        }
+
+        batches
+            .last_mut()
+            .unwrap()
+            .entry(binary_id)
+            .or_default()
+            .push(name);
+        batch_len += len;
+    }
+
+    batches
+        .into_iter()
+        .filter(|batch| !batch.is_empty())
+        .map(|batch| {
+            batch
+                .into_iter()
+                .map(|(binary_id, names)| {
+                    let tests = names
+                        .iter()
+                        .map(|name| format!("test(={name})"))
+                        .collect::<Vec<_>>();
+
+                    format!("(binary_id(={binary_id}) & ({}))", tests.join(" | "))
+                })
+                .collect::<Vec<_>>()
+                .join(" | ")
+        })
+        .collect()
+}
+
+/// The target triple the tests are built for: the given one, or the host.
+fn target_triple(target: Option<&str>) -> CargoDifftestsResult<String> {
+    if let Some(target) = target {
+        return Ok(target.to_owned());
+    }
+
+    let rustc = std::env::var_os("RUSTC").unwrap_or_else(|| OsString::from("rustc"));
+    let output = std::process::Command::new(rustc).arg("-vV").output()?;
+
+    if !output.status.success() {
+        bail!("rustc -vV failed");
+    }
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let host = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("host: "))
+        .context("no host in the output of rustc -vV")?;
+
+    Ok(host.to_owned())
+}
+
+/// The target runner the project already has for the target, which the
+/// nextest wrapper has to run the test binaries through: from the
+/// `CARGO_TARGET_<triple>_RUNNER` environment variable, or else from
+/// `target.<triple>.runner` in the cargo configuration.
+///
+/// Reading the configuration needs the (unstable) `cargo config` command
+/// of a nightly toolchain, and the runners of `target.'cfg(..)'` tables
+/// are not found.
+fn configured_target_runner(
+    runner_var: &str,
+    target: &str,
+) -> CargoDifftestsResult<Option<Vec<String>>> {
+    if let Ok(runner) = std::env::var(runner_var) {
+        let runner = runner
+            .split_whitespace()
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+
+        return Ok(Some(runner).filter(|runner| !runner.is_empty()));
+    }
+
+    let output = std::process::Command::new(cargo_bin_path())
+        .args([
+            "-Zunstable-options",
+            "config",
+            "get",
+            "--format",
+            "json-value",
+        ])
+        .arg(format!("target.{target}.runner"))
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .output()?;
+
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Runner {
+        String(String),
+        Args(Vec<String>),
+    }
+
+    let runner = match serde_json::from_slice::<Runner>(&output.stdout)? {
+        Runner::String(runner) => runner
+            .split_whitespace()
+            .map(str::to_owned)
+            .collect::<Vec<_>>(),
+        Runner::Args(runner) => runner,
+    };
+
+    Ok(Some(runner).filter(|runner| !runner.is_empty()))
+}
+
+/// Lists the doctests of the libraries of the selected packages, with
+/// `cargo test --doc -- --list`.
+///
+/// The doctests that are only compiled (`no_run` and `compile_fail`) are
+/// left out, as they have no coverage.
+pub fn collect_doctests(
+    instrument_dependencies: bool,
+    package_selection: &PackageSelectionArgs,
+) -> CargoDifftestsResult<Vec<ListedTest>> {
+    let metadata = cargo_metadata()?;
+
+    let mut tests = vec![];
+
+    for package in &metadata.packages {
+        let is_default_member = metadata.workspace_default_members.is_empty()
+            || metadata.workspace_default_members.contains(&package.id);
+
+        if !package.targets.iter().any(|target| target.doctest)
+            || !package_selection.selects_package(&package.name, is_default_member)
+        {
+            continue;
+        }
+
+        let output = std::process::Command::new(cargo_bin_path())
+            .args(["test", "--doc", "--package", &package.name])
+            .args(package_selection.feature_args())
+            .args(["--", "--list", "--format=terse"])
+            .env(
+                rustc_wrapper_var(instrument_dependencies),
+                "rustc-wrapper-difftests",
+            )
+            .env("LLVM_PROFILE_FILE", temp_dir_profile_file())
+            .stdout(std::process::Stdio::piped())
+            .output()?;
+
+        if !output.status.success() {
+            bail!("failed to list the doctests of {}", package.name);
+        }
+
+        let harness = TestHarness {
+            path: PathBuf::new(),
+            name: "doctests".to_owned(),
+            package: package.name.clone(),
+            nextest_binary_id: None,
+            doctests: true,
+        };
+
+        let stdout = String::from_utf8(output.stdout)?;
+
+        for line in stdout.lines() {
+            // like `src/lib.rs - add (line 5): test`
+            let Some(name) = line.strip_suffix(": test") else {
+                continue;
+            };
+
+            if name.ends_with(" - compile") || name.ends_with(" - compile fail") {
+                continue;
+            }
+
+            tests.push(ListedTest(harness.clone(), name.to_owned()));
+        }
+    }
+
+    Ok(tests)
+}
+
 pub fn rerun_dirty(
     ctxt: &CargoDifftestsContext,
     results: &[cargo_difftests::AnalyzeAllSingleTest],
@@ -1,9 +1,9 @@
 use std::path::PathBuf;
 
-use cargo_difftests::{bin_context::CargoDifftestsContext, difftest::Difftest};
+use cargo_difftests::{bin_context::CargoDifftestsContext, difftest::Difftest, path_filter::PathFilter};
 use clap::Parser;
 
-use crate::{cli_core::{CompileTestIndexFlags, DifftestDir, ExportProfdataConfigFlags, IgnoreRegistryFilesFlag}, ops::core::compile_test_index_config, CargoDifftestsResult};
+use crate::{cli_core::{CompileTestIndexFlags, DifftestDir, ExportProfdataConfigFlags, IgnoreRegistryFilesFlag, PathFilterArgs}, ops::core::compile_test_index_config, CargoDifftestsResult};
 
 #[derive(Parser, Debug)]
 pub struct CompileTestIndexCommand {
@@ -18,6 +18,8 @@ pub struct CompileTestIndexCommand {
     compile_test_index_flags: CompileTestIndexFlags,
     #[clap(flatten)]
     ignore_registry_files: IgnoreRegistryFilesFlag,
+    #[clap(flatten)]
+    path_filter: PathFilterArgs,
 }
 impl CompileTestIndexCommand {
     pub fn run(self, ctxt: &CargoDifftestsContext) -> CargoDifftestsResult {
@@ -27,6 +29,7 @@ impl CompileTestIndexCommand {
             self.export_profdata_config_flags,
             self.compile_test_index_flags,
             self.ignore_registry_files,
+            self.path_filter.path_filter()?,
         )
     }
 }
@@ -37,11 +40,16 @@ fn run_compile_test_index(
     export_profdata_config_flags: ExportProfdataConfigFlags,
     compile_test_index_flags: CompileTestIndexFlags,
     ignore_registry_files: IgnoreRegistryFilesFlag,
+    path_filter: PathFilter,
 ) -> CargoDifftestsResult {
     let discovered = Difftest::discover_from(dir, None)?;
     assert!(discovered.has_profdata());
 
-    let config = compile_test_index_config(compile_test_index_flags, ignore_registry_files)?;
+    let config = compile_test_index_config(
+        compile_test_index_flags,
+        ignore_registry_files,
+        path_filter,
+    )?;
 
     let result = discovered.compile_test_index_data(
         export_profdata_config_flags.config(ignore_registry_files),
@@ -1,9 +1,9 @@
 use std::path::PathBuf;
 
-use cargo_difftests::{analysis::AnalysisConfig, bin_context::CargoDifftestsContext, difftest::{Difftest, ExportProfdataConfig}};
+use cargo_difftests::{analysis::{AlwaysRun, AnalysisConfig, DirtyAlgorithm}, bin_context::CargoDifftestsContext, difftest::{CurrentEnvFingerprints, Difftest, ExportProfdataConfig}, path_filter::PathFilter};
 use clap::Parser;
 
-use crate::{cli_core::{AlgoArgs, DifftestDir, DirtyAlgorithm}, ops::core::display_analysis_result, CargoDifftestsResult};
+use crate::{cli_core::{AlgoArgs, DifftestDir}, ops::core::display_analysis_result, CargoDifftestsResult};
 
 #[derive(Parser, Debug)]
 pub struct RunAnalysisCommand {
@@ -15,14 +15,13 @@ pub struct RunAnalysisCommand {
 
 impl RunAnalysisCommand {
     pub fn run(self, ctxt: &CargoDifftestsContext) -> CargoDifftestsResult {
-        run_analysis(self.dir.dir, self.algo.algo, self.algo.commit)
+        run_analysis(self.dir.dir, self.algo.dirty_algorithm()?)
     }
 }
 
 fn run_analysis(
     dir: PathBuf,
-    algo: DirtyAlgorithm,
-    commit: Option<git2::Oid>,
+    dirty_algorithm: DirtyAlgorithm,
 ) -> CargoDifftestsResult {
     let mut discovered = Difftest::discover_from(dir, None)?;
 
@@ -33,9 +32,16 @@ fn run_analysis(
         other_binaries: vec![],
     })?;
 
+    let environment = analysis_cx
+        .env_fingerprint()
+        .and_then(|recorded| CurrentEnvFingerprints::default().matching(recorded).cloned());
+
     analysis_cx.run(&AnalysisConfig {
-        dirty_algorithm: algo.convert(commit),
+        dirty_algorithm,
         error_on_invalid_config: true,
+        environment,
+        path_filter: PathFilter::default(),
+        always_run: AlwaysRun::default(),
     })?;
 
     let r = analysis_cx.finish_analysis();
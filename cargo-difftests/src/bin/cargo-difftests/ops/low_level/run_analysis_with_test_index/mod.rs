@@ -1,9 +1,9 @@
 use std::path::PathBuf;
 
-use cargo_difftests::{analysis::{AnalysisConfig, AnalysisContext}, bin_context::CargoDifftestsContext};
+use cargo_difftests::{analysis::{AlwaysRun, AnalysisConfig, AnalysisContext, DirtyAlgorithm}, bin_context::CargoDifftestsContext, difftest::CurrentEnvFingerprints, path_filter::PathFilter};
 use clap::Parser;
 
-use crate::{cli_core::{AlgoArgs, DirtyAlgorithm}, ops::core::display_analysis_result, CargoDifftestsResult};
+use crate::{cli_core::AlgoArgs, ops::core::display_analysis_result, CargoDifftestsResult};
 
 #[derive(Parser, Debug)]
 pub struct RunAnalysisWithTestIndexCommand {
@@ -16,20 +16,26 @@ pub struct RunAnalysisWithTestIndexCommand {
 
 impl RunAnalysisWithTestIndexCommand {
     pub fn run(self, ctxt: &CargoDifftestsContext) -> CargoDifftestsResult {
-        run_analysis_with_test_index(self.index, self.algo.algo, self.algo.commit)
+        run_analysis_with_test_index(self.index, self.algo.dirty_algorithm()?)
     }
 }
 
 fn run_analysis_with_test_index(
     index: PathBuf,
     dirty_algorithm: DirtyAlgorithm,
-    commit: Option<git2::Oid>,
 ) -> CargoDifftestsResult {
     let mut analysis_cx = AnalysisContext::with_index_from(&index)?;
 
+    let environment = analysis_cx
+        .env_fingerprint()
+        .and_then(|recorded| CurrentEnvFingerprints::default().matching(recorded).cloned());
+
     analysis_cx.run(&AnalysisConfig {
-        dirty_algorithm: dirty_algorithm.convert(commit),
+        dirty_algorithm,
         error_on_invalid_config: true,
+        environment,
+        path_filter: PathFilter::default(),
+        always_run: AlwaysRun::default(),
     })?;
 
     let r = analysis_cx.finish_analysis();
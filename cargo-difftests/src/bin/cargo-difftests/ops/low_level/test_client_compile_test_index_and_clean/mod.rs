@@ -1,9 +1,9 @@
 use std::{fs, path::PathBuf};
 
-use cargo_difftests::{bin_context::CargoDifftestsContext, difftest::{Difftest, DiscoverIndexPathResolver}};
+use cargo_difftests::{bin_context::CargoDifftestsContext, difftest::{Difftest, DiscoverIndexPathResolver}, path_filter::PathFilter};
 use clap::Parser;
 
-use crate::{cli_core::{CompileTestIndexFlags, DifftestDir, ExportProfdataConfigFlags, IgnoreRegistryFilesFlag, IndexPathOrResolve}, ops::core::{compile_test_index_config, resolver_for_index_root}, CargoDifftestsResult};
+use crate::{cli_core::{CompileTestIndexFlags, DifftestDir, ExportProfdataConfigFlags, IgnoreRegistryFilesFlag, IndexPathOrResolve, PathFilterArgs}, ops::core::{compile_test_index_config, resolver_for_index_root}, CargoDifftestsResult};
 
 #[derive(Parser, Debug)]
 pub struct TestClientCompileIndexAndCleanCommand {
@@ -18,6 +18,8 @@ pub struct TestClientCompileIndexAndCleanCommand {
     compile_test_index_flags: CompileTestIndexFlags,
     #[clap(flatten)]
     ignore_registry_files: IgnoreRegistryFilesFlag,
+    #[clap(flatten)]
+    path_filter: PathFilterArgs,
 
     /// The root directory where all the difftests were stored.
     #[clap(long, required_if_eq("output", "resolve"))]
@@ -35,6 +37,7 @@ impl TestClientCompileIndexAndCleanCommand {
             self.export_profdata_config_flags,
             self.compile_test_index_flags,
             self.ignore_registry_files,
+            self.path_filter.path_filter()?,
             self.root,
             self.index_root,
         )
@@ -47,6 +50,7 @@ fn run_test_client_compile_test_index_and_clean(
     export_profdata_config_flags: ExportProfdataConfigFlags,
     compile_test_index_flags: CompileTestIndexFlags,
     ignore_registry_files: IgnoreRegistryFilesFlag,
+    path_filter: PathFilter,
     root: Option<PathBuf>,
     index_root: Option<PathBuf>,
 ) -> CargoDifftestsResult {
@@ -65,7 +69,11 @@ fn run_test_client_compile_test_index_and_clean(
 
     assert!(discovered.has_profdata());
 
-    let config = compile_test_index_config(compile_test_index_flags, ignore_registry_files)?;
+    let config = compile_test_index_config(
+        compile_test_index_flags,
+        ignore_registry_files,
+        path_filter,
+    )?;
 
     let result = discovered.compile_test_index_data(
         export_profdata_config_flags.config(ignore_registry_files),
@@ -11,6 +11,7 @@ mod collect_profiling_data;
 mod discover_difftests;
 mod low_level;
 mod rerun_dirty_from_indexes;
+mod who_covers;
 
 #[derive(Parser, Debug)]
 pub enum App {
@@ -52,6 +53,12 @@ pub enum App {
         #[clap(flatten)]
         cmd: rerun_dirty_from_indexes::RerunDirtyFromIndexesCommand,
     },
+    /// Find the tests that cover a file, a range of lines in a file,
+    /// or a function, from their indexes.
+    WhoCovers {
+        #[clap(flatten)]
+        cmd: who_covers::WhoCoversCommand,
+    },
     /// Low-level commands for debugging and development.
     LowLevel {
         #[clap(subcommand)]
@@ -79,6 +86,9 @@ impl App {
             App::RerunDirtyFromIndexes { cmd } => {
                 cmd.run(ctxt)?;
             }
+            App::WhoCovers { cmd } => {
+                cmd.run(ctxt)?;
+            }
             App::LowLevel { cmd } => {
                 cmd.run(ctxt)?;
             }
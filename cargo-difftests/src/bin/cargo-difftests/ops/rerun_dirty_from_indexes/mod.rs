@@ -1,14 +1,17 @@
 use std::path::PathBuf;
 
-use cargo_difftests::{bin_context::CargoDifftestsContext, AnalyzeAllSingleTest};
+use cargo_difftests::bin_context::CargoDifftestsContext;
 use clap::Parser;
 
 use crate::{
-    cli_core::{AlgoArgs, AnalysisIndex, AnalyzeAllActionArgs, AnalyzeAllActionKind, DifftestsRoot, DifftestsRootRequired, ExportProfdataConfigFlags, IgnoreRegistryFilesFlag, RerunRunner},
+    cli_core::{
+        AlgoArgs, AlwaysRunArgs, AnalyzeAllActionArgs, AnalyzeAllActionKind, ExplainFlag,
+        PathFilterArgs, RerunRunner,
+    },
     CargoDifftestsResult,
 };
 
-use super::{analyze_all_from_index, core::discover_indexes_to_vec};
+use super::analyze_all_from_index;
 
 #[derive(Parser, Debug)]
 pub struct RerunDirtyFromIndexesCommand {
@@ -19,6 +22,18 @@ pub struct RerunDirtyFromIndexesCommand {
 
     #[clap(flatten)]
     algo_args: AlgoArgs,
+
+    #[clap(flatten)]
+    path_filter: PathFilterArgs,
+
+    #[clap(flatten)]
+    always_run: AlwaysRunArgs,
+
+    /// Whether to use (and update) the index database in the index root.
+    ///
+    /// See `analyze-all-from-index --help` for more information.
+    #[clap(long)]
+    index_db: bool,
 }
 
 impl RerunDirtyFromIndexesCommand {
@@ -30,6 +45,10 @@ impl RerunDirtyFromIndexesCommand {
                 action: AnalyzeAllActionKind::RerunDirty,
                 runner: self.runner,
             },
+            explain: ExplainFlag { explain: false },
+            path_filter: self.path_filter,
+            always_run: self.always_run,
+            index_db: self.index_db,
         }
         .run(ctxt)
     }
@@ -0,0 +1,86 @@
+use std::path::{Path, PathBuf};
+
+use cargo_difftests::{
+    bin_context::CargoDifftestsContext,
+    who_covers::{who_covers, CoverageQuery},
+};
+use clap::Parser;
+
+use crate::{cli_core::WhoCoversFormat, ops::core::discover_indexes_to_vec, CargoDifftestsResult};
+
+#[derive(Parser, Debug)]
+pub struct WhoCoversCommand {
+    /// The file to look for, as `<path>[:line[-line]]`.
+    ///
+    /// A relative path is relative to the current directory.
+    #[clap(required_unless_present = "function", conflicts_with = "function")]
+    target: Option<CoverageQuery>,
+    /// Look for the tests that executed the function with the given name,
+    /// instead of a file.
+    ///
+    /// Either the full demangled name, or a suffix of it made of whole path
+    /// segments (like `advanced_arithmetic::mul`).
+    ///
+    /// Requires indexes compiled with `--functions-index` or `--full-index`.
+    #[clap(long)]
+    function: Option<String>,
+    /// The root directory where all the index files are stored.
+    #[clap(long)]
+    index_root: PathBuf,
+    /// The format of the output.
+    #[clap(long, default_value_t = Default::default())]
+    format: WhoCoversFormat,
+}
+
+impl WhoCoversCommand {
+    pub fn run(self, _ctxt: &CargoDifftestsContext) -> CargoDifftestsResult {
+        let query = match (self.target, self.function) {
+            (_, Some(name)) => CoverageQuery::Function { name },
+            (Some(query), None) => query,
+            (None, None) => unreachable!("clap requires either a target or a function"),
+        };
+
+        run_who_covers(self.index_root, query, self.format)
+    }
+}
+
+fn run_who_covers(
+    index_root: PathBuf,
+    query: CoverageQuery,
+    format: WhoCoversFormat,
+) -> CargoDifftestsResult {
+    let indexes = {
+        let mut indexes = vec![];
+        discover_indexes_to_vec(&index_root, &mut indexes)?;
+        indexes
+    };
+
+    let cwd = std::env::current_dir()?;
+    let query = query.resolve(&cwd);
+
+    // the paths in the indexes compiled with `--flatten-files-to=repo-root`
+    // are relative to the root of the repository
+    let root = git2::Repository::open_from_env()
+        .ok()
+        .and_then(|repo| repo.workdir().map(Path::to_path_buf))
+        .unwrap_or(cwd);
+
+    let tests = who_covers(&indexes, &query, &root);
+
+    match format {
+        WhoCoversFormat::Text => {
+            for test in &tests {
+                match test.hits {
+                    Some(hits) => println!("{}\t{hits}", test.test_info.test_name),
+                    None => println!("{}\t-", test.test_info.test_name),
+                }
+            }
+        }
+        WhoCoversFormat::Json => {
+            let out_json = serde_json::to_string(&tests)?;
+            println!("{out_json}");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,272 @@
+/*
+ *        Copyright (c) 2023-2024 Dinu Blanovschi
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        https://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+//! Telling which dependency (and which version of it) the code executed
+//! by a test came from, and whether it is still the one in `Cargo.lock`.
+//!
+//! The sources of the dependencies are extracted by cargo into directories
+//! named after their exact version:
+//!
+//! - `$CARGO_HOME/registry/src/<registry>/<name>-<version>/` for the
+//!   dependencies from a registry (like crates.io), and
+//! - `$CARGO_HOME/git/checkouts/<repository>-<hash>/<revision>/` for git
+//!   dependencies, where `<revision>` is a prefix of the commit id.
+//!
+//! So the files of a test are enough to know the dependencies it executed.
+//! If one of them is not in `Cargo.lock` anymore (it was bumped, or moved
+//! to another revision), the test is dirty.
+//!
+//! Path dependencies are not handled here, as their files are just like the
+//! files of the workspace, and are analyzed like them.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::{Component, Path};
+
+use crate::DifftestsResult;
+
+/// A dependency from the registry or from a git repository, at the exact
+/// version it was used at.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(tag = "source", rename_all = "kebab-case")]
+pub enum Dependency {
+    /// A dependency from a registry.
+    Registry {
+        /// The name of the crate.
+        name: String,
+        /// The version of the crate.
+        version: String,
+    },
+    /// A dependency from a git repository.
+    Git {
+        /// The name of the repository.
+        repository: String,
+        /// A prefix of the id of the commit the dependency was checked out at.
+        revision: String,
+    },
+}
+
+impl Dependency {
+    /// Finds the dependency the file belongs to, if it is in the cargo
+    /// registry, or in a git checkout.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use cargo_difftests::dependencies::Dependency;
+    /// # use home::cargo_home;
+    ///
+    /// let file = cargo_home()?.join("registry/src/index.crates.io-6f17d22bba15001f/serde-json-1.0.0-rc.1/src/lib.rs");
+    ///
+    /// assert_eq!(
+    ///     Dependency::of_file(&file),
+    ///     Some(Dependency::Registry {
+    ///         name: "serde-json".to_owned(),
+    ///         version: "1.0.0-rc.1".to_owned(),
+    ///     })
+    /// );
+    ///
+    /// let file = cargo_home()?.join("git/checkouts/rust-clippy-7c58c8d4e7c2b3b1/a1b2c3d/src/lib.rs");
+    ///
+    /// assert_eq!(
+    ///     Dependency::of_file(&file),
+    ///     Some(Dependency::Git {
+    ///         repository: "rust-clippy".to_owned(),
+    ///         revision: "a1b2c3d".to_owned(),
+    ///     })
+    /// );
+    ///
+    /// assert_eq!(Dependency::of_file("src/main.rs".as_ref()), None);
+    ///
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn of_file(file: &Path) -> Option<Self> {
+        let cargo_home = home::cargo_home().ok()?;
+        Self::of_file_in(&cargo_home, file)
+    }
+
+    fn of_file_in(cargo_home: &Path, file: &Path) -> Option<Self> {
+        let mut components = file.strip_prefix(cargo_home).ok()?.components();
+
+        let mut next = || match components.next() {
+            Some(Component::Normal(it)) => it.to_str(),
+            _ => None,
+        };
+
+        match (next()?, next()?) {
+            ("registry", "src") => {
+                let _registry = next()?;
+                let (name, version) = split_name_and_version(next()?)?;
+
+                Some(Dependency::Registry {
+                    name: name.to_owned(),
+                    version: version.to_owned(),
+                })
+            }
+            ("git", "checkouts") => {
+                let (repository, _hash) = next()?.rsplit_once('-')?;
+                let revision = next()?;
+
+                Some(Dependency::Git {
+                    repository: repository.to_owned(),
+                    revision: revision.to_owned(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks whether this exact dependency is in the lockfile.
+    pub fn is_locked(&self, lockfile: &Lockfile) -> bool {
+        lockfile.packages.iter().any(|package| {
+            let source = package.source.as_deref().unwrap_or_default();
+
+            match self {
+                Dependency::Registry { name, version } => {
+                    (source.starts_with("registry+") || source.starts_with("sparse+"))
+                        && package.name == *name
+                        && package.version == *version
+                }
+                Dependency::Git {
+                    repository,
+                    revision,
+                } => {
+                    git_repository_name(source) == Some(repository.as_str())
+                        && source
+                            .rsplit_once('#')
+                            .is_some_and(|(_, commit)| commit.starts_with(revision.as_str()))
+                }
+            }
+        })
+    }
+}
+
+impl fmt::Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Dependency::Registry { name, version } => write!(f, "{name} {version}"),
+            Dependency::Git {
+                repository,
+                revision,
+            } => write!(f, "{repository} (git, at {revision})"),
+        }
+    }
+}
+
+/// Splits the name of a directory in the registry, like `serde-1.0.130`,
+/// into the name and version of the crate.
+///
+/// Crate names cannot contain dots, and versions always do, so the version
+/// starts after the last dash before the first dot.
+fn split_name_and_version(dir: &str) -> Option<(&str, &str)> {
+    let dot = dir.find('.')?;
+    let dash = dir[..dot].rfind('-')?;
+
+    Some((&dir[..dash], &dir[dash + 1..]))
+}
+
+/// The name cargo gives to the checkouts of the git repository in the
+/// given lockfile source, like `rust-clippy` for
+/// `git+https://github.com/rust-lang/rust-clippy.git?branch=master#a1b2c3d`:
+/// the last segment of its URL, without the `.git` suffix.
+fn git_repository_name(source: &str) -> Option<&str> {
+    let url = source.strip_prefix("git+")?;
+    let url = url.split(['?', '#']).next()?;
+    let name = url.trim_end_matches('/').rsplit('/').next()?;
+
+    Some(name.strip_suffix(".git").unwrap_or(name))
+}
+
+/// Finds the dependencies the given files belong to.
+pub fn dependencies_of_files<'a>(files: impl IntoIterator<Item = &'a Path>) -> Vec<Dependency> {
+    let Ok(cargo_home) = home::cargo_home() else {
+        return vec![];
+    };
+
+    files
+        .into_iter()
+        .filter_map(|file| Dependency::of_file_in(&cargo_home, file))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// A package in a [`Lockfile`].
+#[derive(serde::Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct LockedPackage {
+    /// The name of the package.
+    pub name: String,
+    /// The version of the package.
+    pub version: String,
+    /// Where the package comes from, like
+    /// `registry+https://github.com/rust-lang/crates.io-index`.
+    ///
+    /// [`None`] for the packages in the workspace and path dependencies.
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+/// The packages in a `Cargo.lock` file.
+#[derive(serde::Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Lockfile {
+    /// The packages.
+    #[serde(default, rename = "package")]
+    pub packages: Vec<LockedPackage>,
+}
+
+impl Lockfile {
+    /// Parses the contents of a `Cargo.lock` file.
+    ///
+    /// Only the `name`, `version` and `source` of the `[[package]]` entries
+    /// are read, everything else is ignored.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use cargo_difftests::dependencies::Lockfile;
+    ///
+    /// let lockfile = Lockfile::parse(r#"
+    /// version = 3
+    ///
+    /// [[package]]
+    /// name = "serde"
+    /// version = "1.0.130"
+    /// source = "registry+https://github.com/rust-lang/crates.io-index"
+    /// checksum = "f12d06de37cf59146fbdecab66aa99f9fe4f78722e3607577a5375d66bd0c913"
+    ///
+    /// [[package]]
+    /// name = "my-crate"
+    /// version = "0.1.0"
+    /// dependencies = [
+    ///  "serde",
+    /// ]
+    /// "#)?;
+    ///
+    /// assert_eq!(lockfile.packages.len(), 2);
+    /// assert_eq!(lockfile.packages[0].name, "serde");
+    /// assert_eq!(lockfile.packages[1].source, None);
+    ///
+    /// # Ok::<(), cargo_difftests::DifftestsError>(())
+    /// ```
+    pub fn parse(s: &str) -> DifftestsResult<Self> {
+        Ok(toml::from_str(s)?)
+    }
+
+    /// Reads and parses a `Cargo.lock` file.
+    pub fn read_from_file(path: &Path) -> DifftestsResult<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+}
@@ -16,6 +16,7 @@
 
 //! Holds the [`Difftest`] struct and related functions.
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::OsStr;
 use std::fs;
 use std::path::{Path, PathBuf};
@@ -24,7 +25,8 @@ use std::process::Command;
 use cargo_difftests_core::CoreTestDesc;
 use log::{debug, info, warn};
 
-use crate::analysis::AnalysisContext;
+use crate::analysis::{AnalysisContext, DirtyReason};
+use crate::dependencies::{Dependency, Lockfile};
 use crate::index_data::{IndexDataCompilerConfig, TestIndex};
 use crate::{analysis_data, DifftestsError, DifftestsResult};
 
@@ -44,6 +46,18 @@ pub struct Difftest {
     pub(crate) index_data: Option<PathBuf>,
 
     pub(crate) cleaned: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) outcome: Option<TestOutcome>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) git_state: Option<GitState>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) tracked_files: Vec<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) env_fingerprint: Option<EnvFingerprint>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) always_run: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) nextest_binary_id: Option<String>,
 }
 
 impl Difftest {
@@ -78,6 +92,7 @@ impl Difftest {
             test_name,
             test_binary,
             extra_desc,
+            nextest_binary_id: self.nextest_binary_id.clone(),
         })
     }
 
@@ -160,6 +175,55 @@ impl Difftest {
         self.cleaned
     }
 
+    /// Gets the [`TestOutcome`] of the last run of the test, if it was recorded.
+    pub fn outcome(&self) -> Option<&TestOutcome> {
+        self.outcome.as_ref()
+    }
+
+    /// Checks whether the test failed the last time it was run.
+    ///
+    /// Tests that failed are always considered dirty by the analysis.
+    pub fn test_failed(&self) -> bool {
+        self.outcome
+            .as_ref()
+            .is_some_and(|outcome| outcome.status == TestStatus::Failed)
+    }
+
+    /// Checks whether the test marked itself as one that must always run,
+    /// with `cargo_difftests_testclient::always_run`.
+    ///
+    /// Such tests are always considered dirty by the analysis.
+    pub fn always_run(&self) -> bool {
+        self.always_run
+    }
+
+    /// Gets the [`GitState`] of the repository when the test was run,
+    /// if it was recorded.
+    pub fn git_state(&self) -> Option<&GitState> {
+        self.git_state.as_ref()
+    }
+
+    /// Gets the [`EnvFingerprint`] of the environment the test was run in,
+    /// if it was recorded.
+    pub fn env_fingerprint(&self) -> Option<&EnvFingerprint> {
+        self.env_fingerprint.as_ref()
+    }
+
+    /// Checks whether the dependencies were instrumented for coverage when
+    /// the test was run, so the files from the registry tell which of them
+    /// the test executed code from.
+    fn instrumented_dependencies(&self) -> bool {
+        self.env_fingerprint
+            .as_ref()
+            .is_some_and(|it| it.instrumented_dependencies)
+    }
+
+    /// Gets the files the test declared as its inputs, with
+    /// `cargo_difftests_testclient::track_file`.
+    pub fn tracked_files(&self) -> &[PathBuf] {
+        &self.tracked_files
+    }
+
     /// Checks whether the [`Difftest`] has the `.profdata` file.
     pub fn has_profdata(&self) -> bool {
         if self.cleaned {
@@ -237,7 +301,14 @@ impl Difftest {
         config: ExportProfdataConfig,
     ) -> DifftestsResult<AnalysisContext<'_>> {
         info!("Starting analysis...");
-        let profdata = self.export_profdata(config)?;
+        // when the dependencies were instrumented, the files from the registry
+        // are needed to know the dependencies of the test, and are left out
+        // by the analysis instead
+        let profdata = self.export_profdata(ExportProfdataConfig {
+            ignore_registry_files: config.ignore_registry_files
+                && !self.instrumented_dependencies(),
+            ..config
+        })?;
 
         Ok(AnalysisContext::new(self, profdata))
     }
@@ -253,7 +324,13 @@ impl Difftest {
     ) -> DifftestsResult<TestIndex> {
         info!("Compiling test index data...");
 
-        let profdata = self.export_profdata(config)?;
+        // same as in `start_analysis`, they are left out by
+        // `IndexDataCompilerConfig::accept_file`
+        let profdata = self.export_profdata(ExportProfdataConfig {
+            ignore_registry_files: config.ignore_registry_files
+                && !self.instrumented_dependencies(),
+            ..config
+        })?;
         let test_index_data = TestIndex::index(self, profdata, index_data_compiler_config)?;
 
         info!("Done compiling test index data.");
@@ -301,7 +378,7 @@ pub enum DiscoverIndexPathResolver {
     /// A custom remapping function.
     Custom {
         /// The remapping function.
-        f: Box<dyn Fn(&Path) -> Option<PathBuf>>,
+        f: Box<dyn Fn(&Path) -> Option<PathBuf> + Send + Sync>,
     },
 }
 
@@ -374,6 +451,18 @@ fn discover_difftest_from_tempdir(
 
     let mut cleaned = false;
 
+    let mut outcome = None;
+
+    let mut git_state = None;
+
+    let mut tracked_files = vec![];
+
+    let mut env_fingerprint = None;
+
+    let mut always_run = false;
+
+    let mut nextest_binary_id = None;
+
     for e in dir.read_dir()? {
         let e = e?;
         let p = e.path();
@@ -406,6 +495,60 @@ fn discover_difftest_from_tempdir(
         if file_name == Some(OsStr::new(Difftest::CLEANED_FILE_NAME)) {
             cleaned = true;
         }
+
+        if file_name
+            == Some(OsStr::new(
+                cargo_difftests_core::CARGO_DIFFTESTS_TEST_OUTCOME_FILENAME,
+            ))
+        {
+            outcome = Some(TestOutcome::read_from_file(&p)?);
+        }
+
+        if file_name
+            == Some(OsStr::new(
+                cargo_difftests_core::CARGO_DIFFTESTS_GIT_STATE_FILENAME,
+            ))
+        {
+            git_state = Some(GitState::read_from_file(&p)?);
+        }
+
+        if file_name
+            == Some(OsStr::new(
+                cargo_difftests_core::CARGO_DIFFTESTS_TRACKED_FILES_FILENAME,
+            ))
+        {
+            tracked_files = fs::read_to_string(&p)?
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(PathBuf::from)
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect();
+        }
+
+        if file_name
+            == Some(OsStr::new(
+                cargo_difftests_core::CARGO_DIFFTESTS_ENV_FINGERPRINT_FILENAME,
+            ))
+        {
+            env_fingerprint = Some(EnvFingerprint::read_from_file(&p)?);
+        }
+
+        if file_name
+            == Some(OsStr::new(
+                cargo_difftests_core::CARGO_DIFFTESTS_ALWAYS_RUN_FILENAME,
+            ))
+        {
+            always_run = true;
+        }
+
+        if file_name
+            == Some(OsStr::new(
+                cargo_difftests_core::CARGO_DIFFTESTS_NEXTEST_BINARY_ID_FILENAME,
+            ))
+        {
+            nextest_binary_id = Some(fs::read_to_string(&p)?);
+        }
     }
 
     let index_data = 'index_data: {
@@ -439,6 +582,12 @@ fn discover_difftest_from_tempdir(
         profdata_file,
         index_data,
         cleaned,
+        outcome,
+        git_state,
+        tracked_files,
+        env_fingerprint,
+        always_run,
+        nextest_binary_id,
     })
 }
 
@@ -655,10 +804,412 @@ pub fn export_profdata_file(
     Ok(r)
 }
 
+/// The status a test finished with.
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TestStatus {
+    /// The test passed.
+    #[serde(rename = "passed")]
+    Passed,
+    /// The test failed.
+    #[serde(rename = "failed")]
+    Failed,
+    /// The test was ignored by the test harness (e.g. `#[ignore]`).
+    #[serde(rename = "ignored")]
+    Ignored,
+}
+
+/// The outcome of a single run of a test, stored in the
+/// [`CARGO_DIFFTESTS_TEST_OUTCOME_FILENAME`] file in the difftest directory.
+///
+/// [`CARGO_DIFFTESTS_TEST_OUTCOME_FILENAME`]: cargo_difftests_core::CARGO_DIFFTESTS_TEST_OUTCOME_FILENAME
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct TestOutcome {
+    /// Whether the test passed, failed or was ignored.
+    pub status: TestStatus,
+    /// The wall-clock time it took to run the test process.
+    pub duration: std::time::Duration,
+    /// The exit code of the test process, or [`None`] if it
+    /// was terminated by a signal.
+    pub exit_code: Option<i32>,
+}
+
+impl TestOutcome {
+    /// Writes the [`TestOutcome`] to a file.
+    pub fn write_to_file(&self, path: &Path) -> DifftestsResult {
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Reads a [`TestOutcome`] from a file.
+    pub fn read_from_file(path: &Path) -> DifftestsResult<Self> {
+        let s = fs::read_to_string(path)?;
+        serde_json::from_str(&s).map_err(|e| DifftestsError::Json(e, Some(path.to_path_buf())))
+    }
+}
+
+/// The state of the git repository when a test was run, stored in the
+/// [`CARGO_DIFFTESTS_GIT_STATE_FILENAME`] file in the difftest directory.
+///
+/// The `git-diff-*` algorithms diff from the recorded commit by default.
+///
+/// [`CARGO_DIFFTESTS_GIT_STATE_FILENAME`]: cargo_difftests_core::CARGO_DIFFTESTS_GIT_STATE_FILENAME
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct GitState {
+    /// The commit `HEAD` pointed to.
+    #[serde(with = "oid_serde")]
+    pub commit: git2::Oid,
+    /// Whether the tracked files in the working tree had changes
+    /// that were not committed.
+    pub dirty: bool,
+}
+
+impl GitState {
+    /// Gets the current state of the git repository of the current directory.
+    ///
+    /// Returns `Ok(None)` if there is no repository, or it has no commits yet.
+    pub fn current() -> DifftestsResult<Option<Self>> {
+        let Ok(repo) = git2::Repository::open_from_env() else {
+            return Ok(None);
+        };
+
+        let Ok(head) = repo.head().and_then(|head| head.peel_to_commit()) else {
+            return Ok(None);
+        };
+
+        let mut status_options = git2::StatusOptions::new();
+        status_options
+            .include_untracked(false)
+            .include_ignored(false);
+
+        let dirty = !repo.statuses(Some(&mut status_options))?.is_empty();
+
+        Ok(Some(Self {
+            commit: head.id(),
+            dirty,
+        }))
+    }
+
+    /// Writes the [`GitState`] to a file.
+    pub fn write_to_file(&self, path: &Path) -> DifftestsResult {
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Reads a [`GitState`] from a file.
+    pub fn read_from_file(path: &Path) -> DifftestsResult<Self> {
+        let s = fs::read_to_string(path)?;
+        serde_json::from_str(&s).map_err(|e| DifftestsError::Json(e, Some(path.to_path_buf())))
+    }
+}
+
+/// The environment variables that are always part of an [`EnvFingerprint`].
+pub const DEFAULT_FINGERPRINT_ENV_VARS: &[&str] = &["RUSTFLAGS", "CARGO_ENCODED_RUSTFLAGS"];
+
+/// The parts of the environment of a test run that can change the result of
+/// the test without changing any of the files it touched, stored in the
+/// [`CARGO_DIFFTESTS_ENV_FINGERPRINT_FILENAME`] file in the difftest directory.
+///
+/// If any of them is different at analysis time, the test is considered
+/// dirty, regardless of the [`DirtyAlgorithm`].
+///
+/// [`CARGO_DIFFTESTS_ENV_FINGERPRINT_FILENAME`]: cargo_difftests_core::CARGO_DIFFTESTS_ENV_FINGERPRINT_FILENAME
+/// [`DirtyAlgorithm`]: crate::analysis::DirtyAlgorithm
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct EnvFingerprint {
+    /// The version of `rustc`, as printed by `rustc --version`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rustc_version: Option<String>,
+    /// The hash of the contents of the `Cargo.lock` of the workspace, as
+    /// computed by [`hash_file`](crate::index_data::hash_file).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cargo_lock_hash: Option<String>,
+    /// The features enabled for each package in the workspace.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub features: BTreeMap<String, Vec<String>>,
+    /// The flags that select the features (`--features`, `--all-features`
+    /// and `--no-default-features`) the tests were built with.
+    ///
+    /// The current fingerprint has to be computed with the same flags for
+    /// the [`features`](EnvFingerprint::features) to be comparable, see
+    /// [`CurrentEnvFingerprints`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub feature_args: Vec<String>,
+    /// The values of the environment variables, [`None`] if unset.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env_vars: BTreeMap<String, Option<String>>,
+    /// Whether the dependencies were instrumented for coverage too, so the
+    /// dependencies the test executed code from are known (see
+    /// [`TestIndex::dependencies`]).
+    ///
+    /// If so, only those are checked when `Cargo.lock` changes.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub instrumented_dependencies: bool,
+    /// The hashes (see [`hash_file`](crate::index_data::hash_file)) of the
+    /// always dirty files (see [`PathFilter::always_dirty_files`]) when the
+    /// tests were run, by their path relative to the root of the filter.
+    ///
+    /// Not compared by [`EnvFingerprint::changes`], but by the algorithms
+    /// that compare contents instead of modification times, see
+    /// [`always_dirty_hash_analysis`].
+    ///
+    /// [`PathFilter::always_dirty_files`]: crate::path_filter::PathFilter::always_dirty_files
+    /// [`always_dirty_hash_analysis`]: crate::analysis::always_dirty_hash_analysis
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub always_dirty_file_hashes: Option<BTreeMap<PathBuf, String>>,
+    /// The packages in the `Cargo.lock` of the workspace.
+    ///
+    /// Only the hash of the lockfile is recorded, but the current one is
+    /// read by [`EnvFingerprint::current`], to tell which dependencies
+    /// changed when the hash is different.
+    #[serde(skip)]
+    pub lockfile: Option<Lockfile>,
+}
+
+impl EnvFingerprint {
+    /// Fingerprints the environment of the workspace in the current directory,
+    /// with the given environment variables, in addition to the
+    /// [`DEFAULT_FINGERPRINT_ENV_VARS`], and with the features selected by
+    /// the given cargo flags (see [`EnvFingerprint::feature_args`]).
+    pub fn current(env_vars: &[String], feature_args: &[String]) -> DifftestsResult<Self> {
+        #[derive(serde::Deserialize)]
+        struct Metadata {
+            packages: Vec<Package>,
+            workspace_members: Vec<String>,
+            resolve: Option<Resolve>,
+            workspace_root: PathBuf,
+        }
+
+        #[derive(serde::Deserialize)]
+        struct Package {
+            id: String,
+            name: String,
+        }
+
+        #[derive(serde::Deserialize)]
+        struct Resolve {
+            nodes: Vec<ResolveNode>,
+        }
+
+        #[derive(serde::Deserialize)]
+        struct ResolveNode {
+            id: String,
+            features: Vec<String>,
+        }
+
+        let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+        let output = Command::new(cargo)
+            .args(["metadata", "--format-version", "1"])
+            .args(feature_args)
+            .output()?;
+
+        if !output.status.success() {
+            return Err(DifftestsError::ProcessFailed {
+                name: "cargo metadata",
+            });
+        }
+
+        let metadata: Metadata =
+            serde_json::from_slice(&output.stdout).map_err(|e| DifftestsError::Json(e, None))?;
+
+        let mut features = BTreeMap::new();
+
+        for node in metadata.resolve.map(|it| it.nodes).unwrap_or_default() {
+            if !metadata.workspace_members.contains(&node.id) {
+                continue;
+            }
+
+            if let Some(package) = metadata.packages.iter().find(|it| it.id == node.id) {
+                let mut node_features = node.features;
+                node_features.sort();
+                features.insert(package.name.clone(), node_features);
+            }
+        }
+
+        let rustc = std::env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+        let output = Command::new(rustc).arg("--version").output()?;
+
+        if !output.status.success() {
+            return Err(DifftestsError::ProcessFailed {
+                name: "rustc --version",
+            });
+        }
+
+        let rustc_version = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+
+        let env_vars = DEFAULT_FINGERPRINT_ENV_VARS
+            .iter()
+            .copied()
+            .chain(env_vars.iter().map(String::as_str))
+            .map(|name| (name.to_owned(), std::env::var(name).ok()))
+            .collect();
+
+        let lockfile_path = metadata.workspace_root.join("Cargo.lock");
+
+        Ok(Self {
+            rustc_version: Some(rustc_version),
+            cargo_lock_hash: crate::index_data::hash_file(&lockfile_path),
+            features,
+            feature_args: feature_args.to_vec(),
+            env_vars,
+            instrumented_dependencies: false,
+            always_dirty_file_hashes: None,
+            lockfile: Lockfile::read_from_file(&lockfile_path).ok(),
+        })
+    }
+
+    /// Compares the (recorded) fingerprint with the current one, and returns
+    /// a [`DirtyReason::EnvironmentChanged`] for every difference.
+    ///
+    /// The environment variables are compared with their current values,
+    /// even if they are not in the `current` fingerprint.
+    ///
+    /// If `Cargo.lock` changed, and the dependencies were instrumented when
+    /// the test was run, only the `dependencies` of the test that are not
+    /// in the current lockfile anymore are reported, as
+    /// [`DirtyReason::DependencyChanged`]. Otherwise, the whole `Cargo.lock`
+    /// is reported as changed.
+    pub fn changes(
+        &self,
+        current: &EnvFingerprint,
+        dependencies: &[Dependency],
+    ) -> Vec<DirtyReason> {
+        let mut reasons = vec![];
+        let mut changed_dependencies = vec![];
+
+        let mut compare = |what: String, recorded: Option<String>, current: Option<String>| {
+            if recorded != current {
+                reasons.push(DirtyReason::EnvironmentChanged {
+                    what,
+                    recorded,
+                    current,
+                });
+            }
+        };
+
+        compare(
+            "rustc version".to_owned(),
+            self.rustc_version.clone(),
+            current.rustc_version.clone(),
+        );
+
+        if self.cargo_lock_hash != current.cargo_lock_hash {
+            match &current.lockfile {
+                Some(lockfile) if self.instrumented_dependencies => changed_dependencies.extend(
+                    dependencies
+                        .iter()
+                        .filter(|dependency| !dependency.is_locked(lockfile))
+                        .map(|dependency| DirtyReason::DependencyChanged {
+                            dependency: dependency.clone(),
+                        }),
+                ),
+                _ => compare(
+                    "Cargo.lock".to_owned(),
+                    self.cargo_lock_hash.clone(),
+                    current.cargo_lock_hash.clone(),
+                ),
+            }
+        }
+
+        let packages = self
+            .features
+            .keys()
+            .chain(current.features.keys())
+            .collect::<BTreeSet<_>>();
+
+        for package in packages {
+            let features_of = |fingerprint: &EnvFingerprint| {
+                fingerprint
+                    .features
+                    .get(package)
+                    .map(|features| features.join(","))
+            };
+
+            compare(
+                format!("features of {package}"),
+                features_of(self),
+                features_of(current),
+            );
+        }
+
+        for (name, value) in &self.env_vars {
+            let current_value = match current.env_vars.get(name) {
+                Some(value) => value.clone(),
+                None => std::env::var(name).ok(),
+            };
+
+            compare(format!("${name}"), value.clone(), current_value);
+        }
+
+        reasons.extend(changed_dependencies);
+        reasons
+    }
+
+    /// Writes the [`EnvFingerprint`] to a file.
+    pub fn write_to_file(&self, path: &Path) -> DifftestsResult {
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Reads an [`EnvFingerprint`] from a file.
+    pub fn read_from_file(path: &Path) -> DifftestsResult<Self> {
+        let s = fs::read_to_string(path)?;
+        serde_json::from_str(&s).map_err(|e| DifftestsError::Json(e, Some(path.to_path_buf())))
+    }
+}
+
+/// The fingerprints of the current environment, to compare with the ones
+/// recorded when the tests were run.
+///
+/// [`EnvFingerprint::current`] runs `cargo metadata` and `rustc --version`,
+/// so the fingerprint is only computed once for each set of
+/// [`EnvFingerprint::feature_args`] (usually all the tests share the same
+/// one), and then reused.
+#[derive(Debug, Default)]
+pub struct CurrentEnvFingerprints {
+    fingerprints: BTreeMap<Vec<String>, Option<EnvFingerprint>>,
+}
+
+impl CurrentEnvFingerprints {
+    /// Gets the fingerprint of the current environment to compare the
+    /// recorded one with, computed with the same feature flags.
+    ///
+    /// [`None`] if the environment could not be fingerprinted, in which
+    /// case it is not checked.
+    pub fn matching(&mut self, recorded: &EnvFingerprint) -> Option<&EnvFingerprint> {
+        self.fingerprints
+            .entry(recorded.feature_args.clone())
+            .or_insert_with_key(|feature_args| {
+                EnvFingerprint::current(&[], feature_args)
+                    .map_err(|e| {
+                        warn!("could not fingerprint the environment, it will not be checked: {e}")
+                    })
+                    .ok()
+            })
+            .as_ref()
+    }
+}
+
+mod oid_serde {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(oid: &git2::Oid, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(oid)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<git2::Oid, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct TestInfo {
     pub test_name: String,
     pub test_binary: PathBuf,
 
     pub extra_desc: Option<CoreTestDesc>,
+
+    /// The id of the test binary, if the test was run by nextest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nextest_binary_id: Option<String>,
 }
@@ -0,0 +1,348 @@
+/*
+ *        Copyright (c) 2023-2024 Dinu Blanovschi
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        https://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+//! Tracing the files a process opens for reading, so that the inputs of a
+//! test (like fixtures) can be tracked without declaring them by hand.
+//!
+//! On Linux, the process is run under `ptrace`, and every successful
+//! `open`, `openat` and `openat2` syscall that opens a file read-only,
+//! in the process or any of its threads and child processes, is recorded.
+//!
+//! It is not supported on other platforms.
+
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+use crate::DifftestsResult;
+
+/// Runs the command to completion, like [`Command::output`], and returns
+/// its output along with the paths of all the files it opened for reading.
+///
+/// The paths are absolute, but not canonicalized, and may include files
+/// that do not exist anymore, or directories.
+///
+/// The standard output and error are only captured if the command was
+/// configured to pipe them.
+///
+/// On Linux, the command is spawned and traced on the calling thread, which
+/// only waits for its own children, so several commands can be traced at the
+/// same time from different threads.
+pub fn output_with_file_tracing(cmd: &mut Command) -> DifftestsResult<(Output, BTreeSet<PathBuf>)> {
+    imp::output_with_file_tracing(cmd)
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::collections::{BTreeSet, HashMap};
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::process::{CommandExt, ExitStatusExt};
+    use std::path::PathBuf;
+    use std::process::{Command, ExitStatus, Output};
+    use std::{mem, thread};
+
+    use libc::{c_int, c_long, c_void, pid_t};
+    use log::debug;
+    use path_absolutize::Absolutize;
+
+    use crate::DifftestsResult;
+
+    // not in all the versions of `libc`
+    const PTRACE_GET_SYSCALL_INFO: u32 = 0x420e;
+    const PTRACE_SYSCALL_INFO_ENTRY: u8 = 1;
+    const PTRACE_SYSCALL_INFO_EXIT: u8 = 2;
+
+    /// `struct ptrace_syscall_info`, from `linux/ptrace.h`.
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct SyscallInfo {
+        op: u8,
+        pad: [u8; 3],
+        arch: u32,
+        instruction_pointer: u64,
+        stack_pointer: u64,
+        /// For syscall-entry stops, the syscall number followed by its
+        /// arguments, and for syscall-exit stops, the return value.
+        data: [u64; 8],
+    }
+
+    pub(super) fn output_with_file_tracing(
+        cmd: &mut Command,
+    ) -> DifftestsResult<(Output, BTreeSet<PathBuf>)> {
+        // SAFETY: `ptrace(PTRACE_TRACEME)` is async-signal-safe.
+        unsafe {
+            cmd.pre_exec(|| {
+                if ptrace(libc::PTRACE_TRACEME as _, 0, 0, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                Ok(())
+            });
+        }
+
+        let mut child = cmd.spawn()?;
+
+        fn read_all(mut r: impl Read + Send + 'static) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+            thread::spawn(move || {
+                let mut buf = vec![];
+                r.read_to_end(&mut buf)?;
+                Ok(buf)
+            })
+        }
+
+        let stdout = child.stdout.take().map(read_all);
+        let stderr = child.stderr.take().map(read_all);
+
+        // the child is reaped by the tracer, so it must not be waited for
+        let (status, files) = trace(child.id() as pid_t)?;
+
+        let join = |reader: Option<thread::JoinHandle<io::Result<Vec<u8>>>>| match reader {
+            Some(reader) => reader.join().expect("reader thread panicked"),
+            None => Ok(vec![]),
+        };
+
+        let output = Output {
+            status,
+            stdout: join(stdout)?,
+            stderr: join(stderr)?,
+        };
+
+        Ok((output, files))
+    }
+
+    unsafe fn ptrace(request: u32, pid: pid_t, addr: usize, data: usize) -> c_long {
+        unsafe { libc::ptrace(request as _, pid, addr as *mut c_void, data as *mut c_void) }
+    }
+
+    fn waitpid(pid: pid_t, status: &mut c_int) -> io::Result<pid_t> {
+        loop {
+            // `__WNOTHREAD`, to leave the children of the other threads
+            // (like the other tests running in parallel) alone
+            let r = unsafe { libc::waitpid(pid, status, libc::__WALL | libc::__WNOTHREAD) };
+
+            if r != -1 {
+                return Ok(r);
+            }
+
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+
+    /// Traces the (stopped at `exec`) process until it and all of its
+    /// descendants exit, and returns its exit status.
+    fn trace(root: pid_t) -> DifftestsResult<(ExitStatus, BTreeSet<PathBuf>)> {
+        let mut status = 0;
+
+        waitpid(root, &mut status)?;
+
+        if !libc::WIFSTOPPED(status) {
+            return Ok((ExitStatus::from_raw(status), BTreeSet::new()));
+        }
+
+        let options = libc::PTRACE_O_TRACESYSGOOD
+            | libc::PTRACE_O_TRACECLONE
+            | libc::PTRACE_O_TRACEFORK
+            | libc::PTRACE_O_TRACEVFORK
+            | libc::PTRACE_O_EXITKILL;
+
+        unsafe {
+            if ptrace(libc::PTRACE_SETOPTIONS as _, root, 0, options as usize) == -1 {
+                return Err(io::Error::last_os_error().into());
+            }
+            ptrace(libc::PTRACE_SYSCALL as _, root, 0, 0);
+        }
+
+        let mut files = BTreeSet::new();
+        // the file being opened by each task, between syscall entry and exit
+        let mut opening = HashMap::<pid_t, PathBuf>::new();
+        let mut root_status = None;
+
+        loop {
+            let pid = match waitpid(-1, &mut status) {
+                Ok(pid) => pid,
+                Err(e) if e.raw_os_error() == Some(libc::ECHILD) => break,
+                Err(e) => return Err(e.into()),
+            };
+
+            if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+                opening.remove(&pid);
+                if pid == root {
+                    root_status = Some(status);
+                }
+                continue;
+            }
+
+            if !libc::WIFSTOPPED(status) {
+                continue;
+            }
+
+            let signal = match libc::WSTOPSIG(status) {
+                sig if sig == libc::SIGTRAP | 0x80 => {
+                    on_syscall_stop(pid, &mut opening, &mut files);
+                    0
+                }
+                // ptrace events, and the initial stop of new tasks
+                libc::SIGTRAP | libc::SIGSTOP => 0,
+                sig => sig,
+            };
+
+            // fails if the task was killed in the meantime, which is
+            // then reported by `waitpid`
+            unsafe {
+                ptrace(libc::PTRACE_SYSCALL as _, pid, 0, signal as usize);
+            }
+        }
+
+        let status =
+            root_status.ok_or_else(|| io::Error::other("lost track of the traced process"))?;
+
+        Ok((ExitStatus::from_raw(status), files))
+    }
+
+    fn on_syscall_stop(
+        pid: pid_t,
+        opening: &mut HashMap<pid_t, PathBuf>,
+        files: &mut BTreeSet<PathBuf>,
+    ) {
+        let mut info = mem::MaybeUninit::<SyscallInfo>::zeroed();
+
+        let size = unsafe {
+            ptrace(
+                PTRACE_GET_SYSCALL_INFO,
+                pid,
+                mem::size_of::<SyscallInfo>(),
+                info.as_mut_ptr() as usize,
+            )
+        };
+
+        if size <= 0 {
+            return;
+        }
+
+        // SAFETY: zero-initialized, and only partially filled in by the kernel.
+        let info = unsafe { info.assume_init() };
+
+        match info.op {
+            PTRACE_SYSCALL_INFO_ENTRY => {
+                opening.remove(&pid);
+
+                if let Some(path) = opened_file(pid, &info) {
+                    opening.insert(pid, path);
+                }
+            }
+            PTRACE_SYSCALL_INFO_EXIT => {
+                if let Some(path) = opening.remove(&pid)
+                    && (info.data[0] as i64) >= 0
+                {
+                    debug!("traced process {pid} opened {}", path.display());
+                    files.insert(path);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The file a syscall opens for reading, if it is one of the `open`
+    /// syscalls.
+    fn opened_file(pid: pid_t, info: &SyscallInfo) -> Option<PathBuf> {
+        let nr = info.data[0] as c_long;
+        let args = &info.data[1..7];
+
+        let (dirfd, path, flags) = match nr {
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_open => (libc::AT_FDCWD, args[0], args[1]),
+            libc::SYS_openat => (args[0] as c_int, args[1], args[2]),
+            // the flags are the first field of `struct open_how`
+            libc::SYS_openat2 => {
+                let mut flags = [0; 8];
+                read_memory(pid, args[2], &mut flags).ok()?;
+                (args[0] as c_int, args[1], u64::from_ne_bytes(flags))
+            }
+            _ => return None,
+        };
+
+        if flags as c_int & libc::O_ACCMODE != libc::O_RDONLY {
+            return None;
+        }
+
+        let path = read_c_string(pid, path)?;
+
+        if path.is_absolute() {
+            return Some(path.absolutize().ok()?.into_owned());
+        }
+
+        let base = if dirfd == libc::AT_FDCWD {
+            std::fs::read_link(format!("/proc/{pid}/cwd"))
+        } else {
+            std::fs::read_link(format!("/proc/{pid}/fd/{dirfd}"))
+        }
+        .ok()?;
+
+        Some(path.absolutize_from(&base).ok()?.into_owned())
+    }
+
+    fn read_memory(pid: pid_t, addr: u64, buf: &mut [u8]) -> io::Result<usize> {
+        File::open(format!("/proc/{pid}/mem"))?.read_at(buf, addr)
+    }
+
+    fn read_c_string(pid: pid_t, addr: u64) -> Option<PathBuf> {
+        use std::os::unix::ffi::OsStringExt;
+
+        let mut bytes = vec![];
+        let mut chunk = [0; 256];
+
+        while bytes.len() < libc::PATH_MAX as usize {
+            let read = read_memory(pid, addr + bytes.len() as u64, &mut chunk).ok()?;
+            if read == 0 {
+                return None;
+            }
+
+            match chunk[..read].iter().position(|&b| b == 0) {
+                Some(nul) => {
+                    bytes.extend_from_slice(&chunk[..nul]);
+                    return Some(PathBuf::from(std::ffi::OsString::from_vec(bytes)));
+                }
+                None => bytes.extend_from_slice(&chunk[..read]),
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::collections::BTreeSet;
+    use std::path::PathBuf;
+    use std::process::{Command, Output};
+
+    use crate::DifftestsResult;
+
+    pub(super) fn output_with_file_tracing(
+        _cmd: &mut Command,
+    ) -> DifftestsResult<(Output, BTreeSet<PathBuf>)> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "file access tracing is only supported on Linux",
+        )
+        .into())
+    }
+}
@@ -0,0 +1,245 @@
+/*
+ *        Copyright (c) 2023-2024 Dinu Blanovschi
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        https://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+//! Fingerprints of the source text covered by coverage regions.
+//!
+//! A [`RegionFingerprint`] is a hash of the *normalized* source text of a
+//! region, where normalizing means collapsing every run of whitespace into
+//! a single space, and trimming the whitespace at both ends.
+//!
+//! A region's text alone is often not unique in its file (think of `a + b`,
+//! or `{ x }`), so an edited region could still be found elsewhere in the
+//! new text. The fingerprinted text is therefore anchored in its surrounding
+//! context: it spans the whole lines of the region, together with the closest
+//! non-blank line before it and the closest non-blank line after it, if any.
+//! The flip side is that editing one of those lines makes the region count as
+//! changed too.
+//!
+//! The hash is a polynomial rolling hash, so that [`SourceFingerprints`] can
+//! efficiently look for a region's text anywhere in the current version of a
+//! file, regardless of the line it ends up on. This is what makes the
+//! fingerprints survive line shifts caused by edits elsewhere in the file,
+//! as well as re-indentation.
+
+use std::collections::{HashMap, HashSet};
+
+const BASE: u64 = 0x100_0000_01b3;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+struct RegionFingerprintSerDe((u64, usize));
+
+/// A fingerprint of the source text of a region.
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(from = "RegionFingerprintSerDe", into = "RegionFingerprintSerDe")]
+pub struct RegionFingerprint {
+    /// The hash of the normalized text.
+    pub hash: u64,
+    /// The length of the normalized text, in bytes.
+    pub len: usize,
+}
+
+impl From<RegionFingerprintSerDe> for RegionFingerprint {
+    fn from(RegionFingerprintSerDe((hash, len)): RegionFingerprintSerDe) -> Self {
+        Self { hash, len }
+    }
+}
+
+impl From<RegionFingerprint> for RegionFingerprintSerDe {
+    fn from(RegionFingerprint { hash, len }: RegionFingerprint) -> Self {
+        Self((hash, len))
+    }
+}
+
+impl RegionFingerprint {
+    /// Computes the fingerprint of the region between `(l1, c1)` (inclusive)
+    /// and `(l2, c2)` (exclusive) in the given source text, together with
+    /// its surrounding lines (see the [module-level documentation](self)).
+    ///
+    /// Lines and columns are 1-based, as they are in the coverage data.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use cargo_difftests::fingerprint::{RegionFingerprint, SourceFingerprints};
+    ///
+    /// let source = "let x = a + b;\nlet y = a + b;\n";
+    /// // the `a + b` on the first line
+    /// let fingerprint = RegionFingerprint::of_region(source, 1, 9, 1, 14);
+    ///
+    /// // shifted down and re-indented
+    /// let shifted = "\n\n    let x = a + b;\n    let y = a + b;\n";
+    /// assert!(SourceFingerprints::new(shifted).contains(fingerprint));
+    ///
+    /// // edited, even though there is still an `a + b` on the second line
+    /// let edited = "let x = a - b;\nlet y = a + b;\n";
+    /// assert!(!SourceFingerprints::new(edited).contains(fingerprint));
+    /// ```
+    pub fn of_region(source: &str, l1: usize, c1: usize, l2: usize, c2: usize) -> Self {
+        Self::of_text(region_text(source, l1, c1, l2, c2))
+    }
+
+    /// Computes the fingerprint of the given text.
+    pub fn of_text(text: &[u8]) -> Self {
+        let normalized = normalize(text);
+        let normalized = normalized.trim_ascii();
+
+        Self {
+            hash: hash(normalized),
+            len: normalized.len(),
+        }
+    }
+}
+
+fn normalize(text: &[u8]) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(text.len());
+    let mut in_whitespace = false;
+
+    for &b in text {
+        if b.is_ascii_whitespace() {
+            if !in_whitespace {
+                normalized.push(b' ');
+            }
+            in_whitespace = true;
+        } else {
+            normalized.push(b);
+            in_whitespace = false;
+        }
+    }
+
+    normalized
+}
+
+fn hash(text: &[u8]) -> u64 {
+    text.iter()
+        .fold(0u64, |h, &b| h.wrapping_mul(BASE).wrapping_add(b as u64))
+}
+
+fn region_text(source: &str, l1: usize, c1: usize, l2: usize, c2: usize) -> &[u8] {
+    let source = source.as_bytes();
+
+    let mut line_starts = vec![0];
+    line_starts.extend(
+        source
+            .iter()
+            .enumerate()
+            .filter(|&(_, &b)| b == b'\n')
+            .map(|(i, _)| i + 1),
+    );
+
+    let offset = |line: usize, col: usize| {
+        let Some(&line_start) = line_starts.get(line.saturating_sub(1)) else {
+            return source.len();
+        };
+        let line_end = line_starts
+            .get(line)
+            .map_or(source.len(), |next_line_start| next_line_start - 1);
+
+        (line_start + col.saturating_sub(1)).min(line_end)
+    };
+
+    let start = offset(l1, c1);
+    let end = offset(l2, c2);
+
+    if start >= end {
+        return &[];
+    }
+
+    let line_start = |i: usize| {
+        source[..i]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |nl| nl + 1)
+    };
+    let line_end = |i: usize| {
+        source[i..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(source.len(), |nl| i + nl)
+    };
+
+    // the closest non-blank lines before and after the lines of the region
+    let start = line_start(start);
+    let start = source[..start]
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(start, line_start);
+    let end = line_end(end);
+    let end = source[end..]
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .map_or(end, |i| line_end(end + i));
+
+    &source[start..end]
+}
+
+/// The fingerprints of all the possible regions of a source file.
+///
+/// Used to check whether the text of a region (identified by its
+/// [`RegionFingerprint`]) still exists anywhere in the file.
+pub struct SourceFingerprints {
+    normalized: Vec<u8>,
+    hashes_by_len: HashMap<usize, HashSet<u64>>,
+}
+
+impl SourceFingerprints {
+    /// Creates a new [`SourceFingerprints`] for the given source text.
+    pub fn new(source: &str) -> Self {
+        Self {
+            normalized: normalize(source.as_bytes()),
+            hashes_by_len: HashMap::new(),
+        }
+    }
+
+    /// Checks whether the text with the given fingerprint is present
+    /// anywhere in the source.
+    pub fn contains(&mut self, fingerprint: RegionFingerprint) -> bool {
+        if fingerprint.len == 0 {
+            return true;
+        }
+
+        let normalized = &self.normalized;
+
+        self.hashes_by_len
+            .entry(fingerprint.len)
+            .or_insert_with(|| window_hashes(normalized, fingerprint.len))
+            .contains(&fingerprint.hash)
+    }
+}
+
+fn window_hashes(text: &[u8], len: usize) -> HashSet<u64> {
+    let mut hashes = HashSet::new();
+
+    if len > text.len() {
+        return hashes;
+    }
+
+    // BASE^(len - 1), used to remove the first byte of the window.
+    let top = (1..len).fold(1u64, |p, _| p.wrapping_mul(BASE));
+
+    let mut h = hash(&text[..len]);
+    hashes.insert(h);
+
+    for i in len..text.len() {
+        h = h
+            .wrapping_sub((text[i - len] as u64).wrapping_mul(top))
+            .wrapping_mul(BASE)
+            .wrapping_add(text[i] as u64);
+        hashes.insert(h);
+    }
+
+    hashes
+}
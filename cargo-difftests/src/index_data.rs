@@ -17,14 +17,18 @@
 //! Holds the [`TestIndex`] struct, and logic for indexing [`CoverageData`] into
 //! a [`TestIndex`].
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use std::fs::File;
 use std::io::BufWriter;
 use std::path::{Path, PathBuf};
 
-use crate::analysis_data::CoverageData;
-use crate::difftest::TestInfo;
+use log::debug;
+
+use crate::analysis_data::{CoverageData, CoverageFunction};
+use crate::dependencies::{dependencies_of_files, Dependency};
+use crate::difftest::{EnvFingerprint, GitState, TestInfo, TestOutcome, TestStatus};
+use crate::fingerprint::RegionFingerprint;
 use crate::{Difftest, DifftestsResult};
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -77,6 +81,21 @@ impl From<IndexRegion> for IndexRegionSerDe {
     }
 }
 
+/// A function executed by the test, in a [`TestIndex`].
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct IndexFunction {
+    /// The demangled name of the function.
+    pub name: String,
+    /// The line number of the first line of the function.
+    pub l1: usize,
+    /// The line number of the last line of the function.
+    pub l2: usize,
+    /// The number of times the function was executed.
+    pub count: usize,
+    /// The index of the file in the [`TestIndex`].
+    pub file_id: usize,
+}
+
 /// A test index, which is a more compact representation of [`CoverageData`],
 /// and contains only the information needed for analysis.
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -84,36 +103,140 @@ pub struct TestIndex {
     /// The regions in all the files.
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub regions: Vec<IndexRegion>,
+    /// The fingerprints of the source text of all the regions, in the
+    /// same order as [`TestIndex::regions`].
+    ///
+    /// A [`None`] means that the file of the region could not be read
+    /// when the index was compiled.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub region_fingerprints: Vec<Option<RegionFingerprint>>,
+    /// The functions executed by the test.
+    ///
+    /// Only present in indexes compiled with [`IndexSize::Functions`]
+    /// or [`IndexSize::Full`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub functions: Vec<IndexFunction>,
     /// The paths to all the files.
     pub files: Vec<PathBuf>,
+    /// The hashes of the contents of all the files, in the same order
+    /// as [`TestIndex::files`], as computed by [`hash_file`] when the
+    /// index was compiled.
+    ///
+    /// A [`None`] means that the file could not be read at that time.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub file_hashes: Vec<Option<String>>,
+    /// The ids of the files in [`TestIndex::files`] that the test declared
+    /// as its inputs (see [`Difftest::tracked_files`]).
+    ///
+    /// They are checked by all the algorithms, even though they contain no
+    /// regions or functions.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tracked_files: Vec<usize>,
+    /// The dependencies (from the registry or git) that the test executed
+    /// code from, at the versions they had when the test was run.
+    ///
+    /// They are recorded even if the files of the registry are not indexed
+    /// (see [`IndexDataCompilerConfig::accept_file`]).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dependencies: Vec<Dependency>,
     /// The time the test was run.
     pub test_run: chrono::DateTime<chrono::Utc>,
     /// The test description.
     pub test_info: TestInfo,
+    /// The outcome of the test run, if it was recorded.
+    ///
+    /// Tests that failed are always considered dirty by the analysis.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<TestOutcome>,
+    /// The state of the git repository when the test was run, if it
+    /// was recorded.
+    ///
+    /// The `git-diff-*` algorithms diff from its commit by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_state: Option<GitState>,
+    /// The fingerprint of the environment the test was run in, if it was
+    /// recorded.
+    ///
+    /// If it does not match the current environment, the test is dirty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env_fingerprint: Option<EnvFingerprint>,
+    /// Whether the test marked itself as one that must always run (see
+    /// [`Difftest::always_run`]).
+    ///
+    /// Such tests are always considered dirty by the analysis.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub always_run: bool,
 }
 
 impl TestIndex {
-    /// Indexes/compiles the [`CoverageData`] into a [`TestIndex`].
-    pub fn index(
-        difftest: &Difftest,
-        profdata: CoverageData,
-        mut index_data_compiler_config: IndexDataCompilerConfig,
-    ) -> DifftestsResult<Self> {
+    /// A [`TestIndex`] of the [`Difftest`] without any coverage data, for
+    /// a test that failed before writing any profiling data.
+    ///
+    /// It still records the outcome of the test, so the analysis considers
+    /// the test dirty.
+    pub fn without_coverage(difftest: &Difftest, remove_bin_path: bool) -> DifftestsResult<Self> {
         let mut index_data = Self {
             regions: vec![],
+            region_fingerprints: vec![],
+            functions: vec![],
             files: vec![],
+            file_hashes: vec![],
+            tracked_files: vec![],
+            dependencies: vec![],
             test_run: difftest.test_run_time().into(),
             test_info: difftest.test_info()?,
+            outcome: difftest.outcome().cloned(),
+            git_state: difftest.git_state().cloned(),
+            env_fingerprint: difftest.env_fingerprint().cloned(),
+            always_run: difftest.always_run(),
         };
 
-        if index_data_compiler_config.remove_bin_path {
+        if remove_bin_path {
             index_data.test_info.test_binary = PathBuf::new();
         }
 
+        Ok(index_data)
+    }
+
+    /// Indexes/compiles the [`CoverageData`] into a [`TestIndex`].
+    pub fn index(
+        difftest: &Difftest,
+        profdata: CoverageData,
+        mut index_data_compiler_config: IndexDataCompilerConfig,
+    ) -> DifftestsResult<Self> {
+        let mut index_data =
+            Self::without_coverage(difftest, index_data_compiler_config.remove_bin_path)?;
+
         let mut mapping_files = BTreeMap::<PathBuf, usize>::new();
+        let index_filename_converter = &mut index_data_compiler_config.index_filename_converter;
+        let mut intern_file = |index_data: &mut Self, filename: &PathBuf| {
+            *mapping_files.entry(filename.clone()).or_insert_with(|| {
+                let id = index_data.files.len();
+                index_data.files.push(index_filename_converter(filename));
+                index_data.file_hashes.push(hash_file(filename));
+                id
+            })
+        };
+        let mut sources = BTreeMap::<PathBuf, Option<String>>::new();
+
+        let mut functions = BTreeMap::<(usize, usize, usize, String), usize>::new();
+        let mut executed_files = BTreeSet::<&Path>::new();
 
         for mapping in &profdata.data {
             for f in &mapping.functions {
+                if index_data_compiler_config.index_size != IndexSize::Tiny
+                    && f.count > 0
+                    && let Some((l1, l2)) = function_line_span(f)
+                    && (index_data_compiler_config.accept_file)(&f.filenames[0])
+                {
+                    let filename = &f.filenames[0];
+                    let file_id = intern_file(&mut index_data, filename);
+
+                    *functions
+                        .entry((file_id, l1, l2, function_name(&f.name).to_owned()))
+                        .or_default() += f.count;
+                }
+
                 for region in &f.regions {
                     if region.execution_count == 0 {
                         continue;
@@ -121,21 +244,30 @@ impl TestIndex {
 
                     let filename = &f.filenames[region.file_id];
 
+                    executed_files.insert(filename);
+
                     if !(index_data_compiler_config.accept_file)(filename) {
                         continue;
                     }
 
-                    let file_id = *mapping_files.entry(filename.clone()).or_insert_with(|| {
-                        let id = index_data.files.len();
-                        index_data
-                            .files
-                            .push((index_data_compiler_config.index_filename_converter)(
-                                filename,
-                            ));
-                        id
-                    });
+                    let file_id = intern_file(&mut index_data, filename);
 
                     if index_data_compiler_config.index_size == IndexSize::Full {
+                        let source = sources.entry(filename.clone()).or_insert_with(|| {
+                            fs::read_to_string(filename)
+                                .map_err(|e| {
+                                    debug!("could not read file {}: {}", filename.display(), e);
+                                })
+                                .ok()
+                        });
+
+                        index_data
+                            .region_fingerprints
+                            .push(source.as_deref().map(|source| {
+                                RegionFingerprint::of_region(
+                                    source, region.l1, region.c1, region.l2, region.c2,
+                                )
+                            }));
                         index_data.regions.push(IndexRegion {
                             l1: region.l1,
                             c1: region.c1,
@@ -149,6 +281,25 @@ impl TestIndex {
             }
         }
 
+        index_data.dependencies = dependencies_of_files(executed_files);
+
+        // tracked files are always kept, they were explicitly declared
+        for filename in difftest.tracked_files() {
+            let file_id = intern_file(&mut index_data, filename);
+            index_data.tracked_files.push(file_id);
+        }
+
+        index_data.functions = functions
+            .into_iter()
+            .map(|((file_id, l1, l2, name), count)| IndexFunction {
+                name,
+                l1,
+                l2,
+                count,
+                file_id,
+            })
+            .collect();
+
         Ok(index_data)
     }
 
@@ -160,12 +311,57 @@ impl TestIndex {
         Ok(())
     }
 
+    /// Checks whether the test failed when it was run.
+    pub fn test_failed(&self) -> bool {
+        self.outcome
+            .as_ref()
+            .is_some_and(|outcome| outcome.status == TestStatus::Failed)
+    }
+
     /// Reads a [`TestIndex`] from a file.
     pub fn read_from_file(path: &Path) -> DifftestsResult<Self> {
         Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
     }
 }
 
+/// Hashes the contents of the file at the given path, returning
+/// the hex representation of the hash, or [`None`] if the file
+/// could not be read.
+///
+/// The hash is the git blob id of the contents of the file, so it
+/// does not depend on the machine or on the path to the file.
+pub fn hash_file(path: &Path) -> Option<String> {
+    match git2::Oid::hash_file(git2::ObjectType::Blob, path) {
+        Ok(oid) => Some(oid.to_string()),
+        Err(e) => {
+            debug!("could not hash file {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Computes the span of lines of the function, from the regions
+/// in the file the function is defined in.
+pub(crate) fn function_line_span(f: &CoverageFunction) -> Option<(usize, usize)> {
+    let mut regions = f.regions.iter().filter(|r| r.file_id == 0);
+    let first = regions.next()?;
+
+    Some(regions.fold((first.l1, first.l2), |(l1, l2), r| {
+        (l1.min(r.l1), l2.max(r.l2))
+    }))
+}
+
+/// Strips the hash suffix (like `::h0123456789abcdef`) that legacy symbol
+/// mangling adds to function names.
+pub(crate) fn function_name(name: &str) -> &str {
+    match name.rsplit_once("::h") {
+        Some((name, hash)) if hash.len() == 16 && hash.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            name
+        }
+        _ => name,
+    }
+}
+
 /// Configuration for the [`TestIndex::index`] function.
 pub struct IndexDataCompilerConfig {
     /// Whether to ignore files in the cargo registry.
@@ -205,6 +401,16 @@ pub enum IndexSize {
     /// [`GitDiffStrategy::Hunks`]: crate::dirty_algorithm::GitDiffStrategy
     #[default]
     Tiny,
-    /// The full size, which contains all the information, including regions.
+    /// Contains the file names, and the functions executed by the test,
+    /// with their line spans, but not the individual regions.
+    ///
+    /// This is enough for [`GitDiffStrategy::Functions`], but not for
+    /// [`GitDiffStrategy::Hunks`].
+    ///
+    /// [`GitDiffStrategy::Functions`]: crate::analysis::GitDiffStrategy::Functions
+    /// [`GitDiffStrategy::Hunks`]: crate::analysis::GitDiffStrategy::Hunks
+    Functions,
+    /// The full size, which contains all the information, including regions
+    /// and their [`RegionFingerprint`]s, as well as the functions.
     Full,
 }
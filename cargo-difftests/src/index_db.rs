@@ -0,0 +1,688 @@
+/*
+ *        Copyright (c) 2023-2024 Dinu Blanovschi
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        https://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+//! An [`IndexDatabase`], which aggregates all the [`TestIndex`]es in an index
+//! root into a single file.
+//!
+//! The files of all the tests are interned into a single table, so that it is
+//! cheap to find all the tests that touched a file (the "postings" of the file),
+//! and the regions and functions of all the tests are grouped by file and sorted
+//! by their first line, so that the ones intersecting a diff hunk can be found
+//! without looking at the others.
+//!
+//! This allows computing the dirty set of all the tests with a single git diff
+//! pass, with [`IndexDatabase::git_diff_analysis`], instead of one per test.
+//!
+//! The database is kept up to date incrementally with [`IndexDatabase::update`]:
+//! only the indexes that were added or modified since the last update are read.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+
+use crate::analysis::{
+    always_dirty_git_diff_analysis, file_is_from_cargo_registry, hunk_intersects_lines,
+    normalize_path, settled_reasons, AlwaysRun, AnalysisReport, DirtyHunk, DirtyReason,
+    DirtyRegion, GitDiffChanges, GitDiffChangesCache, GitDiffStrategy, GitDiffTarget,
+    InvalidConfigError,
+};
+use crate::difftest::CurrentEnvFingerprints;
+use crate::index_data::TestIndex;
+use crate::path_filter::PathFilter;
+use crate::{DifftestsError, DifftestsResult};
+
+/// The name of the file the [`IndexDatabase`] is stored in, in the index root.
+///
+/// This file is not a [`TestIndex`], so it should be skipped when looking
+/// for the indexes in the index root.
+pub const INDEX_DB_FILE_NAME: &str = "cargo_difftests_index_db.json";
+
+/// A test in an [`IndexDatabase`].
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct IndexDatabaseTest {
+    /// The path to the index file, relative to the index root.
+    pub index_path: PathBuf,
+    /// The time the index file was last modified, when it was read.
+    pub index_modified: chrono::DateTime<chrono::Utc>,
+    /// The ids of the files of the test in [`IndexDatabase::files`],
+    /// indexed by the file ids in the [`TestIndex`].
+    pub file_ids: Vec<usize>,
+    /// The index of the test.
+    ///
+    /// Its [`TestIndex::files`] are not stored, see
+    /// [`IndexDatabaseTest::file_ids`] instead.
+    pub index: TestIndex,
+}
+
+/// An aggregated database of all the [`TestIndex`]es in an index root.
+///
+/// See the [module-level documentation](crate::index_db) for more information.
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+pub struct IndexDatabase {
+    /// The interned paths of the files touched by the tests.
+    pub files: Vec<PathBuf>,
+    /// The tests, sorted by the paths of their index files.
+    pub tests: Vec<IndexDatabaseTest>,
+}
+
+/// The configuration of [`IndexDatabase::git_diff_analysis`], the
+/// counterpart of the [`AnalysisConfig`] of the analysis of a single test.
+///
+/// [`AnalysisConfig`]: crate::analysis::AnalysisConfig
+pub struct IndexDbAnalysisConfig<'a> {
+    /// The strategy of the git diff analysis.
+    pub strategy: GitDiffStrategy,
+    /// The commit to diff from, or [`None`] for the commit recorded when
+    /// each test was run.
+    pub commit: Option<git2::Oid>,
+    /// What to diff the commit with.
+    pub target: GitDiffTarget,
+    /// The fingerprints of the current environment, to compare with the ones
+    /// recorded when the tests were run.
+    ///
+    /// If [`None`], the environment is not checked.
+    pub environment: Option<&'a mut CurrentEnvFingerprints>,
+    /// The files whose changes count, and the ones whose changes make all
+    /// the tests dirty (see [`PathFilter`]).
+    pub path_filter: &'a PathFilter,
+    /// The tests that are always dirty, besides the ones that marked
+    /// themselves as such (see [`AlwaysRun`]).
+    pub always_run: &'a AlwaysRun,
+}
+
+impl IndexDatabase {
+    /// Reads the [`IndexDatabase`] stored in the index root, if any, and
+    /// brings it up to date with the indexes in the index root.
+    ///
+    /// If the stored database cannot be read (for example, because it was
+    /// written by a different version of `cargo-difftests`), it is rebuilt
+    /// from scratch.
+    pub fn open(index_root: &Path) -> DifftestsResult<Self> {
+        let path = index_root.join(INDEX_DB_FILE_NAME);
+
+        let mut db = if path.exists() {
+            Self::read_from_file(&path).unwrap_or_else(|e| {
+                warn!("could not read index database, rebuilding it: {e}");
+                Self::default()
+            })
+        } else {
+            Self::default()
+        };
+
+        db.update(index_root)?;
+
+        Ok(db)
+    }
+
+    /// Updates the database with the indexes in the index root.
+    ///
+    /// Indexes that were not modified since they were last read are reused,
+    /// the others are (re-)read, and the tests whose indexes no longer exist
+    /// are removed.
+    pub fn update(&mut self, index_root: &Path) -> DifftestsResult {
+        let mut index_files = vec![];
+        discover_index_files(index_root, index_root, &mut index_files)?;
+        index_files.sort();
+
+        let old_files = std::mem::take(&mut self.files);
+        let mut old_tests = std::mem::take(&mut self.tests)
+            .into_iter()
+            .map(|test| (test.index_path.clone(), test))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut files = BTreeMap::<PathBuf, usize>::new();
+
+        for (index_path, index_modified) in index_files {
+            let (index, test_files) = match old_tests.remove(&index_path) {
+                Some(test) if test.index_modified == index_modified => {
+                    let test_files = test
+                        .file_ids
+                        .iter()
+                        .map(|&id| old_files[id].clone())
+                        .collect();
+                    (test.index, test_files)
+                }
+                _ => {
+                    debug!("reading index {}", index_path.display());
+                    let mut index = TestIndex::read_from_file(&index_root.join(&index_path))
+                        .map_err(|e| match e {
+                            DifftestsError::Json(e, None) => {
+                                DifftestsError::Json(e, Some(index_root.join(&index_path)))
+                            }
+                            e => e,
+                        })?;
+                    let test_files = std::mem::take(&mut index.files);
+                    (index, test_files)
+                }
+            };
+
+            let file_ids = test_files
+                .into_iter()
+                .map(|file| {
+                    let id = files.len();
+                    *files.entry(file).or_insert(id)
+                })
+                .collect();
+
+            self.tests.push(IndexDatabaseTest {
+                index_path,
+                index_modified,
+                file_ids,
+                index,
+            });
+        }
+
+        for index_path in old_tests.keys() {
+            debug!("index {} was removed", index_path.display());
+        }
+
+        self.files = vec![PathBuf::new(); files.len()];
+        for (file, id) in files {
+            self.files[id] = file;
+        }
+
+        Ok(())
+    }
+
+    /// Reads an [`IndexDatabase`] from a file.
+    pub fn read_from_file(path: &Path) -> DifftestsResult<Self> {
+        serde_json::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| DifftestsError::Json(e, Some(path.to_path_buf())))
+    }
+
+    /// Writes the [`IndexDatabase`] to a file.
+    pub fn write_to_file(&self, path: &Path) -> DifftestsResult {
+        let mut file = File::create(path)?;
+        let mut writer = BufWriter::new(&mut file);
+        serde_json::to_writer(&mut writer, self)?;
+        Ok(())
+    }
+
+    /// Converts the database back into the [`TestIndex`]es of the tests,
+    /// in the same order as [`IndexDatabase::tests`].
+    pub fn into_test_indexes(self) -> Vec<TestIndex> {
+        let files = self.files;
+
+        self.tests
+            .into_iter()
+            .map(|test| {
+                let mut index = test.index;
+                index.files = test.file_ids.iter().map(|&id| files[id].clone()).collect();
+                index
+            })
+            .collect()
+    }
+
+    /// Performs a git diff analysis of all the tests in the database at once.
+    ///
+    /// Like [`DirtyAlgorithm::GitDiff`], the tests are compared with the
+    /// commit of the [`IndexDbAnalysisConfig`], or if [`None`], with the
+    /// commit recorded when they were run (or HEAD). The diff is only
+    /// computed once per commit, and cached in the given
+    /// [`GitDiffChangesCache`].
+    ///
+    /// The reports are the same as the ones that the analysis of each test
+    /// on its own would produce, and are returned in the same order as
+    /// [`IndexDatabase::tests`].
+    ///
+    /// [`DirtyAlgorithm::GitDiff`]: crate::analysis::DirtyAlgorithm::GitDiff
+    pub fn git_diff_analysis(
+        &self,
+        config: IndexDbAnalysisConfig,
+        git_diff_cache: &mut GitDiffChangesCache,
+    ) -> DifftestsResult<Vec<AnalysisReport>> {
+        let IndexDbAnalysisConfig {
+            strategy,
+            commit,
+            target,
+            mut environment,
+            path_filter,
+            always_run,
+        } = config;
+
+        // the tests that are dirty regardless of the diff
+        let mut settled = vec![false; self.tests.len()];
+
+        let mut reports = self
+            .tests
+            .iter()
+            .zip(&mut settled)
+            .map(|(test, settled)| {
+                let forced = always_run
+                    .forced_reason(&test.index.test_info.test_name, test.index.always_run);
+
+                let environment_changes =
+                    || match (environment.as_deref_mut(), &test.index.env_fingerprint) {
+                        (Some(environment), Some(recorded)) => environment
+                            .matching(recorded)
+                            .map(|current| recorded.changes(current, &test.index.dependencies))
+                            .unwrap_or_default(),
+                        _ => vec![],
+                    };
+
+                if let Some(reasons) =
+                    settled_reasons(forced, test.index.test_failed(), environment_changes)
+                {
+                    *settled = true;
+                    return Ok(AnalysisReport { reasons });
+                }
+
+                let invalid = match strategy {
+                    GitDiffStrategy::FilesOnly | GitDiffStrategy::Semantic => None,
+                    GitDiffStrategy::Hunks if test.index.regions.is_empty() => {
+                        Some(InvalidConfigError::GitDiffHunksOnTestIndexWithNoRegions)
+                    }
+                    GitDiffStrategy::Functions if test.index.functions.is_empty() => {
+                        Some(InvalidConfigError::GitDiffFunctionsOnTestIndexWithNoFunctions)
+                    }
+                    GitDiffStrategy::Hunks | GitDiffStrategy::Functions => None,
+                };
+
+                match invalid {
+                    Some(e) => {
+                        log::error!("{e}");
+                        for hint in e.hints() {
+                            log::error!("{hint}");
+                        }
+
+                        Err(DifftestsError::InvalidConfig(e))
+                    }
+                    None => Ok(AnalysisReport::default()),
+                }
+            })
+            .collect::<DifftestsResult<Vec<_>>>()?;
+
+        // the commit each test is compared with
+        let bases = self
+            .tests
+            .iter()
+            .map(|test| match (commit, &test.index.git_state) {
+                (Some(commit), _) => Some(commit),
+                (None, Some(git_state)) => {
+                    if git_state.dirty {
+                        warn!(
+                            "Test {} was run on a working tree with uncommitted changes, \
+                            which will also be considered as changes since {}",
+                            test.index.test_info.test_name, git_state.commit
+                        );
+                    }
+                    Some(git_state.commit)
+                }
+                (None, None) => None,
+            })
+            .collect::<Vec<_>>();
+
+        let groups = bases
+            .iter()
+            .copied()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|base| {
+                Ok((
+                    base,
+                    git_diff_cache.get_for_strategy(strategy, base, target)?,
+                ))
+            })
+            .collect::<DifftestsResult<Vec<_>>>()?;
+
+        let analyzed =
+            |test_id: usize, base: Option<git2::Oid>| !settled[test_id] && bases[test_id] == base;
+
+        let file_ids = self.file_ids();
+
+        match strategy {
+            GitDiffStrategy::FilesOnly | GitDiffStrategy::Semantic => {
+                let postings = self.postings();
+
+                for (base, changes) in &groups {
+                    self.files_only(changes, &file_ids, &postings, |test_id, file| {
+                        if analyzed(test_id, *base) {
+                            reports[test_id]
+                                .reasons
+                                .push(DirtyReason::FileChangedInDiff { file: file.clone() });
+                        }
+                    });
+                }
+            }
+            GitDiffStrategy::Hunks => {
+                let spans = self.line_spans(|test| {
+                    test.index
+                        .regions
+                        .iter()
+                        .map(|r| (r.file_id, r.l1, r.l2))
+                        .collect()
+                });
+
+                for (base, changes) in &groups {
+                    self.for_each_intersection(
+                        changes,
+                        &file_ids,
+                        &spans,
+                        |test_id, region_id, file, hunk| {
+                            if analyzed(test_id, *base) {
+                                reports[test_id]
+                                    .reasons
+                                    .push(DirtyReason::HunkIntersectsRegion {
+                                        file: file.clone(),
+                                        hunk: *hunk,
+                                        region: DirtyRegion::from(
+                                            &self.tests[test_id].index.regions[region_id],
+                                        ),
+                                    });
+                            }
+                        },
+                    );
+                }
+            }
+            GitDiffStrategy::Functions => {
+                let spans = self.line_spans(|test| {
+                    test.index
+                        .functions
+                        .iter()
+                        .map(|f| (f.file_id, f.l1, f.l2))
+                        .collect()
+                });
+
+                for (base, changes) in &groups {
+                    self.for_each_intersection(
+                        changes,
+                        &file_ids,
+                        &spans,
+                        |test_id, function_id, file, hunk| {
+                            if analyzed(test_id, *base) {
+                                reports[test_id].reasons.push(DirtyReason::FunctionChanged {
+                                    file: file.clone(),
+                                    function: self.tests[test_id].index.functions[function_id]
+                                        .name
+                                        .clone(),
+                                    hunk: *hunk,
+                                });
+                            }
+                        },
+                    );
+                }
+            }
+        }
+
+        if matches!(
+            strategy,
+            GitDiffStrategy::Hunks | GitDiffStrategy::Functions
+        ) {
+            let tracked_postings = self.tracked_postings();
+
+            for (base, changes) in &groups {
+                self.files_only(changes, &file_ids, &tracked_postings, |test_id, file| {
+                    if analyzed(test_id, *base) {
+                        reports[test_id]
+                            .reasons
+                            .push(DirtyReason::TrackedFileChanged { file: file.clone() });
+                    }
+                });
+            }
+        }
+
+        for report in &mut reports {
+            report.reasons.retain(|reason| match reason.file() {
+                Some(file) => path_filter.accepts(file),
+                None => true,
+            });
+        }
+
+        for (base, changes) in &groups {
+            let always_dirty = always_dirty_git_diff_analysis(path_filter, changes);
+
+            if always_dirty.is_empty() {
+                continue;
+            }
+
+            for (test_id, report) in reports.iter_mut().enumerate() {
+                if analyzed(test_id, *base) {
+                    report.reasons.extend(always_dirty.iter().cloned());
+                }
+            }
+        }
+
+        Ok(reports)
+    }
+
+    /// Calls `f` with the test id and the file, for each test that touched
+    /// a file changed in the diff, once per changed file, with the first
+    /// of its files that matches.
+    fn files_only(
+        &self,
+        changes: &GitDiffChanges,
+        file_ids: &HashMap<PathBuf, usize>,
+        postings: &[Vec<usize>],
+        mut f: impl FnMut(usize, &PathBuf),
+    ) {
+        for file_changes in &changes.files {
+            let Some(path) = file_changes.indexed_path() else {
+                continue;
+            };
+
+            // for each test, the first of its files that matches
+            let mut matches = BTreeMap::<usize, &PathBuf>::new();
+
+            for file_id in Self::changed_file_ids(changes, file_ids, path) {
+                let file = &self.files[file_id];
+                if file_is_from_cargo_registry(file) {
+                    continue;
+                }
+
+                for &test_id in &postings[file_id] {
+                    matches
+                        .entry(test_id)
+                        .and_modify(|it| *it = (*it).min(file))
+                        .or_insert(file);
+                }
+            }
+
+            for (test_id, file) in matches {
+                f(test_id, file);
+            }
+        }
+    }
+
+    /// The ids of the files, by their (normalized) paths.
+    fn file_ids(&self) -> HashMap<PathBuf, usize> {
+        self.files
+            .iter()
+            .enumerate()
+            .map(|(file_id, file)| (normalize_path(file), file_id))
+            .collect()
+    }
+
+    /// The ids of the files that are the file at `path` in the diff (see
+    /// [`GitDiffChanges::is_same_file`]).
+    fn changed_file_ids<'a>(
+        changes: &'a GitDiffChanges,
+        file_ids: &'a HashMap<PathBuf, usize>,
+        path: &Path,
+    ) -> impl Iterator<Item = usize> + 'a {
+        changes
+            .indexed_paths(path)
+            .filter_map(|path| file_ids.get(&path).copied())
+    }
+
+    /// The ids of the tests that touched each file, indexed by file id.
+    fn postings(&self) -> Vec<Vec<usize>> {
+        let mut postings = vec![vec![]; self.files.len()];
+
+        for (test_id, test) in self.tests.iter().enumerate() {
+            for &file_id in &test.file_ids {
+                postings[file_id].push(test_id);
+            }
+        }
+
+        postings
+    }
+
+    /// The ids of the tests that declared each file as one of their inputs,
+    /// indexed by file id.
+    fn tracked_postings(&self) -> Vec<Vec<usize>> {
+        let mut postings = vec![vec![]; self.files.len()];
+
+        for (test_id, test) in self.tests.iter().enumerate() {
+            for &file_id in &test.index.tracked_files {
+                postings[test.file_ids[file_id]].push(test_id);
+            }
+        }
+
+        postings
+    }
+
+    /// Groups the spans of lines (regions or functions) of all the tests
+    /// by file, given the `(file_id, l1, l2)` of the spans of each test.
+    fn line_spans(
+        &self,
+        spans_of: impl Fn(&IndexDatabaseTest) -> Vec<(usize, usize, usize)>,
+    ) -> Vec<FileLineSpans> {
+        let mut spans = (0..self.files.len())
+            .map(|_| FileLineSpans::default())
+            .collect::<Vec<_>>();
+
+        for (test_id, test) in self.tests.iter().enumerate() {
+            for (span_id, (file_id, l1, l2)) in spans_of(test).into_iter().enumerate() {
+                spans[test.file_ids[file_id]].push(LineSpan {
+                    l1,
+                    l2,
+                    test_id,
+                    span_id,
+                });
+            }
+        }
+
+        for file_spans in &mut spans {
+            file_spans.spans.sort_by_key(|span| span.l1);
+        }
+
+        spans
+    }
+
+    /// Calls `f` with `(test_id, span_id, file, hunk)` for each span that
+    /// intersects a hunk in the changes.
+    ///
+    /// For each changed file, the spans are visited in the order of the tests,
+    /// then in the order they have in the test, and then in the order of the
+    /// hunks, which is the same order the analysis of each test on its own
+    /// would visit them in.
+    fn for_each_intersection(
+        &self,
+        changes: &GitDiffChanges,
+        file_ids: &HashMap<PathBuf, usize>,
+        spans: &[FileLineSpans],
+        mut f: impl FnMut(usize, usize, &PathBuf, &DirtyHunk),
+    ) {
+        for file_changes in &changes.files {
+            let Some(path) = file_changes.indexed_path() else {
+                continue;
+            };
+
+            let changed_file_ids =
+                Self::changed_file_ids(changes, file_ids, path).collect::<Vec<_>>();
+
+            let mut intersections = file_changes
+                .hunks
+                .iter()
+                .enumerate()
+                .flat_map(|(hunk_id, hunk)| {
+                    changed_file_ids.iter().flat_map(move |&file_id| {
+                        spans[file_id]
+                            .intersecting(hunk)
+                            .map(move |span| (span.test_id, span.span_id, hunk_id, file_id))
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            intersections.sort();
+
+            for (test_id, span_id, hunk_id, file_id) in intersections {
+                f(
+                    test_id,
+                    span_id,
+                    &self.files[file_id],
+                    &file_changes.hunks[hunk_id],
+                );
+            }
+        }
+    }
+}
+
+fn discover_index_files(
+    index_root: &Path,
+    dir: &Path,
+    index_files: &mut Vec<(PathBuf, chrono::DateTime<chrono::Utc>)>,
+) -> DifftestsResult {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        if path.is_dir() {
+            discover_index_files(index_root, &path, index_files)?;
+        } else if path.file_name() != Some(INDEX_DB_FILE_NAME.as_ref()) {
+            let modified = entry.metadata()?.modified()?;
+            let relative_path = path.strip_prefix(index_root).unwrap_or(&path);
+            index_files.push((relative_path.to_path_buf(), modified.into()));
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
+struct LineSpan {
+    l1: usize,
+    l2: usize,
+    test_id: usize,
+    span_id: usize,
+}
+
+/// The spans of lines in a file, sorted by their first line.
+#[derive(Default)]
+struct FileLineSpans {
+    spans: Vec<LineSpan>,
+    /// The maximum of `l2 - l1` over all the spans.
+    max_len: usize,
+}
+
+impl FileLineSpans {
+    fn push(&mut self, span: LineSpan) {
+        self.max_len = self.max_len.max(span.l2.saturating_sub(span.l1));
+        self.spans.push(span);
+    }
+
+    fn intersecting<'a>(&'a self, hunk: &'a DirtyHunk) -> impl Iterator<Item = &'a LineSpan> {
+        let hunk_start = hunk.old_start as usize;
+        // a pure insertion after the line `hunk_start` also intersects the
+        // spans starting on the next line (see `hunk_intersects_lines`)
+        let hunk_end = hunk_start + (hunk.old_lines as usize).max(1);
+
+        // a span can only intersect the hunk if it starts before its end,
+        // and ends (so starts at most `max_len` lines before it ends) after
+        // its start
+        let lo = hunk_start.saturating_sub(self.max_len + 1);
+        let start = self.spans.partition_point(|span| span.l1 < lo);
+        let end = self.spans.partition_point(|span| span.l1 <= hunk_end);
+
+        self.spans[start..end.max(start)]
+            .iter()
+            .filter(move |span| hunk_intersects_lines(hunk, span.l1, span.l2))
+    }
+}
@@ -19,17 +19,25 @@
 use std::collections::BTreeSet;
 use std::path::PathBuf;
 
-use difftest::TestInfo;
+use difftest::{TestInfo, TestOutcome};
 
-use crate::analysis::AnalysisResult;
+use crate::analysis::{AnalysisReport, AnalysisResult};
 use crate::difftest::Difftest;
 use crate::index_data::TestIndex;
 
 pub mod analysis;
 pub mod analysis_data;
+pub mod dependencies;
 pub mod difftest;
+pub mod file_tracing;
+pub mod fingerprint;
 pub mod index_data;
+pub mod index_db;
+pub mod nextest;
+pub mod path_filter;
+pub mod semantic_diff;
 pub mod test_rerunner_core;
+pub mod who_covers;
 pub mod bin_context;
 
 /// Errors that can occur when running `cargo difftests`.
@@ -65,6 +73,9 @@ pub enum DifftestsError {
     /// A [git2::Error] occurred.
     #[error("git error: {0}")]
     Git(#[from] git2::Error),
+    /// A TOML error (while parsing a `Cargo.lock` file).
+    #[error("TOML error: {0}")]
+    Toml(#[from] toml::de::Error),
     /// The difftest has been cleaned.
     #[error("difftest has been cleaned")]
     DifftestCleaned,
@@ -77,6 +88,15 @@ pub enum DifftestsError {
 
     #[error("invalid config: {0}")]
     InvalidConfig(analysis::InvalidConfigError),
+
+    /// A glob pattern (of a [`path_filter::PathFilter`] or of an
+    /// [`analysis::AlwaysRun`]) is invalid.
+    #[error("invalid glob pattern `{pattern}`: {error}")]
+    InvalidGlob {
+        pattern: String,
+        #[source]
+        error: glob::PatternError,
+    },
 }
 
 impl From<serde_json::Error> for DifftestsError {
@@ -161,6 +181,18 @@ pub struct AnalyzeAllSingleTest {
     pub test_info: TestInfo,
     /// The result of the analysis.
     pub verdict: AnalysisVerdict,
+    /// Whether the test is dirty only because it must always run (see
+    /// [`analysis::AlwaysRun`]), rather than because of some change.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub forced: bool,
+    /// The outcome of the last run of the test (pass/fail status,
+    /// duration and exit code), if it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<TestOutcome>,
+    /// The report of the analysis, with the reasons for which the test
+    /// is dirty, if they were requested (with `--explain`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub report: Option<AnalysisReport>,
 }
 
 /// An analysis verdict.
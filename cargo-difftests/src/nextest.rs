@@ -0,0 +1,197 @@
+/*
+ *        Copyright (c) 2023-2024 Dinu Blanovschi
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        https://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+//! Running the tests with [cargo-nextest](https://nexte.st).
+//!
+//! `cargo nextest run` already runs every test in its own process, but it
+//! cannot give each of them its own `LLVM_PROFILE_FILE`. So the
+//! [`NEXTEST_WRAPPER_BIN`] is set as the target runner, and nextest runs
+//! every test binary through it, as `<wrapper> <test binary> <args>`.
+//!
+//! The wrapper looks the test up in the [`NextestTests`] file given in the
+//! [`NEXTEST_TESTS_ENV`] environment variable, and if it is one of the tests
+//! to collect the profiling data of, runs it with the environment of its
+//! difftest directory, and records its [`TestOutcome`] there. Any other
+//! invocation (like nextest listing the tests) is passed through as-is.
+//!
+//! The difftest directories are prepared somewhere else, and only replace
+//! the old ones once nextest runs the tests, so the tests that nextest does
+//! not run (like the ones after a failure, without `--no-fail-fast`) keep
+//! their old profiling data.
+//!
+//! If the project already has a target runner, it is given to the wrapper
+//! in the [`NEXTEST_RUNNER_ENV`] environment variable, and the wrapper runs
+//! the test binaries through it.
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::time::Instant;
+
+use crate::difftest::{TestOutcome, TestStatus};
+use crate::{DifftestsError, DifftestsResult};
+
+/// The name of the binary that nextest runs the test binaries through.
+pub const NEXTEST_WRAPPER_BIN: &str = "cargo-difftests-nextest-wrapper";
+
+/// The environment variable with the path to the [`NextestTests`] file.
+pub const NEXTEST_TESTS_ENV: &str = "CARGO_DIFFTESTS_NEXTEST_TESTS";
+
+/// The environment variable with the target runner (as a JSON array of
+/// the program and its arguments) that the wrapper runs the test binaries
+/// through, if any.
+pub const NEXTEST_RUNNER_ENV: &str = "CARGO_DIFFTESTS_NEXTEST_RUNNER";
+
+/// The `CARGO_TARGET_<triple>_RUNNER` environment variable, which sets the
+/// target runner for the given target triple.
+pub fn target_runner_var(target: &str) -> String {
+    format!(
+        "CARGO_TARGET_{}_RUNNER",
+        target.to_uppercase().replace(['-', '.'], "_")
+    )
+}
+
+/// The tests to collect the profiling data of, in a `cargo nextest run`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+pub struct NextestTests {
+    /// The tests, by the path of their test binary, then by their name.
+    pub dirs: BTreeMap<PathBuf, BTreeMap<String, NextestTest>>,
+}
+
+/// A test to collect the profiling data of, in a `cargo nextest run`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NextestTest {
+    /// The difftest directory of the test.
+    pub difftest_dir: PathBuf,
+    /// The prepared difftest directory, which replaces the
+    /// [`difftest_dir`](Self::difftest_dir) when the test is first run.
+    pub prepared_dir: PathBuf,
+}
+
+impl NextestTest {
+    /// Replaces the old difftest directory with the prepared one, the first
+    /// time the test is run, or removes the profiling data of the earlier
+    /// attempt, if nextest retries the test.
+    fn reset_difftest_dir(&self) -> DifftestsResult {
+        if self.prepared_dir.exists() {
+            if self.difftest_dir.exists() {
+                fs::remove_dir_all(&self.difftest_dir)?;
+            }
+
+            if let Some(parent) = self.difftest_dir.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            fs::rename(&self.prepared_dir, &self.difftest_dir)?;
+
+            return Ok(());
+        }
+
+        for e in self.difftest_dir.read_dir()? {
+            let p = e?.path();
+            if p.extension() == Some(OsStr::new("profraw")) {
+                fs::remove_file(p)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl NextestTests {
+    /// Writes the [`NextestTests`] to a file.
+    pub fn write_to_file(&self, path: &Path) -> DifftestsResult {
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Reads the [`NextestTests`] from a file.
+    pub fn read_from_file(path: &Path) -> DifftestsResult<Self> {
+        let s = fs::read_to_string(path)?;
+        serde_json::from_str(&s).map_err(|e| DifftestsError::Json(e, Some(path.to_path_buf())))
+    }
+
+    /// The test that the test binary runs with the given arguments, if it
+    /// is one of the tests.
+    ///
+    /// Nextest passes the name of the test (along with `--exact`) as one of
+    /// the arguments.
+    pub fn test(&self, binary: &Path, args: &[OsString]) -> Option<&NextestTest> {
+        let tests = self.dirs.get(binary)?;
+
+        args.iter()
+            .filter_map(|arg| arg.to_str())
+            .find_map(|arg| tests.get(arg))
+    }
+}
+
+/// Runs the test binary with the given arguments, as the target runner of
+/// nextest (see the [module-level documentation](crate::nextest)).
+pub fn run_as_target_runner(binary: &Path, args: &[OsString]) -> DifftestsResult<ExitStatus> {
+    let runner = match std::env::var(NEXTEST_RUNNER_ENV) {
+        Ok(runner) => serde_json::from_str::<Vec<String>>(&runner)?,
+        Err(_) => vec![],
+    };
+
+    let mut runner = runner.into_iter();
+    let mut cmd = match runner.next() {
+        Some(program) => {
+            let mut cmd = Command::new(program);
+            cmd.args(runner).arg(binary);
+            cmd
+        }
+        None => Command::new(binary),
+    };
+    cmd.args(args);
+
+    let tests = match std::env::var_os(NEXTEST_TESTS_ENV) {
+        Some(path) => Some(NextestTests::read_from_file(Path::new(&path))?),
+        None => None,
+    };
+
+    let Some(test) = tests.as_ref().and_then(|tests| tests.test(binary, args)) else {
+        return Ok(cmd.status()?);
+    };
+
+    test.reset_difftest_dir()?;
+
+    let difftest_dir = &test.difftest_dir;
+
+    let start = Instant::now();
+
+    let status = cmd
+        .env("CARGO_DIFFTEST_DIR", difftest_dir)
+        .env("LLVM_PROFILE_FILE", difftest_dir.join("%p_%m.profraw"))
+        .status()?;
+
+    let outcome = TestOutcome {
+        status: if status.success() {
+            TestStatus::Passed
+        } else {
+            TestStatus::Failed
+        },
+        duration: start.elapsed(),
+        exit_code: status.code(),
+    };
+
+    outcome.write_to_file(
+        &difftest_dir.join(cargo_difftests_core::CARGO_DIFFTESTS_TEST_OUTCOME_FILENAME),
+    )?;
+
+    Ok(status)
+}
@@ -0,0 +1,175 @@
+/*
+ *        Copyright (c) 2023-2024 Dinu Blanovschi
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        https://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+//! Rules, as globs, for the files that are considered when compiling the
+//! indexes and when analyzing the tests.
+//!
+//! - The files that are not *included*, or that are *excluded*, are left
+//!   out of the indexes, and changes to them never make a test dirty. This
+//!   is useful for generated code, vendored code, or test utilities.
+//! - A change to one of the *always dirty* files (like `build.rs`) makes
+//!   all the tests dirty, whether they executed code from it or not.
+//!
+//! The patterns are matched against the paths relative to the root given
+//! to [`PathFilter::new`] (usually the root of the workspace). Paths that
+//! are already relative, like the ones in the git diff, are matched as they
+//! are, and so are the paths outside of the root.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+use glob::Pattern;
+
+use crate::{DifftestsError, DifftestsResult};
+
+/// The include, exclude and always dirty rules, see the
+/// [module-level documentation](crate::path_filter).
+///
+/// The [`Default`] filter accepts all the files, and has no always dirty
+/// files.
+///
+/// The clones of a filter share the [`PathFilter::always_dirty_files`],
+/// which are only looked for once.
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    root: PathBuf,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    always_dirty: Vec<Pattern>,
+    always_dirty_files: Arc<OnceLock<Vec<PathBuf>>>,
+}
+
+impl PathFilter {
+    /// Creates a new filter, with the patterns relative to the given root.
+    ///
+    /// If `include` is empty, all the files that are not excluded are
+    /// included.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use std::path::Path;
+    /// # use cargo_difftests::path_filter::PathFilter;
+    ///
+    /// let filter = PathFilter::new(
+    ///     "/workspace",
+    ///     &["src/**"],
+    ///     &["src/generated/**"],
+    ///     &["**/build.rs"],
+    /// )?;
+    ///
+    /// assert!(filter.accepts(Path::new("/workspace/src/lib.rs")));
+    /// assert!(filter.accepts(Path::new("src/lib.rs")));
+    /// assert!(!filter.accepts(Path::new("/workspace/src/generated/parser.rs")));
+    /// assert!(!filter.accepts(Path::new("/workspace/tests/common/mod.rs")));
+    ///
+    /// assert!(filter.is_always_dirty(Path::new("/workspace/build.rs")));
+    /// assert!(filter.is_always_dirty(Path::new("crates/core/build.rs")));
+    /// assert!(!filter.is_always_dirty(Path::new("/workspace/src/lib.rs")));
+    ///
+    /// # Ok::<(), cargo_difftests::DifftestsError>(())
+    /// ```
+    pub fn new(
+        root: impl Into<PathBuf>,
+        include: &[impl AsRef<str>],
+        exclude: &[impl AsRef<str>],
+        always_dirty: &[impl AsRef<str>],
+    ) -> DifftestsResult<Self> {
+        Ok(Self {
+            root: root.into(),
+            include: compile_globs(include)?,
+            exclude: compile_globs(exclude)?,
+            always_dirty: compile_globs(always_dirty)?,
+            always_dirty_files: Arc::default(),
+        })
+    }
+
+    fn matches(&self, patterns: &[Pattern], path: &Path) -> bool {
+        let path = path.strip_prefix(&self.root).unwrap_or(path);
+
+        patterns.iter().any(|pattern| pattern.matches_path(path))
+    }
+
+    /// Whether the file is included, and not excluded.
+    pub fn accepts(&self, path: &Path) -> bool {
+        (self.include.is_empty() || self.matches(&self.include, path))
+            && !self.matches(&self.exclude, path)
+    }
+
+    /// Whether a change to the file makes all the tests dirty.
+    pub fn is_always_dirty(&self, path: &Path) -> bool {
+        self.matches(&self.always_dirty, path)
+    }
+
+    /// The path relative to the root, or the path itself if it is already
+    /// relative, or outside of the root.
+    pub fn relative_path<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.root).unwrap_or(path)
+    }
+
+    /// Finds the files under the root that match the always dirty patterns,
+    /// sorted.
+    ///
+    /// The target directories (recognized by their `CACHEDIR.TAG`) and the
+    /// `.git` directory are skipped, and so are the directories that cannot
+    /// be read. The files are only looked for the first time, and then
+    /// reused.
+    pub fn always_dirty_files(&self) -> &[PathBuf] {
+        if self.always_dirty.is_empty() {
+            return &[];
+        }
+
+        self.always_dirty_files.get_or_init(|| {
+            let mut files = vec![];
+            self.find_always_dirty_files(&self.root, &mut files);
+            files.sort();
+            files
+        })
+    }
+
+    fn find_always_dirty_files(&self, dir: &Path, files: &mut Vec<PathBuf>) {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+
+            if file_type.is_dir() {
+                if entry.file_name() != ".git" && !path.join("CACHEDIR.TAG").exists() {
+                    self.find_always_dirty_files(&path, files);
+                }
+            } else if file_type.is_file() && self.is_always_dirty(&path) {
+                files.push(path);
+            }
+        }
+    }
+}
+
+pub(crate) fn compile_globs(patterns: &[impl AsRef<str>]) -> DifftestsResult<Vec<Pattern>> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Pattern::new(pattern.as_ref()).map_err(|error| DifftestsError::InvalidGlob {
+                pattern: pattern.as_ref().to_owned(),
+                error,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,139 @@
+/*
+ *        Copyright (c) 2023-2024 Dinu Blanovschi
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        https://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+//! Telling apart the changes to Rust source files that only touch whitespace
+//! or comments from the ones that change the code.
+//!
+//! Both versions of the source are tokenized with [`proc_macro2`], which
+//! already drops whitespace and regular comments. Doc comments are turned
+//! into `#[doc = "..."]` attributes by the tokenizer, so those are dropped
+//! as well. If the remaining tokens are the same, the change is cosmetic.
+//!
+//! Anything that cannot be tokenized is never considered cosmetic.
+
+use std::str::FromStr;
+
+use proc_macro2::{Delimiter, Spacing, TokenStream, TokenTree};
+
+use crate::analysis::DirtyHunk;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    Open(Delimiter),
+    Close(Delimiter),
+    Ident(String),
+    Punct(char, Spacing),
+    Literal(String),
+}
+
+fn is_doc_attribute(tree: &TokenTree) -> bool {
+    let TokenTree::Group(group) = tree else {
+        return false;
+    };
+
+    group.delimiter() == Delimiter::Bracket
+        && matches!(
+            group.stream().into_iter().next(),
+            Some(TokenTree::Ident(ident)) if ident == "doc"
+        )
+}
+
+fn flatten(stream: TokenStream, tokens: &mut Vec<Token>) {
+    let trees = stream.into_iter().collect::<Vec<_>>();
+    let mut i = 0;
+
+    while i < trees.len() {
+        // skip `#[doc = ...]` and `#![doc = ...]`
+        if let TokenTree::Punct(punct) = &trees[i]
+            && punct.as_char() == '#'
+        {
+            let bang = matches!(trees.get(i + 1), Some(TokenTree::Punct(p)) if p.as_char() == '!');
+            let attr = i + 1 + bang as usize;
+
+            if trees.get(attr).is_some_and(is_doc_attribute) {
+                i = attr + 1;
+                continue;
+            }
+        }
+
+        match &trees[i] {
+            TokenTree::Group(group) => {
+                tokens.push(Token::Open(group.delimiter()));
+                flatten(group.stream(), tokens);
+                tokens.push(Token::Close(group.delimiter()));
+            }
+            TokenTree::Ident(ident) => tokens.push(Token::Ident(ident.to_string())),
+            TokenTree::Punct(punct) => tokens.push(Token::Punct(punct.as_char(), punct.spacing())),
+            TokenTree::Literal(literal) => tokens.push(Token::Literal(literal.to_string())),
+        }
+
+        i += 1;
+    }
+}
+
+fn tokenize(source: &str) -> Option<Vec<Token>> {
+    let stream = TokenStream::from_str(source).ok()?;
+    let mut tokens = vec![];
+    flatten(stream, &mut tokens);
+    Some(tokens)
+}
+
+/// Checks whether the two versions of a Rust source file only differ in
+/// whitespace or comments (including doc comments).
+pub fn only_whitespace_or_comments_changed(old: &str, new: &str) -> bool {
+    match (tokenize(old), tokenize(new)) {
+        (Some(old), Some(new)) => old == new,
+        _ => false,
+    }
+}
+
+/// Checks whether the given hunk, from the diff between the two versions of
+/// a Rust source file, only changes whitespace or comments.
+///
+/// The hunk is checked on its own, by applying only it to the old version,
+/// and comparing the result with the old version.
+pub fn hunk_is_cosmetic(old: &str, new: &str, hunk: &DirtyHunk) -> bool {
+    let old_lines = old.split_inclusive('\n').collect::<Vec<_>>();
+    let new_lines = new.split_inclusive('\n').collect::<Vec<_>>();
+
+    let (old_start, old_len) = (hunk.old_start as usize, hunk.old_lines as usize);
+    let (new_start, new_len) = (hunk.new_start as usize, hunk.new_lines as usize);
+
+    // the lines of a pure insertion go after `old_start`, and the ones that
+    // are removed start at `old_start` (both 1-based)
+    let removed_start = if old_len == 0 {
+        old_start
+    } else {
+        old_start - 1
+    };
+    let added_start = if new_len == 0 {
+        new_start
+    } else {
+        new_start - 1
+    };
+
+    let (Some(before), Some(added), Some(after)) = (
+        old_lines.get(..removed_start),
+        new_lines.get(added_start..added_start + new_len),
+        old_lines.get(removed_start + old_len..),
+    ) else {
+        return false;
+    };
+
+    let patched = [before, added, after].concat().concat();
+
+    only_whitespace_or_comments_changed(old, &patched)
+}
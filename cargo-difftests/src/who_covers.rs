@@ -0,0 +1,231 @@
+/*
+ *        Copyright (c) 2023-2024 Dinu Blanovschi
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        https://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+//! Reverse queries over [`TestIndex`]es: which tests cover a given file,
+//! range of lines, or function.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::analysis::normalize_path;
+use crate::difftest::TestInfo;
+use crate::index_data::TestIndex;
+
+/// What to look for in the indexes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CoverageQuery {
+    /// A file, optionally restricted to a range of lines.
+    File {
+        /// The path to the file.
+        path: PathBuf,
+        /// The range of lines (both ends inclusive), if any.
+        lines: Option<(usize, usize)>,
+    },
+    /// A function.
+    ///
+    /// Matches functions whose demangled name is either exactly this,
+    /// or ends with `::` followed by this.
+    Function {
+        /// The name of the function.
+        name: String,
+    },
+}
+
+impl CoverageQuery {
+    /// Joins the path of a [`CoverageQuery::File`] to `base` (if it is
+    /// relative) and normalizes it (see [`normalize_path`]), so that it can
+    /// be compared exactly with the paths of the files in the indexes.
+    pub fn resolve(self, base: &Path) -> Self {
+        match self {
+            Self::File { path, lines } => Self::File {
+                path: normalize_path(&base.join(path)),
+                lines,
+            },
+            query => query,
+        }
+    }
+}
+
+/// An error that occurs when parsing a [`CoverageQuery`].
+#[derive(Debug, thiserror::Error)]
+pub enum CoverageQueryParseError {
+    #[error("empty path")]
+    EmptyPath,
+    #[error("invalid line range: {0}-{1}")]
+    InvalidLineRange(usize, usize),
+}
+
+impl FromStr for CoverageQuery {
+    type Err = CoverageQueryParseError;
+
+    /// Parses a `<path>[:line[-line]]` query.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_lines = |lines: &str| -> Option<(usize, usize)> {
+            match lines.split_once('-') {
+                Some((start, end)) => Some((start.parse().ok()?, end.parse().ok()?)),
+                None => {
+                    let line = lines.parse().ok()?;
+                    Some((line, line))
+                }
+            }
+        };
+
+        let (path, lines) = match s.rsplit_once(':') {
+            Some((path, lines)) => match parse_lines(lines) {
+                Some(lines) => (path, Some(lines)),
+                // not a line range, so the colon is part of the path
+                None => (s, None),
+            },
+            None => (s, None),
+        };
+
+        if path.is_empty() {
+            return Err(CoverageQueryParseError::EmptyPath);
+        }
+
+        if let Some((start, end)) = lines
+            && (start == 0 || start > end)
+        {
+            return Err(CoverageQueryParseError::InvalidLineRange(start, end));
+        }
+
+        Ok(Self::File {
+            path: PathBuf::from(path),
+            lines,
+        })
+    }
+}
+
+/// A test that covers what was queried.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct CoveringTest {
+    /// The description of the test.
+    pub test_info: TestInfo,
+    /// The execution count of the most executed region or function that
+    /// matched the query.
+    ///
+    /// This is [`None`] if the index of the test does not have the needed
+    /// granularity to tell (for example, a line range was queried, but the
+    /// index only contains the files touched by the test). Such tests are
+    /// still reported, as they touched the file and might cover the query.
+    pub hits: Option<usize>,
+}
+
+/// Whether `index_file`, as it is in an index, is the file at the (resolved)
+/// `path`.
+///
+/// The paths in the indexes are absolute, or relative to `root` (with
+/// `--flatten-files-to=repo-root`). Only the exact same file matches, so
+/// `src/lib.rs` does not match the `src/lib.rs` of another crate in the
+/// workspace.
+fn same_file(index_file: &Path, path: &Path, root: &Path) -> bool {
+    normalize_path(&root.join(index_file)) == path
+}
+
+fn function_matches(function_name: &str, name: &str) -> bool {
+    function_name == name
+        || function_name
+            .strip_suffix(name)
+            .is_some_and(|prefix| prefix.ends_with("::"))
+}
+
+/// Checks whether the test described by the [`TestIndex`] covers
+/// what was queried.
+///
+/// The query has to be [resolved](CoverageQuery::resolve), and the relative
+/// paths in the index are relative to `root`.
+///
+/// Returns [`None`] if it doesn't.
+pub fn test_covers(index: &TestIndex, query: &CoverageQuery, root: &Path) -> Option<CoveringTest> {
+    let covering_test = |hits| {
+        Some(CoveringTest {
+            test_info: index.test_info.clone(),
+            hits,
+        })
+    };
+
+    match query {
+        CoverageQuery::File { path, lines } => {
+            let file_ids = index
+                .files
+                .iter()
+                .enumerate()
+                .filter(|(_, f)| same_file(f, path, root))
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>();
+
+            if file_ids.is_empty() {
+                return None;
+            }
+
+            let intersects =
+                |l1: usize, l2: usize| lines.is_none_or(|(start, end)| l1 <= end && start <= l2);
+
+            if !index.regions.is_empty() {
+                let hits = index
+                    .regions
+                    .iter()
+                    .filter(|r| file_ids.contains(&r.file_id) && intersects(r.l1, r.l2))
+                    .map(|r| r.count)
+                    .max()?;
+
+                covering_test(Some(hits))
+            } else if !index.functions.is_empty() {
+                let hits = index
+                    .functions
+                    .iter()
+                    .filter(|f| file_ids.contains(&f.file_id) && intersects(f.l1, f.l2))
+                    .map(|f| f.count)
+                    .max()?;
+
+                covering_test(Some(hits))
+            } else {
+                covering_test(None)
+            }
+        }
+        CoverageQuery::Function { name } => {
+            let hits = index
+                .functions
+                .iter()
+                .filter(|f| function_matches(&f.name, name))
+                .map(|f| f.count)
+                .max()?;
+
+            covering_test(Some(hits))
+        }
+    }
+}
+
+/// Finds all the tests that cover what was queried, sorted by their
+/// hit counts, most hits first (see [`test_covers`]).
+pub fn who_covers<'a>(
+    indexes: impl IntoIterator<Item = &'a TestIndex>,
+    query: &CoverageQuery,
+    root: &Path,
+) -> Vec<CoveringTest> {
+    let mut tests = indexes
+        .into_iter()
+        .filter_map(|index| test_covers(index, query, root))
+        .collect::<Vec<_>>();
+
+    tests.sort_by(|a, b| {
+        b.hits
+            .cmp(&a.hits)
+            .then_with(|| a.test_info.test_name.cmp(&b.test_info.test_name))
+    });
+
+    tests
+}
@@ -16,6 +16,7 @@
 
 use std::{
     borrow::Cow,
+    collections::BTreeMap,
     ffi::OsStr,
     path::{Path, PathBuf},
 };
@@ -90,6 +91,19 @@ impl CargoProject {
         Ok(std::fs::write(p, contents.to_content(self).as_ref())?)
     }
 
+    pub fn read(&self, file: impl AsRef<Path>) -> R<String> {
+        Ok(std::fs::read_to_string(self.path.join(file))?)
+    }
+
+    pub fn remove(&self, file: impl AsRef<Path>) -> R {
+        std::fs::remove_file(self.path.join(file))?;
+        Ok(())
+    }
+
+    pub fn exists(&self, path: impl AsRef<Path>) -> bool {
+        self.path.join(path).exists()
+    }
+
     fn _internal_run_cargo(&self, args: &[&str]) -> R {
         let output = std::process::Command::new(env!("CARGO"))
             .args(args)
@@ -122,8 +136,13 @@ impl CargoProject {
     }
 
     pub fn run_all_tests_difftests(&self) -> R {
+        self.run_all_tests_difftests_with_args(&[])
+    }
+
+    pub fn run_all_tests_difftests_with_args(&self, extra_args: &[&str]) -> R {
         let mut cmd = self._internal_cargo_difftests_cmd()?;
         cmd.args(&["collect-profiling-data", "--index-strategy=never"]);
+        cmd.args(extra_args);
 
         let output = cmd.output()?;
 
@@ -170,6 +189,8 @@ impl CargoProject {
 
     pub fn difftests_dir(&self, harness: &str, name: &str) -> PathBuf {
         let mut p = self.difftests_root();
+        // the package has the name of the test
+        p.push(self.test_name);
         p.push(harness);
         p.push(name);
         p
@@ -201,6 +222,24 @@ impl CargoProject {
         Ok(Repository::open(&self.path)?)
     }
 
+    /// Moves a file, and stages the move, like `git mv`.
+    pub fn git_mv(&self, repo: &Repository, from: impl AsRef<Path>, to: impl AsRef<Path>) -> R {
+        let (from, to) = (from.as_ref(), to.as_ref());
+
+        if let Some(parent) = self.path.join(to).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        std::fs::rename(self.path.join(from), self.path.join(to))?;
+
+        let mut index = repo.index()?;
+        index.remove_path(from)?;
+        index.add_path(to)?;
+        index.write()?;
+
+        Ok(())
+    }
+
     pub fn commit<T: IntoCString>(
         &self,
         repo: &Repository,
@@ -234,6 +273,10 @@ impl CargoProject {
         }
     }
 
+    pub fn index_root(&self) -> PathBuf {
+        self.path.join("index_root")
+    }
+
     pub fn analysis_index_strategy_always(&self) -> AnalysisIndexStrategyInfo {
         AnalysisIndexStrategyInfo::Always {
             index_root: self.path.join("index_root"),
@@ -318,14 +361,29 @@ impl CargoDifftestsInvocation {
         self
     }
 
-    pub fn run(mut self) -> R {
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(mut self, key: K, value: V) -> Self {
+        self.command.env(key, value);
+        self
+    }
+
+    pub fn env_remove<K: AsRef<OsStr>>(mut self, key: K) -> Self {
+        self.command.env_remove(key);
+        self
+    }
+
+    pub fn run(self) -> R {
+        self.run_for_stdout()?;
+        Ok(())
+    }
+
+    pub fn run_for_stdout(mut self) -> R<String> {
         let child = self.command.spawn()?;
         let output = child.wait_with_output()?;
         let stdout = String::from_utf8(output.stdout)?;
         let stderr = String::from_utf8(output.stderr)?;
         self.stdout_match.check(&stdout)?;
         self.stderr_match.check(&stderr)?;
-        Ok(())
+        Ok(stdout)
     }
 }
 
@@ -375,6 +433,22 @@ impl CargoDifftestsTestAnalysis {
 
 pub type R<T = ()> = anyhow::Result<T>;
 
+/// Parses the JSON output of `analyze-all` (or `analyze-all-from-index`)
+/// into a map from test names to verdicts.
+pub fn analyze_all_verdicts(stdout: &str) -> R<BTreeMap<String, String>> {
+    let results: Vec<serde_json::Value> = serde_json::from_str(stdout)?;
+
+    Ok(results
+        .iter()
+        .map(|r| {
+            (
+                r["test_info"]["test_name"].as_str().unwrap().to_owned(),
+                r["verdict"].as_str().unwrap().to_owned(),
+            )
+        })
+        .collect())
+}
+
 #[derive(Default)]
 pub struct CargoProjectConfig {
     pub init_git: bool,
@@ -529,12 +603,17 @@ pub fn init_sample_project(test_name: &'static str) -> R<CargoProject> {
 pub enum AnalysisAlgo {
     #[default]
     FsMtime,
+    ContentHash,
+    RegionFingerprints,
     GitDiffHunks {
         commit: Option<Oid>,
     },
     GitDiffFiles {
         commit: Option<Oid>,
     },
+    GitDiffFunctions {
+        commit: Option<Oid>,
+    },
 }
 
 impl AnalysisAlgo {
@@ -557,6 +636,10 @@ impl AnalysisAlgo {
             commit: Some(commit),
         }
     }
+
+    pub fn git_diff_functions_with_head() -> Self {
+        Self::GitDiffFunctions { commit: None }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -610,6 +693,12 @@ impl TestAnalysisStrategyInfo {
             AnalysisAlgo::FsMtime => {
                 cmd.arg("--algo=fs-mtime");
             }
+            AnalysisAlgo::ContentHash => {
+                cmd.arg("--algo=content-hash");
+            }
+            AnalysisAlgo::RegionFingerprints => {
+                cmd.arg("--algo=region-fingerprints");
+            }
             AnalysisAlgo::GitDiffHunks { commit } => {
                 cmd.arg("--algo=git-diff-hunks");
                 if let Some(commit) = commit {
@@ -622,16 +711,30 @@ impl TestAnalysisStrategyInfo {
                     cmd.arg("--commit").arg(commit.to_string());
                 }
             }
+            AnalysisAlgo::GitDiffFunctions { commit } => {
+                cmd.arg("--algo=git-diff-functions");
+                if let Some(commit) = commit {
+                    cmd.arg("--commit").arg(commit.to_string());
+                }
+            }
         }
 
         self.index.args_to_cmd(cmd);
 
-        if let AnalysisAlgo::GitDiffHunks { .. } = self.algo
+        if let AnalysisAlgo::GitDiffHunks { .. } | AnalysisAlgo::RegionFingerprints = self.algo
             && let AnalysisIndexStrategyInfo::Always { .. }
             | AnalysisIndexStrategyInfo::AlwaysAndClean { .. }
             | AnalysisIndexStrategyInfo::IfAvailable { .. } = self.index
         {
             cmd.arg("--full-index");
         }
+
+        if let AnalysisAlgo::GitDiffFunctions { .. } = self.algo
+            && let AnalysisIndexStrategyInfo::Always { .. }
+            | AnalysisIndexStrategyInfo::AlwaysAndClean { .. }
+            | AnalysisIndexStrategyInfo::IfAvailable { .. } = self.index
+        {
+            cmd.arg("--functions-index");
+        }
     }
 }
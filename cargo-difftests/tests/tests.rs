@@ -17,6 +17,13 @@
 #![feature(exit_status_error)]
 #![feature(let_chains)]
 
+use std::collections::BTreeMap;
+
+use cargo_difftests::{
+    analysis::{DirtyHunk, LineMapping},
+    nextest::{target_runner_var, NextestTest, NextestTests},
+};
+
 mod test_support;
 use test_support::*;
 
@@ -196,96 +203,192 @@ fn sample_project_test_with_index() -> R {
     )
 }
 
-fn test_git_diff_files(
-    test_name: &'static str,
-    analysis_index_strategy: impl FnOnce(&CargoProject) -> AnalysisIndexStrategyInfo,
-) -> R {
+#[test]
+fn sample_project_test_parallel() -> R {
+    let project = init_sample_project("sample_project_test_parallel")?;
+
+    project.run_all_tests_difftests_with_args(&["--jobs=4"])?;
+
+    let strategy = TestAnalysisStrategyInfo::default();
+
+    for test in ["test_add", "test_sub", "test_mul", "test_div"] {
+        project
+            .analyze_test("tests", test, &strategy)?
+            .assert_is_clean()?;
+    }
+
+    project.touch_file("src/advanced_arithmetic.rs")?;
+
+    project
+        .analyze_test("tests", "test_add", &strategy)?
+        .assert_is_clean()?;
+    project
+        .analyze_test("tests", "test_sub", &strategy)?
+        .assert_is_clean()?;
+    project
+        .analyze_test("tests", "test_mul", &strategy)?
+        .assert_is_dirty()?;
+    project
+        .analyze_test("tests", "test_div", &strategy)?
+        .assert_is_dirty()?;
+
+    Ok(())
+}
+
+#[test]
+fn no_fail_fast_failed_tests_are_dirty() -> R {
     let project = create_cargo_project(
-        test_name,
-        CargoProjectConfig {
-            init_git: true,
-            ..CargoProjectConfig::default()
-        },
+        "no_fail_fast_failed_tests_are_dirty",
+        CargoProjectConfig::default(),
     )?;
-    let repo = project.load_git_repo()?;
 
-    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 { a + b }")?;
+    project.edit(
+        "src/lib.rs",
+        r#"
+pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+pub fn sub(a: i32, b: i32) -> i32 {
+    a - b
+}
+"#,
+    )?;
     project.edit(
         "tests/tests.rs",
         project.test_code(
-            "add",
+            "{add, sub}",
             r#"
-    #[test]
-    fn test_add() {
-        assert_eq!(add(1, 2), 3);
-    }
-    "#,
+#[test]
+fn test_add() {
+    assert_eq!(add(1, 2), 3);
+}
+
+#[test]
+fn test_sub() {
+    assert_eq!(sub(3, 2), 2);
+}
+"#,
         ),
     )?;
-    let strategy = TestAnalysisStrategyInfo {
-        algo: AnalysisAlgo::git_diff_files_with_head(),
-        index: analysis_index_strategy(&project),
-        ..TestAnalysisStrategyInfo::default()
-    };
 
-    let _commit = project.commit(&repo, "Commit 2", ["src/lib.rs", "tests/tests.rs"].iter())?;
+    assert!(project
+        .run_all_tests_difftests_with_args(&["--no-fail-fast"])
+        .is_err());
+
+    let strategy = TestAnalysisStrategyInfo::default();
 
-    project.run_all_tests_difftests()?;
     project
         .analyze_test("tests", "test_add", &strategy)?
         .assert_is_clean()?;
+    project
+        .analyze_test("tests", "test_sub", &strategy)?
+        .assert_is_dirty()?;
+
+    Ok(())
+}
+
+#[test]
+fn no_fail_fast_failed_test_without_profiling_data_is_dirty() -> R {
+    let project = create_cargo_project(
+        "no_fail_fast_failed_test_without_profiling_data_is_dirty",
+        CargoProjectConfig::default(),
+    )?;
 
-    // edit file, so it is different from HEAD
     project.edit(
         "src/lib.rs",
-        "pub fn add(a: i32, b: i32) -> i32 { a + b + 1 }",
+        r#"
+pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+pub fn sub(a: i32, b: i32) -> i32 {
+    a - b
+}
+"#,
+    )?;
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "{add, sub}",
+            r#"
+#[test]
+fn test_add() {
+    assert_eq!(add(1, 2), 3);
+}
+
+#[test]
+fn test_sub() {
+    // aborting skips writing the profiling data
+    if std::env::var_os("ABORT_TEST_SUB").is_some() {
+        std::process::abort();
+    }
+    assert_eq!(sub(3, 2), 1);
+}
+"#,
+        ),
     )?;
 
-    // now the algorithm should tell us that it's dirty
-    project
-        .analyze_test("tests", "test_add", &strategy)?
-        .assert_is_dirty()?;
+    let index_root = project.index_root();
+
+    let collect = |abort: bool| -> R<bool> {
+        let mut cmd = project._internal_cargo_difftests_cmd()?;
+        cmd.arg("collect-profiling-data")
+            .args(["--no-fail-fast", "--compile-index", "--index-root"])
+            .arg(&index_root);
+        if abort {
+            cmd.env("ABORT_TEST_SUB", "1");
+        }
+        Ok(cmd.output()?.status.success())
+    };
 
-    // but if we go back to the old version of the file
+    let verdicts = || -> R<BTreeMap<String, String>> {
+        let invocation = project
+            .cargo_difftests()?
+            .args(["analyze-all-from-index", "--algo", "fs-mtime"])
+            .arg("--index-root")
+            .arg(&index_root);
+        analyze_all_verdicts(&invocation.run_for_stdout()?)
+    };
 
-    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 { a + b }")?;
+    assert!(collect(false)?);
 
-    // it should be clean again
-    project
-        .analyze_test("tests", "test_add", &strategy)?
-        .assert_is_clean()?;
+    let v = verdicts()?;
+    assert_eq!(v["test_add"], "clean");
+    assert_eq!(v["test_sub"], "clean");
+
+    assert!(!collect(true)?);
+
+    // the old index of the test is not kept around as-is
+    let v = verdicts()?;
+    assert_eq!(v["test_add"], "clean");
+    assert_eq!(v["test_sub"], "dirty");
 
     Ok(())
 }
 
 #[test]
-fn test_git_diff_files_no_index() -> R {
-    test_git_diff_files(
-        "test_git_diff_files_no_index",
-        CargoProject::analysis_index_strategy_never,
-    )
-}
+fn analyze_all_reports_test_outcome() -> R {
+    let project = init_sample_project("analyze_all_reports_test_outcome")?;
 
-#[test]
-fn test_git_diff_files_with_index() -> R {
-    test_git_diff_files(
-        "test_git_diff_files_with_index",
-        CargoProject::analysis_index_strategy_always,
-    )
+    project.run_all_tests_difftests()?;
+
+    project
+        .cargo_difftests()?
+        .args(["analyze-all", "--dir"])
+        .arg(project.difftests_root())
+        .stdout_contains(r#""status":"passed""#)
+        .run()?;
+
+    Ok(())
 }
 
-fn test_git_diff_files_with_commit(
-    test_name: &'static str,
-    analysis_index_strategy: impl FnOnce(&CargoProject) -> AnalysisIndexStrategyInfo,
-) -> R {
+#[test]
+fn explain_reports_dirty_reasons() -> R {
     let project = create_cargo_project(
-        test_name,
-        CargoProjectConfig {
-            init_git: true,
-            ..CargoProjectConfig::default()
-        },
+        "explain_reports_dirty_reasons",
+        CargoProjectConfig::default(),
     )?;
-    let repo = project.load_git_repo()?;
 
     project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 { a + b }")?;
     project.edit(
@@ -301,141 +404,1761 @@ fn test_git_diff_files_with_commit(
         ),
     )?;
 
-    let commit = project.commit(&repo, "Commit 2", ["src/lib.rs", "tests/tests.rs"].iter())?;
-
     project.run_all_tests_difftests()?;
 
-    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 {a+b}")?;
-
-    let commit2 = project.commit(&repo, "Commit 3", ["src/lib.rs"].iter())?;
+    project.touch_file("src/lib.rs")?;
 
-    let index = analysis_index_strategy(&project);
+    project
+        .cargo_difftests()?
+        .args(["analyze", "--explain", "--dir"])
+        .arg(project.difftests_dir("tests", "test_add"))
+        .stdout_contains("lib.rs was modified")
+        .run()?;
 
-    let strategy = TestAnalysisStrategyInfo {
-        algo: AnalysisAlgo::git_diff_files_with_commit(commit),
-        index: index.clone(),
-        ..TestAnalysisStrategyInfo::default()
-    };
+    project
+        .cargo_difftests()?
+        .args(["analyze-all", "--explain", "--dir"])
+        .arg(project.difftests_root())
+        .stdout_contains(r#""kind":"file-modified-after-test-run""#)
+        .run()?;
 
-    let strategy2 = TestAnalysisStrategyInfo {
-        algo: AnalysisAlgo::git_diff_files_with_commit(commit2),
-        index,
-        ..TestAnalysisStrategyInfo::default()
-    };
+    Ok(())
+}
 
-    project
-        .analyze_test("tests", "test_add", &strategy2)?
-        .assert_is_clean()?;
+#[test]
+fn who_covers_finds_tests() -> R {
+    let project = init_sample_project("who_covers_finds_tests")?;
 
-    project
-        .analyze_test("tests", "test_add", &strategy)?
-        .assert_is_dirty()?;
+    let index_root = project.index_root();
 
-    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 { a + b }")?;
+    project.run_all_tests_difftests_with_args(&[
+        "--compile-index",
+        "--full-index",
+        "--index-root",
+        index_root.to_str().unwrap(),
+    ])?;
 
     project
-        .analyze_test("tests", "test_add", &strategy)?
-        .assert_is_clean()?;
+        .cargo_difftests()?
+        .args(["who-covers", "--format=json", "--index-root"])
+        .arg(&index_root)
+        .arg("src/advanced_arithmetic.rs")
+        .stdout_contains(r#""test_name":"test_mul""#)
+        .run()?;
+
+    // the body of `mul` is on line 3 of the file, and the body of `div` on line 7
+    project
+        .cargo_difftests()?
+        .args(["who-covers", "--index-root"])
+        .arg(&index_root)
+        .arg("src/advanced_arithmetic.rs:7")
+        .stdout_exact("test_div\t1\n")
+        .run()?;
+
+    // the path is resolved from the current directory and normalized
+    project
+        .cargo_difftests()?
+        .args(["who-covers", "--index-root"])
+        .arg(&index_root)
+        .arg("./src/../src/advanced_arithmetic.rs:7")
+        .stdout_exact("test_div\t1\n")
+        .run()?;
+
+    // and only the exact same file matches, not any file with that suffix
+    project
+        .cargo_difftests()?
+        .args(["who-covers", "--index-root"])
+        .arg(&index_root)
+        .arg("advanced_arithmetic.rs")
+        .stdout_exact("")
+        .run()?;
 
     project
-        .analyze_test("tests", "test_add", &strategy2)?
-        .assert_is_dirty()?;
+        .cargo_difftests()?
+        .args(["who-covers", "--function", "sub", "--index-root"])
+        .arg(&index_root)
+        .stdout_exact("test_sub\t1\n")
+        .run()?;
 
     Ok(())
 }
 
 #[test]
-fn test_git_diff_files_with_commit_no_index() -> R {
-    test_git_diff_files_with_commit(
-        "test_git_diff_files_with_commit_no_index",
-        CargoProject::analysis_index_strategy_never,
-    )
-}
-
-#[test]
-fn test_git_diff_files_with_commit_with_index() -> R {
-    test_git_diff_files_with_commit(
-        "test_git_diff_files_with_commit_with_index",
-        CargoProject::analysis_index_strategy_always,
-    )
-}
-
-fn test_git_diff_hunks(
-    test_name: &'static str,
-    analysis_index_strategy: impl FnOnce(&CargoProject) -> AnalysisIndexStrategyInfo,
-) -> R {
+fn index_db_gives_same_verdicts() -> R {
     let project = create_cargo_project(
-        test_name,
+        "index_db_gives_same_verdicts",
         CargoProjectConfig {
             init_git: true,
             ..CargoProjectConfig::default()
         },
     )?;
+    let repo = project.load_git_repo()?;
 
     project.edit(
         "src/lib.rs",
-        r#"
-pub fn add(a: i32, b: i32) -> i32 {
+        r#"pub fn add(a: i32, b: i32) -> i32 {
     a + b
 }
 
 pub fn sub(a: i32, b: i32) -> i32 {
     a - b
 }
-
-pub fn extra() {
-    println!("extra");
-}
-
 "#,
     )?;
-
     project.edit(
         "tests/tests.rs",
         project.test_code(
             "{add, sub}",
             r#"
-#[test]
-fn test_add() {
-    assert_eq!(add(1, 2), 3);
-}
-
-#[test]
-fn test_sub() {
-    assert_eq!(sub(3, 2), 1);
-}
+    #[test]
+    fn test_add() {
+        assert_eq!(add(1, 2), 3);
+    }
 
-"#,
+    #[test]
+    fn test_sub() {
+        assert_eq!(sub(2, 1), 1);
+    }
+    "#,
         ),
     )?;
 
-    let _commit = project.commit(
-        &project.load_git_repo()?,
-        "Commit 2",
-        ["src/lib.rs", "tests/tests.rs"].iter(),
-    )?;
+    project.commit(&repo, "Commit 2", ["src/lib.rs", "tests/tests.rs"].iter())?;
 
-    project.run_all_tests_difftests()?;
+    let index_root = project.index_root();
 
-    let strategy = TestAnalysisStrategyInfo {
-        algo: AnalysisAlgo::git_diff_hunks_with_head(),
-        index: analysis_index_strategy(&project),
-        ..TestAnalysisStrategyInfo::default()
+    project.run_all_tests_difftests_with_args(&[
+        "--compile-index",
+        "--full-index",
+        "--index-root",
+        index_root.to_str().unwrap(),
+    ])?;
+
+    let verdicts = |algo: &str, index_db: bool| -> R<BTreeMap<String, String>> {
+        let mut invocation = project
+            .cargo_difftests()?
+            .args(["analyze-all-from-index", "--algo", algo, "--index-root"])
+            .arg(&index_root);
+        if index_db {
+            invocation = invocation.arg("--index-db");
+        }
+
+        analyze_all_verdicts(&invocation.run_for_stdout()?)
     };
 
-    project
-        .analyze_test("tests", "test_add", &strategy)?
-        .assert_is_clean()?;
+    let algos = ["git-diff-files", "git-diff-hunks", "git-diff-functions"];
 
-    project
-        .analyze_test("tests", "test_sub", &strategy)?
-        .assert_is_clean()?;
+    for algo in algos {
+        let v = verdicts(algo, true)?;
+        assert_eq!(v, verdicts(algo, false)?);
+        assert_eq!(v["test_add"], "clean");
+        assert_eq!(v["test_sub"], "clean");
+    }
+
+    assert!(index_root.join("cargo_difftests_index_db.json").exists());
+
+    project.edit(
+        "src/lib.rs",
+        r#"pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+pub fn sub(a: i32, b: i32) -> i32 {
+    a - b + 0
+}
+"#,
+    )?;
+
+    for algo in algos {
+        let v = verdicts(algo, true)?;
+        assert_eq!(v, verdicts(algo, false)?);
+        assert_eq!(v["test_sub"], "dirty");
+    }
+
+    assert_eq!(verdicts("git-diff-files", true)?["test_add"], "dirty");
+    assert_eq!(verdicts("git-diff-hunks", true)?["test_add"], "clean");
+
+    // a pure insertion right above a covered function
+    project.edit(
+        "src/lib.rs",
+        r#"pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+#[inline]
+pub fn sub(a: i32, b: i32) -> i32 {
+    a - b
+}
+"#,
+    )?;
+
+    for algo in algos {
+        let v = verdicts(algo, true)?;
+        assert_eq!(v, verdicts(algo, false)?, "{algo}");
+        assert_eq!(v["test_sub"], "dirty", "{algo}");
+    }
+
+    assert_eq!(verdicts("git-diff-hunks", true)?["test_add"], "clean");
+
+    Ok(())
+}
+
+#[test]
+fn analyze_all_git_diff_hunks() -> R {
+    let project = create_cargo_project(
+        "analyze_all_git_diff_hunks",
+        CargoProjectConfig {
+            init_git: true,
+            ..CargoProjectConfig::default()
+        },
+    )?;
+    let repo = project.load_git_repo()?;
+
+    project.edit(
+        "src/lib.rs",
+        r#"pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+pub fn sub(a: i32, b: i32) -> i32 {
+    a - b
+}
+"#,
+    )?;
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "{add, sub}",
+            r#"
+    #[test]
+    fn test_add() {
+        assert_eq!(add(1, 2), 3);
+    }
+
+    #[test]
+    fn test_sub() {
+        assert_eq!(sub(2, 1), 1);
+    }
+    "#,
+        ),
+    )?;
+
+    project.commit(&repo, "Commit 2", ["src/lib.rs", "tests/tests.rs"].iter())?;
+
+    project.run_all_tests_difftests()?;
+
+    project.edit(
+        "src/lib.rs",
+        r#"pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+pub fn sub(a: i32, b: i32) -> i32 {
+    a - b + 0
+}
+"#,
+    )?;
+
+    let verdicts = analyze_all_verdicts(
+        &project
+            .cargo_difftests()?
+            .args(["analyze-all", "--algo", "git-diff-hunks", "--dir"])
+            .arg(project.difftests_root())
+            .run_for_stdout()?,
+    )?;
+
+    assert_eq!(verdicts["test_add"], "clean");
+    assert_eq!(verdicts["test_sub"], "dirty");
+
+    Ok(())
+}
+
+#[test]
+fn test_git_diff_revisions() -> R {
+    let project = create_cargo_project(
+        "test_git_diff_revisions",
+        CargoProjectConfig {
+            init_git: true,
+            ..CargoProjectConfig::default()
+        },
+    )?;
+    let repo = project.load_git_repo()?;
+
+    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 { a + b }")?;
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "add",
+            r#"
+    #[test]
+    fn test_add() {
+        assert_eq!(add(1, 2), 3);
+    }
+    "#,
+        ),
+    )?;
+
+    let commit = project.commit(&repo, "Commit 2", ["src/lib.rs", "tests/tests.rs"].iter())?;
+    repo.branch("base", &repo.find_commit(commit)?, false)?;
+
+    project.run_all_tests_difftests()?;
+
+    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 { a + b + 0 }")?;
+    project.commit(&repo, "Commit 3", ["src/lib.rs"].iter())?;
+
+    let analyze = |args: &[&str], expected: &str| -> R {
+        project
+            .cargo_difftests()?
+            .args(["analyze", "--algo", "git-diff-files", "--dir"])
+            .arg(project.difftests_dir("tests", "test_add"))
+            .args(args)
+            .stdout_exact(expected)
+            .run()
+    };
+
+    analyze(&["--commit", "HEAD"], "clean\n")?;
+    analyze(&["--commit", "HEAD~1"], "dirty\n")?;
+    analyze(&["--commit", "base"], "dirty\n")?;
+    analyze(&["--merge-base-with", "base"], "dirty\n")?;
+
+    // revert the change in the working tree only
+    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 { a + b }")?;
+
+    analyze(&["--commit", "HEAD~1"], "clean\n")?;
+    analyze(&["--commit", "HEAD~1", "--committed-only"], "dirty\n")?;
+    analyze(&["--commit", "HEAD", "--committed-only"], "clean\n")?;
+    // diffs from the commit the test was run at
+    analyze(&["--committed-only"], "dirty\n")?;
+
+    Ok(())
+}
+
+#[test]
+fn test_git_diff_from_recorded_commit() -> R {
+    let project = create_cargo_project(
+        "test_git_diff_from_recorded_commit",
+        CargoProjectConfig {
+            init_git: true,
+            ..CargoProjectConfig::default()
+        },
+    )?;
+    let repo = project.load_git_repo()?;
+
+    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 { a + b }")?;
+    project.edit("src/unused.rs", "pub fn unused() {}")?;
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "add",
+            r#"
+    #[test]
+    fn test_add() {
+        assert_eq!(add(1, 2), 3);
+    }
+    "#,
+        ),
+    )?;
+
+    project.commit(
+        &repo,
+        "Commit 2",
+        ["src/lib.rs", "src/unused.rs", "tests/tests.rs"].iter(),
+    )?;
+
+    project.run_all_tests_difftests()?;
+
+    let analyze = |args: &[&str], expected: &str| -> R {
+        project
+            .cargo_difftests()?
+            .args(["analyze", "--algo", "git-diff-files", "--dir"])
+            .arg(project.difftests_dir("tests", "test_add"))
+            .args(args)
+            .stdout_exact(expected)
+            .run()
+    };
+
+    project.edit("src/unused.rs", "pub fn unused() { let _ = 1; }")?;
+    project.commit(&repo, "Commit 3", ["src/unused.rs"].iter())?;
+
+    analyze(&[], "clean\n")?;
+
+    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 { a + b + 0 }")?;
+    project.commit(&repo, "Commit 4", ["src/lib.rs"].iter())?;
+
+    // the change was committed after the test was run, so it is still dirty
+    analyze(&[], "dirty\n")?;
+    analyze(&["--commit", "HEAD"], "clean\n")?;
+
+    Ok(())
+}
+
+#[test]
+fn test_git_diff_moves_between_crates() -> R {
+    let project = create_cargo_project(
+        "test_git_diff_moves_between_crates",
+        CargoProjectConfig {
+            init_git: true,
+            ..CargoProjectConfig::default()
+        },
+    )?;
+    let repo = project.load_git_repo()?;
+
+    project.edit(
+        "Cargo.toml",
+        r#"
+[package]
+name = "test_git_diff_moves_between_crates"
+version = "0.1.0"
+edition = "2021"
+
+[dependencies]
+helper = { path = "helper" }
+
+[workspace]
+"#,
+    )?;
+    project.edit(
+        "helper/Cargo.toml",
+        r#"
+[package]
+name = "helper"
+version = "0.1.0"
+edition = "2021"
+"#,
+    )?;
+    project.edit("helper/src/lib.rs", "mod math;\npub use math::*;\n")?;
+    let math = |mul: &str, div: &str| {
+        format!(
+            r#"pub fn mul(a: i32, b: i32) -> i32 {{
+    {mul}
+}}
+
+pub fn div(a: i32, b: i32) -> i32 {{
+    {div}
+}}
+
+pub fn rem(a: i32, b: i32) -> i32 {{
+    a % b
+}}
+
+pub fn neg(a: i32) -> i32 {{
+    -a
+}}
+"#
+        )
+    };
+    project.edit("helper/src/math.rs", math("a * b", "a / b"))?;
+    project.edit(
+        "src/lib.rs",
+        "pub use helper::*;\n\npub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n",
+    )?;
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "{add,mul}",
+            r#"
+    #[test]
+    fn test_add() {
+        assert_eq!(add(1, 2), 3);
+    }
+
+    #[test]
+    fn test_mul() {
+        assert_eq!(mul(2, 3), 6);
+    }
+    "#,
+        ),
+    )?;
+
+    project.commit(
+        &repo,
+        "Commit 2",
+        [
+            "Cargo.toml",
+            "helper/Cargo.toml",
+            "helper/src/lib.rs",
+            "helper/src/math.rs",
+            "src/lib.rs",
+            "tests/tests.rs",
+        ]
+        .iter(),
+    )?;
+
+    project.run_all_tests_difftests()?;
+
+    let analyze = |algo: &str, test: &str, expected: &str| -> R {
+        project
+            .cargo_difftests()?
+            .args(["analyze", "--algo", algo, "--dir"])
+            .arg(project.difftests_dir("tests", test))
+            .stdout_exact(expected)
+            .run()
+    };
+
+    // move the math module from the helper crate to the main one
+    project.git_mv(&repo, "helper/src/math.rs", "src/math.rs")?;
+    project.edit("helper/src/lib.rs", "")?;
+    project.edit(
+        "src/lib.rs",
+        "pub use helper::*;\nmod math;\npub use math::*;\n\npub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n",
+    )?;
+
+    // the file the test touched was moved
+    analyze("git-diff-files", "test_mul", "dirty\n")?;
+    analyze("git-diff-files", "test_add", "dirty\n")?;
+    // but none of the code it executed changed
+    analyze("git-diff-hunks", "test_mul", "clean\n")?;
+    analyze("git-diff-functions", "test_mul", "clean\n")?;
+    analyze("git-diff-hunks", "test_add", "clean\n")?;
+
+    // the hunks in the moved file are checked against the old one
+    project.edit("src/math.rs", math("a * b", "a / b + 0"))?;
+
+    analyze("git-diff-hunks", "test_mul", "clean\n")?;
+
+    project.edit("src/math.rs", math("a * b + 0", "a / b + 0"))?;
+
+    analyze("git-diff-hunks", "test_mul", "dirty\n")?;
+    analyze("git-diff-functions", "test_mul", "dirty\n")?;
+    analyze("git-diff-hunks", "test_add", "clean\n")?;
+
+    Ok(())
+}
+
+#[test]
+fn test_git_diff_semantic() -> R {
+    let project = create_cargo_project(
+        "test_git_diff_semantic",
+        CargoProjectConfig {
+            init_git: true,
+            ..CargoProjectConfig::default()
+        },
+    )?;
+    let repo = project.load_git_repo()?;
+
+    project.edit(
+        "src/lib.rs",
+        r#"pub fn add(a: i32, b: i32) -> i32 { a + b }
+
+pub fn sub(a: i32, b: i32) -> i32 { a - b }
+"#,
+    )?;
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "add",
+            r#"
+    #[test]
+    fn test_add() {
+        assert_eq!(add(1, 2), 3);
+    }
+    "#,
+        ),
+    )?;
+
+    project.commit(&repo, "Commit 2", ["src/lib.rs", "tests/tests.rs"].iter())?;
+
+    project.run_all_tests_difftests()?;
+
+    let analyze = |algo: &str, expected: &str| -> R {
+        project
+            .cargo_difftests()?
+            .args(["analyze", "--algo", algo, "--dir"])
+            .arg(project.difftests_dir("tests", "test_add"))
+            .stdout_exact(expected)
+            .run()
+    };
+
+    // reformat, and add some comments
+    project.edit(
+        "src/lib.rs",
+        r#"//! Arithmetic.
+
+/// Adds two numbers.
+pub fn add(a: i32, b: i32) -> i32 {
+    // the sum
+    a + b /* of both */
+}
+
+pub fn sub(a: i32, b: i32) -> i32 {
+    a - b
+}
+"#,
+    )?;
+
+    analyze("git-diff-files", "dirty\n")?;
+    analyze("git-diff-semantic", "clean\n")?;
+
+    // an actual change, along with the formatting ones
+    project.edit(
+        "src/lib.rs",
+        r#"//! Arithmetic.
+
+/// Adds two numbers.
+pub fn add(a: i32, b: i32) -> i32 {
+    // the sum
+    a + b /* of both */
+}
+
+pub fn sub(a: i32, b: i32) -> i32 {
+    b - a
+}
+"#,
+    )?;
+
+    analyze("git-diff-semantic", "dirty\n")?;
+
+    Ok(())
+}
+
+#[test]
+fn test_tracked_files() -> R {
+    let project = create_cargo_project(
+        "test_tracked_files",
+        CargoProjectConfig {
+            init_git: true,
+            need_deps: vec!["cargo-difftests-testclient".to_owned()],
+        },
+    )?;
+    let repo = project.load_git_repo()?;
+
+    project.edit(
+        "src/lib.rs",
+        "pub fn parse(s: &str) -> i32 { s.trim().parse().unwrap() }\n",
+    )?;
+    project.edit("fixtures/answer.txt", "42\n")?;
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "parse",
+            r#"
+    #[test]
+    fn test_fixture() {
+        cargo_difftests_testclient::track_file("fixtures/answer.txt").unwrap();
+        let answer = std::fs::read_to_string("fixtures/answer.txt").unwrap();
+        assert_eq!(parse(&answer), 42);
+    }
+
+    #[test]
+    fn test_inline() {
+        assert_eq!(parse("42"), 42);
+    }
+    "#,
+        ),
+    )?;
+
+    project.commit(
+        &repo,
+        "Commit 2",
+        ["src/lib.rs", "fixtures/answer.txt", "tests/tests.rs"].iter(),
+    )?;
+
+    let index_root = project.index_root();
+
+    project.run_all_tests_difftests_with_args(&[
+        "--compile-index",
+        "--full-index",
+        "--index-root",
+        index_root.to_str().unwrap(),
+    ])?;
+
+    let algos = [
+        "fs-mtime",
+        "content-hash",
+        "region-fingerprints",
+        "git-diff-files",
+        "git-diff-hunks",
+        "git-diff-functions",
+        "git-diff-semantic",
+    ];
+
+    let verdicts = |algo: &str, index_db: bool| -> R<BTreeMap<String, String>> {
+        let mut invocation = project
+            .cargo_difftests()?
+            .args(["analyze-all-from-index", "--algo", algo, "--index-root"])
+            .arg(&index_root);
+        if index_db {
+            invocation = invocation.arg("--index-db");
+        }
+
+        analyze_all_verdicts(&invocation.run_for_stdout()?)
+    };
+
+    for algo in algos {
+        let v = verdicts(algo, false)?;
+        assert_eq!(v["test_fixture"], "clean", "{algo}");
+        assert_eq!(v["test_inline"], "clean", "{algo}");
+    }
+
+    // the fixture contains no code, but the test declared it as an input
+    project.edit("fixtures/answer.txt", "42\n43\n")?;
+
+    for algo in algos {
+        let v = verdicts(algo, false)?;
+        assert_eq!(v["test_fixture"], "dirty", "{algo}");
+        assert_eq!(v["test_inline"], "clean", "{algo}");
+
+        if algo.starts_with("git-diff") {
+            assert_eq!(v, verdicts(algo, true)?, "{algo}");
+        }
+    }
+
+    // also from the coverage data, without an index
+    project
+        .cargo_difftests()?
+        .args(["analyze", "--algo", "git-diff-hunks", "--dir"])
+        .arg(project.difftests_dir("tests", "test_fixture"))
+        .stdout_exact("dirty\n")
+        .run()?;
+
+    // a removed fixture has no mtime, but still makes the test dirty
+    project.remove("fixtures/answer.txt")?;
+
+    for algo in ["fs-mtime", "content-hash"] {
+        let v = verdicts(algo, false)?;
+        assert_eq!(v["test_fixture"], "dirty", "{algo}");
+        assert_eq!(v["test_inline"], "clean", "{algo}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_always_run() -> R {
+    let project = create_cargo_project(
+        "test_always_run",
+        CargoProjectConfig {
+            init_git: true,
+            need_deps: vec!["cargo-difftests-testclient".to_owned()],
+        },
+    )?;
+    let repo = project.load_git_repo()?;
+
+    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 { a + b }\n")?;
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "add",
+            r#"
+    #[test]
+    fn test_add() {
+        assert_eq!(add(1, 2), 3);
+    }
+
+    #[test]
+    fn test_smoke() {
+        cargo_difftests_testclient::always_run().unwrap();
+        assert_eq!(add(2, 2), 4);
+    }
+
+    #[test]
+    fn test_network() {
+        assert_eq!(add(3, 2), 5);
+    }
+    "#,
+        ),
+    )?;
+
+    project.commit(&repo, "Commit 2", ["src/lib.rs", "tests/tests.rs"].iter())?;
+
+    let index_root = project.index_root();
+
+    project.run_all_tests_difftests_with_args(&[
+        "--compile-index",
+        "--index-root",
+        index_root.to_str().unwrap(),
+    ])?;
+
+    let analyze = |algo: &str, index_db: bool, always_run: &[&str]| -> R<String> {
+        let mut invocation = project
+            .cargo_difftests()?
+            .args(["analyze-all-from-index", "--algo", algo, "--index-root"])
+            .arg(&index_root);
+        if index_db {
+            invocation = invocation.arg("--index-db");
+        }
+        for pattern in always_run {
+            invocation = invocation.args(["--always-run", *pattern]);
+        }
+
+        invocation.run_for_stdout()
+    };
+
+    for (algo, index_db) in [
+        ("content-hash", false),
+        ("git-diff-files", false),
+        ("git-diff-files", true),
+    ] {
+        let v = analyze_all_verdicts(&analyze(algo, index_db, &[])?)?;
+        assert_eq!(v["test_add"], "clean", "{algo}");
+        assert_eq!(v["test_smoke"], "dirty", "{algo}");
+        assert_eq!(v["test_network"], "clean", "{algo}");
+
+        let stdout = analyze(algo, index_db, &["*network*"])?;
+        let v = analyze_all_verdicts(&stdout)?;
+        assert_eq!(v["test_add"], "clean", "{algo}");
+        assert_eq!(v["test_smoke"], "dirty", "{algo}");
+        assert_eq!(v["test_network"], "dirty", "{algo}");
+        assert_eq!(stdout.matches(r#""forced":true"#).count(), 2, "{stdout}");
+    }
+
+    // the tests that must always run do not fail `assert-clean`
+    let output = project
+        ._internal_cargo_difftests_cmd()?
+        .args(["analyze-all-from-index", "--action", "assert-clean", "--index-root"])
+        .arg(&index_root)
+        .output()?;
+    assert!(output.status.success());
+
+    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 { b + a }\n")?;
+
+    let output = project
+        ._internal_cargo_difftests_cmd()?
+        .args(["analyze-all-from-index", "--action", "assert-clean", "--index-root"])
+        .arg(&index_root)
+        .output()?;
+    assert!(!output.status.success());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_trace_file_access() -> R {
+    let project = create_cargo_project(
+        "test_trace_file_access",
+        CargoProjectConfig {
+            init_git: true,
+            ..CargoProjectConfig::default()
+        },
+    )?;
+    let repo = project.load_git_repo()?;
+
+    project.edit(
+        "src/lib.rs",
+        "pub fn parse(s: &str) -> i32 { s.trim().parse().unwrap() }\n",
+    )?;
+    project.edit("fixtures/answer.txt", "42\n")?;
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "parse",
+            r#"
+    #[test]
+    fn test_fixture() {
+        // from another thread, which is traced as well
+        let answer = std::thread::spawn(|| std::fs::read_to_string("fixtures/answer.txt"))
+            .join()
+            .unwrap()
+            .unwrap();
+        assert_eq!(parse(&answer), 42);
+    }
+
+    #[test]
+    fn test_inline() {
+        assert_eq!(parse("42"), 42);
+    }
+    "#,
+        ),
+    )?;
+
+    project.commit(
+        &repo,
+        "Commit 2",
+        ["src/lib.rs", "fixtures/answer.txt", "tests/tests.rs"].iter(),
+    )?;
+
+    project.run_all_tests_difftests_with_args(&["--trace-file-access"])?;
+
+    let analyze = |test: &str, algo: &str, expected: &str| -> R {
+        project
+            .cargo_difftests()?
+            .args(["analyze", "--algo", algo, "--dir"])
+            .arg(project.difftests_dir("tests", test))
+            .stdout_exact(expected)
+            .run()
+    };
+
+    analyze("test_fixture", "git-diff-hunks", "clean\n")?;
+
+    project.edit("fixtures/answer.txt", "42\n43\n")?;
+
+    analyze("test_fixture", "git-diff-hunks", "dirty\n")?;
+    analyze("test_fixture", "fs-mtime", "dirty\n")?;
+    analyze("test_inline", "git-diff-hunks", "clean\n")?;
+    analyze("test_inline", "fs-mtime", "clean\n")?;
+
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_trace_file_access_parallel() -> R {
+    let project = create_cargo_project(
+        "test_trace_file_access_parallel",
+        CargoProjectConfig::default(),
+    )?;
+
+    project.edit(
+        "src/lib.rs",
+        "pub fn parse(s: &str) -> i32 { s.trim().parse().unwrap() }\n",
+    )?;
+
+    let tests = ["a", "b", "c", "d"];
+
+    let mut test_code = String::new();
+    for test in tests {
+        project.edit(format!("fixtures/{test}.txt"), "42\n")?;
+        test_code.push_str(&format!(
+            r#"
+    #[test]
+    fn test_{test}() {{
+        let answer = std::fs::read_to_string("fixtures/{test}.txt").unwrap();
+        assert_eq!(parse(&answer), 42);
+    }}
+    "#
+        ));
+    }
+    project.edit("tests/tests.rs", project.test_code("parse", test_code))?;
+
+    // every worker traces the tests it spawned, while the others
+    // trace theirs at the same time
+    project.run_all_tests_difftests_with_args(&["--trace-file-access", "--jobs", "4"])?;
+
+    for test in tests {
+        project
+            .cargo_difftests()?
+            .args(["analyze", "--algo", "fs-mtime", "--dir"])
+            .arg(project.difftests_dir("tests", &format!("test_{test}")))
+            .stdout_exact("clean\n")
+            .run()?;
+    }
+
+    project.edit("fixtures/b.txt", "42\n43\n")?;
+
+    for test in tests {
+        let expected = if test == "b" { "dirty\n" } else { "clean\n" };
+
+        project
+            .cargo_difftests()?
+            .args(["analyze", "--algo", "fs-mtime", "--dir"])
+            .arg(project.difftests_dir("tests", &format!("test_{test}")))
+            .stdout_exact(expected)
+            .run()?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_env_fingerprint() -> R {
+    let project = create_cargo_project(
+        "test_env_fingerprint",
+        CargoProjectConfig {
+            init_git: true,
+            ..CargoProjectConfig::default()
+        },
+    )?;
+    let repo = project.load_git_repo()?;
+
+    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 { a + b }\n")?;
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "add",
+            r#"
+    #[test]
+    fn test_add() {
+        assert_eq!(add(1, 2), 3);
+    }
+    "#,
+        ),
+    )?;
+
+    project.commit(&repo, "Commit 2", ["src/lib.rs", "tests/tests.rs"].iter())?;
+
+    let index_root = project.index_root();
+
+    project
+        .cargo_difftests()?
+        .args([
+            "collect-profiling-data",
+            "--compile-index",
+            "--index-root",
+            index_root.to_str().unwrap(),
+            "--fingerprint-env",
+            "DIFFTESTS_TEST_MODE",
+        ])
+        .env("DIFFTESTS_TEST_MODE", "fast")
+        .run()?;
+
+    let verdict = |mode: Option<&str>, index_db: bool| -> R<String> {
+        let mut invocation = project
+            .cargo_difftests()?
+            .args([
+                "analyze-all-from-index",
+                "--algo",
+                "git-diff-files",
+                "--explain",
+                "--index-root",
+            ])
+            .arg(&index_root)
+            .env_remove("DIFFTESTS_TEST_MODE");
+        if let Some(mode) = mode {
+            invocation = invocation.env("DIFFTESTS_TEST_MODE", mode);
+        }
+        if index_db {
+            invocation = invocation.arg("--index-db");
+        }
+
+        let stdout = invocation.run_for_stdout()?;
+        if !stdout.contains(r#""verdict":"clean""#) {
+            assert!(stdout.contains(r#""kind":"environment-changed""#), "{stdout}");
+        }
+
+        Ok(analyze_all_verdicts(&stdout)?["test_add"].clone())
+    };
+
+    for index_db in [false, true] {
+        assert_eq!(verdict(Some("fast"), index_db)?, "clean");
+        assert_eq!(verdict(Some("slow"), index_db)?, "dirty");
+        assert_eq!(verdict(None, index_db)?, "dirty");
+    }
+
+    // bumping the version changes the lockfile
+    let manifest = project.read("Cargo.toml")?;
+    project.edit(
+        "Cargo.toml",
+        manifest.replacen(r#"version = "0.1.0""#, r#"version = "0.2.0""#, 1),
+    )?;
+
+    for index_db in [false, true] {
+        assert_eq!(verdict(Some("fast"), index_db)?, "dirty");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_dependency_bump() -> R {
+    let project = create_cargo_project(
+        "test_dependency_bump",
+        CargoProjectConfig {
+            init_git: true,
+            ..CargoProjectConfig::default()
+        },
+    )?;
+    let repo = project.load_git_repo()?;
+
+    let manifest = project.read("Cargo.toml")?;
+    project.edit(
+        "Cargo.toml",
+        format!("{manifest}\n[dependencies]\nanstyle = \"=1.0.4\"\n"),
+    )?;
+    project.edit(
+        "src/lib.rs",
+        r#"
+pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+pub fn bold(s: &str) -> String {
+    let style = anstyle::Style::new().bold();
+    format!("{}{s}{}", style.render(), style.render_reset())
+}
+"#,
+    )?;
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "{add, bold}",
+            r#"
+    #[test]
+    fn test_add() {
+        assert_eq!(add(1, 2), 3);
+    }
+
+    #[test]
+    fn test_bold() {
+        assert!(bold("x").contains('x'));
+    }
+    "#,
+        ),
+    )?;
+
+    project.commit(
+        &repo,
+        "Commit 2",
+        ["Cargo.toml", "src/lib.rs", "tests/tests.rs"].iter(),
+    )?;
+
+    let index_root = project.index_root();
+
+    project.run_all_tests_difftests_with_args(&[
+        "--instrument-dependencies",
+        "--compile-index",
+        "--index-root",
+        index_root.to_str().unwrap(),
+    ])?;
+
+    let verdicts = |index_db: bool| -> R<BTreeMap<String, String>> {
+        let mut invocation = project
+            .cargo_difftests()?
+            .args([
+                "analyze-all-from-index",
+                "--algo",
+                "git-diff-files",
+                "--explain",
+                "--index-root",
+            ])
+            .arg(&index_root);
+        if index_db {
+            invocation = invocation.arg("--index-db");
+        }
+
+        let stdout = invocation.run_for_stdout()?;
+        let verdicts = analyze_all_verdicts(&stdout)?;
+        if verdicts["test_bold"] == "dirty" {
+            assert!(
+                stdout.contains(
+                    r#"{"kind":"dependency-changed","dependency":{"source":"registry","name":"anstyle","version":"1.0.4"}}"#
+                ),
+                "{stdout}"
+            );
+        }
+
+        Ok(verdicts)
+    };
+
+    for index_db in [false, true] {
+        let verdicts = verdicts(index_db)?;
+        assert_eq!(verdicts["test_add"], "clean");
+        assert_eq!(verdicts["test_bold"], "clean");
+    }
+
+    // the lockfile is updated by `cargo metadata`, when analyzing
+    let manifest = project.read("Cargo.toml")?;
+    project.edit("Cargo.toml", manifest.replace("=1.0.4", "=1.0.14"))?;
+
+    for index_db in [false, true] {
+        let verdicts = verdicts(index_db)?;
+        assert_eq!(verdicts["test_add"], "clean");
+        assert_eq!(verdicts["test_bold"], "dirty");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_config_file() -> R {
+    let project = create_cargo_project("test_config_file", CargoProjectConfig::default())?;
+
+    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 { a + b }\n")?;
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "add",
+            r#"
+    #[test]
+    fn test_add() {
+        assert_eq!(add(1, 2), 3);
+    }
+    "#,
+        ),
+    )?;
+
+    project.edit(
+        "difftests.toml",
+        "index-root = \"index_root\"\nalgo = \"content-hash\"\n",
+    )?;
+
+    project.run_all_tests_difftests_with_args(&["--compile-index"])?;
+    assert!(project.index_root().is_dir());
+
+    let verdicts = || -> R<BTreeMap<String, String>> {
+        let stdout = project
+            .cargo_difftests()?
+            .arg("analyze-all-from-index")
+            .run_for_stdout()?;
+        analyze_all_verdicts(&stdout)
+    };
+
+    assert_eq!(verdicts()?["test_add"], "clean");
+    // `content-hash` does not care about the mtime
+    project.touch_file("src/lib.rs")?;
+    assert_eq!(verdicts()?["test_add"], "clean");
+
+    // the command line takes precedence
+    let stdout = project
+        .cargo_difftests()?
+        .args(["analyze-all-from-index", "--algo", "fs-mtime"])
+        .run_for_stdout()?;
+    assert_eq!(analyze_all_verdicts(&stdout)?["test_add"], "dirty");
+
+    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 { b + a }\n")?;
+    assert_eq!(verdicts()?["test_add"], "dirty");
+
+    project.edit("difftests.toml", "index_root = \"index_root\"\n")?;
+    project
+        .cargo_difftests()?
+        .arg("analyze-all-from-index")
+        .stderr_contains("unknown field `index_root`")
+        .run()?;
+
+    project.edit("difftests.toml", "algo = \"git-diff-everything\"\n")?;
+    project
+        .cargo_difftests()?
+        .arg("analyze-all-from-index")
+        .stderr_contains("unknown algo `git-diff-everything`")
+        .run()?;
+
+    // or in `Cargo.toml`
+    let manifest = project.read("Cargo.toml")?;
+    project.edit(
+        "Cargo.toml",
+        format!("{manifest}\n[workspace.metadata.difftests]\nindex-root = \"index_root\"\n"),
+    )?;
+    project
+        .cargo_difftests()?
+        .arg("analyze-all-from-index")
+        .stderr_contains("only one of them can be used")
+        .run()?;
+
+    project.remove("difftests.toml")?;
+    assert_eq!(verdicts()?["test_add"], "dirty");
+
+    Ok(())
+}
+
+#[test]
+fn test_path_filter() -> R {
+    let project = create_cargo_project("test_path_filter", CargoProjectConfig::default())?;
+
+    project.edit(
+        "src/lib.rs",
+        "mod util;\n\npub fn add(a: i32, b: i32) -> i32 {\n    util::sum(a, b)\n}\n",
+    )?;
+    project.edit(
+        "src/util.rs",
+        "pub fn sum(a: i32, b: i32) -> i32 {\n    a + b\n}\n",
+    )?;
+    project.edit("data/input.txt", "1 2\n")?;
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "add",
+            r#"
+    #[test]
+    fn test_add() {
+        assert_eq!(add(1, 2), 3);
+    }
+    "#,
+        ),
+    )?;
+
+    project.edit(
+        "difftests.toml",
+        "index-root = \"index_root\"\n\
+         algo = \"content-hash\"\n\
+         exclude = [\"src/util.rs\"]\n\
+         always-dirty = [\"data/*.txt\"]\n",
+    )?;
+
+    project.run_all_tests_difftests_with_args(&["--compile-index"])?;
+
+    let verdicts = || -> R<BTreeMap<String, String>> {
+        let stdout = project
+            .cargo_difftests()?
+            .args(["analyze-all-from-index", "--explain"])
+            .run_for_stdout()?;
+        analyze_all_verdicts(&stdout)
+    };
+
+    assert_eq!(verdicts()?["test_add"], "clean");
+
+    // excluded, so not in the index
+    project.edit(
+        "src/util.rs",
+        "pub fn sum(a: i32, b: i32) -> i32 {\n    b + a\n}\n",
+    )?;
+    assert_eq!(verdicts()?["test_add"], "clean");
+
+    // only the mtime changes, and the always dirty files are compared
+    // with the hashes recorded when the test was run
+    project.touch_file("data/input.txt")?;
+    assert_eq!(verdicts()?["test_add"], "clean");
+
+    project.edit("data/input.txt", "2 1\n")?;
+    assert_eq!(verdicts()?["test_add"], "dirty");
+
+    project.edit("difftests.toml", "exclude = [\"src/[\"]\n")?;
+    project
+        .cargo_difftests()?
+        .arg("analyze-all-from-index")
+        .stderr_contains("invalid glob pattern `src/[`")
+        .run()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_content_hash() -> R {
+    let project = create_cargo_project("test_content_hash", CargoProjectConfig::default())?;
+
+    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 { a + b }")?;
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "add",
+            r#"
+    #[test]
+    fn test_add() {
+        assert_eq!(add(1, 2), 3);
+    }
+    "#,
+        ),
+    )?;
+
+    project.run_all_tests_difftests()?;
+
+    let strategy = TestAnalysisStrategyInfo {
+        algo: AnalysisAlgo::ContentHash,
+        index: project.analysis_index_strategy_always(),
+    };
+
+    project
+        .analyze_test("tests", "test_add", &strategy)?
+        .assert_is_clean()?;
+
+    // only the mtime changes, so it should still be clean
+    project.touch_file("src/lib.rs")?;
+
+    project
+        .analyze_test("tests", "test_add", &strategy)?
+        .assert_is_clean()?;
+
+    project.edit(
+        "src/lib.rs",
+        "pub fn add(a: i32, b: i32) -> i32 { a + b + 1 }",
+    )?;
+
+    project
+        .analyze_test("tests", "test_add", &strategy)?
+        .assert_is_dirty()?;
+
+    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 { a + b }")?;
+
+    project
+        .analyze_test("tests", "test_add", &strategy)?
+        .assert_is_clean()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_region_fingerprints() -> R {
+    let project = create_cargo_project("test_region_fingerprints", CargoProjectConfig::default())?;
+
+    project.edit(
+        "src/lib.rs",
+        r#"
+pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+pub fn sub(a: i32, b: i32) -> i32 {
+    a - b
+}
+"#,
+    )?;
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "add",
+            r#"
+    #[test]
+    fn test_add() {
+        assert_eq!(add(1, 2), 3);
+    }
+    "#,
+        ),
+    )?;
+
+    project.run_all_tests_difftests()?;
+
+    let strategy = TestAnalysisStrategyInfo {
+        algo: AnalysisAlgo::RegionFingerprints,
+        index: project.analysis_index_strategy_always(),
+    };
+
+    project
+        .analyze_test("tests", "test_add", &strategy)?
+        .assert_is_clean()?;
+
+    // shifts `add` down and re-indents it, and changes `sub`, which
+    // the test doesn't execute, so it should still be clean
+    project.edit(
+        "src/lib.rs",
+        r#"
+pub fn mul(a: i32, b: i32) -> i32 {
+    a * b
+}
+
+pub fn add(a: i32, b: i32) -> i32 {
+        a + b
+}
+
+pub fn sub(a: i32, b: i32) -> i32 {
+    a - b - 0
+}
+"#,
+    )?;
+
+    project
+        .analyze_test("tests", "test_add", &strategy)?
+        .assert_is_clean()?;
+
+    project.edit(
+        "src/lib.rs",
+        r#"
+pub fn add(a: i32, b: i32) -> i32 {
+    a + b + 1
+}
+
+pub fn sub(a: i32, b: i32) -> i32 {
+    a - b
+}
+"#,
+    )?;
+
+    project
+        .analyze_test("tests", "test_add", &strategy)?
+        .assert_is_dirty()?;
+
+    Ok(())
+}
+
+fn test_git_diff_files(
+    test_name: &'static str,
+    analysis_index_strategy: impl FnOnce(&CargoProject) -> AnalysisIndexStrategyInfo,
+) -> R {
+    let project = create_cargo_project(
+        test_name,
+        CargoProjectConfig {
+            init_git: true,
+            ..CargoProjectConfig::default()
+        },
+    )?;
+    let repo = project.load_git_repo()?;
+
+    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 { a + b }")?;
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "add",
+            r#"
+    #[test]
+    fn test_add() {
+        assert_eq!(add(1, 2), 3);
+    }
+    "#,
+        ),
+    )?;
+    let strategy = TestAnalysisStrategyInfo {
+        algo: AnalysisAlgo::git_diff_files_with_head(),
+        index: analysis_index_strategy(&project),
+        ..TestAnalysisStrategyInfo::default()
+    };
+
+    let _commit = project.commit(&repo, "Commit 2", ["src/lib.rs", "tests/tests.rs"].iter())?;
+
+    project.run_all_tests_difftests()?;
+    project
+        .analyze_test("tests", "test_add", &strategy)?
+        .assert_is_clean()?;
+
+    // edit file, so it is different from HEAD
+    project.edit(
+        "src/lib.rs",
+        "pub fn add(a: i32, b: i32) -> i32 { a + b + 1 }",
+    )?;
+
+    // now the algorithm should tell us that it's dirty
+    project
+        .analyze_test("tests", "test_add", &strategy)?
+        .assert_is_dirty()?;
+
+    // but if we go back to the old version of the file
+
+    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 { a + b }")?;
+
+    // it should be clean again
+    project
+        .analyze_test("tests", "test_add", &strategy)?
+        .assert_is_clean()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_git_diff_files_no_index() -> R {
+    test_git_diff_files(
+        "test_git_diff_files_no_index",
+        CargoProject::analysis_index_strategy_never,
+    )
+}
+
+#[test]
+fn test_git_diff_files_with_index() -> R {
+    test_git_diff_files(
+        "test_git_diff_files_with_index",
+        CargoProject::analysis_index_strategy_always,
+    )
+}
+
+fn test_git_diff_files_with_commit(
+    test_name: &'static str,
+    analysis_index_strategy: impl FnOnce(&CargoProject) -> AnalysisIndexStrategyInfo,
+) -> R {
+    let project = create_cargo_project(
+        test_name,
+        CargoProjectConfig {
+            init_git: true,
+            ..CargoProjectConfig::default()
+        },
+    )?;
+    let repo = project.load_git_repo()?;
+
+    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 { a + b }")?;
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "add",
+            r#"
+    #[test]
+    fn test_add() {
+        assert_eq!(add(1, 2), 3);
+    }
+    "#,
+        ),
+    )?;
+
+    let commit = project.commit(&repo, "Commit 2", ["src/lib.rs", "tests/tests.rs"].iter())?;
+
+    project.run_all_tests_difftests()?;
+
+    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 {a+b}")?;
+
+    let commit2 = project.commit(&repo, "Commit 3", ["src/lib.rs"].iter())?;
+
+    let index = analysis_index_strategy(&project);
+
+    let strategy = TestAnalysisStrategyInfo {
+        algo: AnalysisAlgo::git_diff_files_with_commit(commit),
+        index: index.clone(),
+        ..TestAnalysisStrategyInfo::default()
+    };
+
+    let strategy2 = TestAnalysisStrategyInfo {
+        algo: AnalysisAlgo::git_diff_files_with_commit(commit2),
+        index,
+        ..TestAnalysisStrategyInfo::default()
+    };
+
+    project
+        .analyze_test("tests", "test_add", &strategy2)?
+        .assert_is_clean()?;
+
+    project
+        .analyze_test("tests", "test_add", &strategy)?
+        .assert_is_dirty()?;
+
+    project.edit("src/lib.rs", "pub fn add(a: i32, b: i32) -> i32 { a + b }")?;
+
+    project
+        .analyze_test("tests", "test_add", &strategy)?
+        .assert_is_clean()?;
+
+    project
+        .analyze_test("tests", "test_add", &strategy2)?
+        .assert_is_dirty()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_git_diff_files_with_commit_no_index() -> R {
+    test_git_diff_files_with_commit(
+        "test_git_diff_files_with_commit_no_index",
+        CargoProject::analysis_index_strategy_never,
+    )
+}
+
+#[test]
+fn test_git_diff_files_with_commit_with_index() -> R {
+    test_git_diff_files_with_commit(
+        "test_git_diff_files_with_commit_with_index",
+        CargoProject::analysis_index_strategy_always,
+    )
+}
+
+fn test_git_diff_hunks(
+    test_name: &'static str,
+    analysis_index_strategy: impl FnOnce(&CargoProject) -> AnalysisIndexStrategyInfo,
+) -> R {
+    let project = create_cargo_project(
+        test_name,
+        CargoProjectConfig {
+            init_git: true,
+            ..CargoProjectConfig::default()
+        },
+    )?;
+
+    project.edit(
+        "src/lib.rs",
+        r#"
+pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+pub fn sub(a: i32, b: i32) -> i32 {
+    a - b
+}
+
+pub fn extra() {
+    println!("extra");
+}
+
+"#,
+    )?;
+
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "{add, sub}",
+            r#"
+#[test]
+fn test_add() {
+    assert_eq!(add(1, 2), 3);
+}
+
+#[test]
+fn test_sub() {
+    assert_eq!(sub(3, 2), 1);
+}
+
+"#,
+        ),
+    )?;
+
+    let _commit = project.commit(
+        &project.load_git_repo()?,
+        "Commit 2",
+        ["src/lib.rs", "tests/tests.rs"].iter(),
+    )?;
+
+    project.run_all_tests_difftests()?;
+
+    let strategy = TestAnalysisStrategyInfo {
+        algo: AnalysisAlgo::git_diff_hunks_with_head(),
+        index: analysis_index_strategy(&project),
+        ..TestAnalysisStrategyInfo::default()
+    };
+
+    project
+        .analyze_test("tests", "test_add", &strategy)?
+        .assert_is_clean()?;
+
+    project
+        .analyze_test("tests", "test_sub", &strategy)?
+        .assert_is_clean()?;
+
+    project.edit(
+        "src/lib.rs",
+        r#"
+pub fn add(a: i32, b: i32) -> i32 {
+    // add a comment here
+    a + b
+}
+
+pub fn sub(a: i32, b: i32) -> i32 {
+    a - b
+}
+
+pub fn extra() {
+    println!("extra");
+}
+
+"#,
+    )?;
+
+    project
+        .analyze_test("tests", "test_add", &strategy)?
+        .assert_is_dirty()?;
+    project
+        .analyze_test("tests", "test_sub", &strategy)?
+        .assert_is_clean()?;
 
     project.edit(
         "src/lib.rs",
         r#"
 pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+pub fn sub(a: i32, b: i32) -> i32 {
     // add a comment here
+    a - b
+}
+
+pub fn extra() {
+    println!("extra");
+}
+
+"#,
+    )?;
+
+    project
+        .analyze_test("tests", "test_add", &strategy)?
+        .assert_is_clean()?;
+
+    project
+        .analyze_test("tests", "test_sub", &strategy)?
+        .assert_is_dirty()?;
+
+    project.edit(
+        "src/lib.rs",
+        r#"
+pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+pub fn sub(a: i32, b: i32) -> i32 {
+    a - b
+}
+
+pub fn extra() {
+    println!("extra");
+    // add a few comments to parts unused by both tests
+    // add a few comments to parts unused by both tests
+}
+
+    "#,
+    )?;
+
+    project
+        .analyze_test("tests", "test_add", &strategy)?
+        .assert_is_clean()?;
+
+    project
+        .analyze_test("tests", "test_sub", &strategy)?
+        .assert_is_clean()?;
+
+    // go back to original state
+    project.edit(
+        "src/lib.rs",
+        r#"
+pub fn add(a: i32, b: i32) -> i32 {
     a + b
 }
 
@@ -443,16 +2166,100 @@ pub fn sub(a: i32, b: i32) -> i32 {
     a - b
 }
 
-pub fn extra() {
-    println!("extra");
+pub fn extra() {
+    println!("extra");
+}
+
+"#,
+    )?;
+
+    project
+        .analyze_test("tests", "test_add", &strategy)?
+        .assert_is_clean()?;
+
+    project
+        .analyze_test("tests", "test_sub", &strategy)?
+        .assert_is_clean()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_git_diff_hunks_no_index() -> R {
+    test_git_diff_hunks(
+        "test_git_diff_hunks_no_index",
+        CargoProject::analysis_index_strategy_never,
+    )
+}
+
+#[test]
+fn test_git_diff_hunks_with_index() -> R {
+    test_git_diff_hunks(
+        "test_git_diff_hunks_with_index",
+        CargoProject::analysis_index_strategy_always,
+    )
+}
+
+fn test_git_diff_functions(
+    test_name: &'static str,
+    analysis_index_strategy: impl FnOnce(&CargoProject) -> AnalysisIndexStrategyInfo,
+) -> R {
+    let project = create_cargo_project(
+        test_name,
+        CargoProjectConfig {
+            init_git: true,
+            ..CargoProjectConfig::default()
+        },
+    )?;
+
+    project.edit(
+        "src/lib.rs",
+        r#"
+pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+pub fn sub(a: i32, b: i32) -> i32 {
+    a - b
+}
+"#,
+    )?;
+
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "{add, sub}",
+            r#"
+#[test]
+fn test_add() {
+    assert_eq!(add(1, 2), 3);
+}
+
+#[test]
+fn test_sub() {
+    assert_eq!(sub(3, 2), 1);
 }
-
 "#,
+        ),
+    )?;
+
+    let _commit = project.commit(
+        &project.load_git_repo()?,
+        "Commit 2",
+        ["src/lib.rs", "tests/tests.rs"].iter(),
     )?;
 
+    project.run_all_tests_difftests()?;
+
+    let strategy = TestAnalysisStrategyInfo {
+        algo: AnalysisAlgo::git_diff_functions_with_head(),
+        index: analysis_index_strategy(&project),
+    };
+
     project
         .analyze_test("tests", "test_add", &strategy)?
-        .assert_is_dirty()?;
+        .assert_is_clean()?;
+
     project
         .analyze_test("tests", "test_sub", &strategy)?
         .assert_is_clean()?;
@@ -465,14 +2272,8 @@ pub fn add(a: i32, b: i32) -> i32 {
 }
 
 pub fn sub(a: i32, b: i32) -> i32 {
-    // add a comment here
-    a - b
-}
-
-pub fn extra() {
-    println!("extra");
+    a - b + 0
 }
-
 "#,
     )?;
 
@@ -484,76 +2285,581 @@ pub fn extra() {
         .analyze_test("tests", "test_sub", &strategy)?
         .assert_is_dirty()?;
 
-    project.edit(
-        "src/lib.rs",
-        r#"
-pub fn add(a: i32, b: i32) -> i32 {
-    a + b
+    project
+        .cargo_difftests()?
+        .args(["analyze", "--explain", "--algo=git-diff-functions", "--dir"])
+        .arg(project.difftests_dir("tests", "test_sub"))
+        .stdout_contains("::sub` changed")
+        .run()?;
+
+    Ok(())
 }
 
-pub fn sub(a: i32, b: i32) -> i32 {
-    a - b
+#[test]
+fn test_git_diff_functions_no_index() -> R {
+    test_git_diff_functions(
+        "test_git_diff_functions_no_index",
+        CargoProject::analysis_index_strategy_never,
+    )
 }
 
-pub fn extra() {
-    println!("extra");
-    // add a few comments to parts unused by both tests
-    // add a few comments to parts unused by both tests
+#[test]
+fn test_git_diff_functions_with_index() -> R {
+    test_git_diff_functions(
+        "test_git_diff_functions_with_index",
+        CargoProject::analysis_index_strategy_always,
+    )
+}
+
+/// A small xorshift generator, so that the random cases are reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+#[test]
+fn line_mapping_matches_real_edits() -> R {
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let mut fresh = 0;
+
+    for case in 0..500 {
+        let old_len = rng.below(30);
+        let old = (1..=old_len).map(|l| format!("old {l}")).collect::<Vec<_>>();
+
+        // the lines of the new file, with the old line they were, if any
+        let mut new = old
+            .iter()
+            .enumerate()
+            .map(|(i, l)| (l.clone(), Some(i + 1)))
+            .collect::<Vec<_>>();
+
+        for _ in 0..rng.below(6) {
+            match rng.below(3) {
+                0 => {
+                    let at = rng.below(new.len() + 1);
+                    for _ in 0..1 + rng.below(3) {
+                        fresh += 1;
+                        new.insert(at, (format!("new {fresh}"), None));
+                    }
+                }
+                1 if !new.is_empty() => {
+                    let at = rng.below(new.len());
+                    let n = (1 + rng.below(3)).min(new.len() - at);
+                    new.drain(at..at + n);
+                }
+                2 if !new.is_empty() => {
+                    let at = rng.below(new.len());
+                    fresh += 1;
+                    new[at] = (format!("new {fresh}"), None);
+                }
+                _ => {}
+            }
+        }
+
+        // the oracle: where each old line ended up, if it is still there
+        let mut expected = vec![None; old_len + 1];
+        for (i, (_, old_line)) in new.iter().enumerate() {
+            if let Some(old_line) = old_line {
+                expected[*old_line] = Some(i + 1);
+            }
+        }
+
+        let old_text = old.iter().map(|l| format!("{l}\n")).collect::<String>();
+        let new_text = new.iter().map(|(l, _)| format!("{l}\n")).collect::<String>();
+
+        let mut diff_options = git2::DiffOptions::new();
+        diff_options.context_lines(0);
+        let patch = git2::Patch::from_buffers(
+            old_text.as_bytes(),
+            None,
+            new_text.as_bytes(),
+            None,
+            Some(&mut diff_options),
+        )?;
+        let hunks = (0..patch.num_hunks())
+            .map(|i| Ok(DirtyHunk::from(&patch.hunk(i)?.0)))
+            .collect::<R<Vec<_>>>()?;
+
+        let line_mapping = LineMapping::new(&hunks);
+
+        for (line, &expected_line) in expected.iter().enumerate().skip(1) {
+            assert_eq!(
+                line_mapping.map_line(line),
+                expected_line,
+                "case {case}: line {line}, hunks {hunks:?}"
+            );
+        }
+
+        // whether lines were only inserted (nothing was removed)
+        // right before the new line `new_line`
+        let inserted_before = |old_line: usize, new_line: usize| {
+            (old_line == 1 || expected[old_line - 1].is_some())
+                && new_line > 1
+                && new[new_line - 2].1.is_none()
+        };
+
+        for l1 in 1..=old_len {
+            for l2 in l1..=old_len {
+                // touched if any of the lines is gone, or lines were inserted
+                // between them, or right before or after them
+                let touched = match (expected[l1], expected[l2]) {
+                    (Some(e1), Some(e2)) if (l1..=l2).all(|l| expected[l].is_some()) => {
+                        e2 - e1 != l2 - l1
+                            || inserted_before(l1, e1)
+                            || (l2 == old_len || expected[l2 + 1].is_some())
+                                && new.get(e2).is_some_and(|(_, old_line)| old_line.is_none())
+                    }
+                    _ => true,
+                };
+
+                assert_eq!(
+                    line_mapping.lines_touched(l1, l2),
+                    touched,
+                    "case {case}: lines {l1}..={l2}, hunks {hunks:?}"
+                );
+                assert_eq!(
+                    line_mapping.hunks_touching(l1, l2).next().is_some(),
+                    touched,
+                    "case {case}: hunks touching lines {l1}..={l2}, hunks {hunks:?}"
+                );
+            }
+        }
+    }
+
+    Ok(())
 }
 
+#[test]
+fn test_package_selection() -> R {
+    let project = create_cargo_project("test_package_selection", CargoProjectConfig::default())?;
+
+    project.edit(
+        "src/lib.rs",
+        "pub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n\n#[cfg(test)]\nmod tests {\n    #[test]\n    fn test_unit() {\n        assert_eq!(super::add(1, 2), 3);\n    }\n}\n",
+    )?;
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "add",
+            r#"
+    #[test]
+    fn test_add() {
+        assert_eq!(add(1, 2), 3);
+    }
+    "#,
+        ),
+    )?;
+    project.edit(
+        "tests/other.rs",
+        project.test_code(
+            "add",
+            r#"
+    #[test]
+    fn test_other() {
+        assert_eq!(add(2, 2), 4);
+    }
     "#,
+        ),
     )?;
 
-    project
-        .analyze_test("tests", "test_add", &strategy)?
-        .assert_is_clean()?;
+    project.run_all_tests_difftests_with_args(&["--test", "other"])?;
 
-    project
-        .analyze_test("tests", "test_sub", &strategy)?
-        .assert_is_clean()?;
+    let exists = |harness: &str, name: &str| project.exists(project.difftests_dir(harness, name));
+
+    assert!(exists("other", "test_other"));
+    assert!(!exists("tests", "test_add"));
+    assert!(!exists("test_package_selection", "tests::test_unit"));
+
+    project.run_all_tests_difftests_with_args(&["-p", "test_package_selection", "--lib"])?;
+
+    assert!(exists("test_package_selection", "tests::test_unit"));
+    assert!(!exists("tests", "test_add"));
+
+    Ok(())
+}
+
+#[test]
+fn test_doctests() -> R {
+    let project = create_cargo_project(
+        "test_doctests",
+        CargoProjectConfig {
+            init_git: true,
+            need_deps: vec![],
+        },
+    )?;
+    let repo = project.load_git_repo()?;
 
-    // go back to original state
     project.edit(
         "src/lib.rs",
-        r#"
-pub fn add(a: i32, b: i32) -> i32 {
-    a + b
-}
+        "mod sub;\n\npub use sub::sub;\n\n/// ```\n/// assert_eq!(test_doctests::add(1, 2), 3);\n/// ```\npub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n",
+    )?;
+    // a doctest that fails when its binary is run on its own
+    project.edit(
+        "src/sub.rs",
+        "/// ```should_panic\n/// assert_eq!(test_doctests::sub(3, 2), 2);\n/// ```\npub fn sub(a: i32, b: i32) -> i32 {\n    a - b\n}\n",
+    )?;
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "sub",
+            r#"
+    #[test]
+    fn test_sub() {
+        assert_eq!(sub(3, 2), 1);
+    }
+    "#,
+        ),
+    )?;
 
-pub fn sub(a: i32, b: i32) -> i32 {
-    a - b
-}
+    project.commit(
+        &repo,
+        "Commit 2",
+        ["src/lib.rs", "src/sub.rs", "tests/tests.rs"].iter(),
+    )?;
 
-pub fn extra() {
-    println!("extra");
+    let index_root = project.index_root();
+
+    project.run_all_tests_difftests_with_args(&[
+        "--doctests",
+        "--compile-index",
+        "--index-root",
+        index_root.to_str().unwrap(),
+    ])?;
+
+    let doctest = "src/lib.rs - add (line 5)";
+    assert!(project.exists(project.difftests_dir("doctests", doctest)));
+
+    let verdicts = || -> R<BTreeMap<String, String>> {
+        let invocation = project
+            .cargo_difftests()?
+            .args(["analyze-all-from-index", "--algo", "git-diff-hunks", "--index-root"])
+            .arg(&index_root);
+        analyze_all_verdicts(&invocation.run_for_stdout()?)
+    };
+
+    let v = verdicts()?;
+    assert_eq!(v[doctest], "clean");
+    assert_eq!(v["test_sub"], "clean");
+    let should_panic = v
+        .keys()
+        .find(|name| name.starts_with("src/sub.rs - "))
+        .expect("the should_panic doctest was not collected")
+        .clone();
+    assert_eq!(v[&should_panic], "clean");
+
+    project.edit(
+        "src/sub.rs",
+        "/// ```should_panic\n/// assert_eq!(test_doctests::sub(3, 2), 2);\n/// ```\npub fn sub(a: i32, b: i32) -> i32 {\n    a + -b\n}\n",
+    )?;
+
+    let v = verdicts()?;
+    assert_eq!(v[doctest], "clean");
+    assert_eq!(v["test_sub"], "dirty");
+    assert_eq!(v[&should_panic], "dirty");
+
+    project.edit(
+        "src/lib.rs",
+        "mod sub;\n\npub use sub::sub;\n\n/// ```\n/// assert_eq!(test_doctests::add(1, 2), 3);\n/// ```\npub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}\n",
+    )?;
+
+    let v = verdicts()?;
+    assert_eq!(v[doctest], "dirty");
+
+    Ok(())
 }
 
-"#,
+#[test]
+fn git_diff_same_file_in_two_crates() -> R {
+    let project = create_cargo_project(
+        "git_diff_same_file_in_two_crates",
+        CargoProjectConfig {
+            init_git: true,
+            ..CargoProjectConfig::default()
+        },
     )?;
+    let repo = project.load_git_repo()?;
 
-    project
-        .analyze_test("tests", "test_add", &strategy)?
-        .assert_is_clean()?;
+    let cargo_toml = project.read("Cargo.toml")?.replace(
+        "[workspace.dependencies]",
+        "[dependencies]\nother = { path = \"other\" }\n\n[workspace]\nmembers = [\"other\"]\n\n[workspace.dependencies]",
+    );
+    project.edit("Cargo.toml", cargo_toml)?;
+    project.edit(
+        "other/Cargo.toml",
+        "[package]\nname = \"other\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+    )?;
+    project.edit(
+        "other/src/lib.rs",
+        "pub fn mul(a: i32, b: i32) -> i32 {\n    a * b\n}\n",
+    )?;
+    project.edit(
+        "src/lib.rs",
+        "pub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n",
+    )?;
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "add",
+            r#"
+    #[test]
+    fn test_add() {
+        assert_eq!(add(1, 2), 3);
+    }
 
-    project
-        .analyze_test("tests", "test_sub", &strategy)?
-        .assert_is_clean()?;
+    #[test]
+    fn test_mul() {
+        assert_eq!(other::mul(2, 3), 6);
+    }
+    "#,
+        ),
+    )?;
+
+    project.commit(
+        &repo,
+        "Commit 2",
+        [
+            "Cargo.toml",
+            "other/Cargo.toml",
+            "other/src/lib.rs",
+            "src/lib.rs",
+            "tests/tests.rs",
+        ]
+        .iter(),
+    )?;
+
+    let index_root = project.index_root();
+
+    project.run_all_tests_difftests_with_args(&[
+        "--compile-index",
+        "--full-index",
+        "--index-root",
+        index_root.to_str().unwrap(),
+    ])?;
+
+    let verdicts = |algo: &str, index_db: bool| -> R<BTreeMap<String, String>> {
+        let mut invocation = project
+            .cargo_difftests()?
+            .args(["analyze-all-from-index", "--algo", algo, "--index-root"])
+            .arg(&index_root);
+        if index_db {
+            invocation = invocation.arg("--index-db");
+        }
+
+        analyze_all_verdicts(&invocation.run_for_stdout()?)
+    };
+
+    // only the `src/lib.rs` of the root crate changes
+    project.edit(
+        "src/lib.rs",
+        "pub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}\n",
+    )?;
+
+    for algo in ["git-diff-files", "git-diff-hunks", "git-diff-functions"] {
+        for index_db in [false, true] {
+            let v = verdicts(algo, index_db)?;
+            assert_eq!(v["test_add"], "dirty", "{algo}, index db: {index_db}");
+            assert_eq!(v["test_mul"], "clean", "{algo}, index db: {index_db}");
+        }
+    }
 
     Ok(())
 }
 
 #[test]
-fn test_git_diff_hunks_no_index() -> R {
-    test_git_diff_hunks(
-        "test_git_diff_hunks_no_index",
-        CargoProject::analysis_index_strategy_never,
-    )
+fn nextest_tests_test() {
+    let mut tests = NextestTests::default();
+    let binary = std::path::PathBuf::from("/target/debug/deps/tests-0123456789abcdef");
+    let test_add = NextestTest {
+        difftest_dir: std::path::PathBuf::from("difftests/tests/tests::test_add"),
+        prepared_dir: std::path::PathBuf::from("prepared/tests/tests::test_add"),
+    };
+    tests
+        .dirs
+        .entry(binary.clone())
+        .or_default()
+        .insert("tests::test_add".to_owned(), test_add.clone());
+
+    let args = |args: &[&str]| {
+        args.iter()
+            .map(std::ffi::OsString::from)
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        tests.test(
+            &binary,
+            &args(&["--exact", "tests::test_add", "--nocapture"])
+        ),
+        Some(&test_add)
+    );
+    // another test of the same binary
+    assert_eq!(
+        tests.test(&binary, &args(&["--exact", "tests::test_sub"])),
+        None
+    );
+    // nextest listing the tests
+    assert_eq!(
+        tests.test(&binary, &args(&["--list", "--format", "terse"])),
+        None
+    );
+    // a test with the same name in another binary
+    assert_eq!(
+        tests.test(
+            std::path::Path::new("/target/debug/deps/other-0123456789abcdef"),
+            &args(&["--exact", "tests::test_add"])
+        ),
+        None
+    );
 }
 
 #[test]
-fn test_git_diff_hunks_with_index() -> R {
-    test_git_diff_hunks(
-        "test_git_diff_hunks_with_index",
-        CargoProject::analysis_index_strategy_always,
-    )
+fn nextest_target_runner_var() {
+    assert_eq!(
+        target_runner_var("x86_64-unknown-linux-gnu"),
+        "CARGO_TARGET_X86_64_UNKNOWN_LINUX_GNU_RUNNER"
+    );
+    assert_eq!(
+        target_runner_var("thumbv7em-none-eabihf"),
+        "CARGO_TARGET_THUMBV7EM_NONE_EABIHF_RUNNER"
+    );
+}
+
+#[test]
+fn test_nextest_rerun_dirty() -> R {
+    let nextest_installed = std::process::Command::new(env!("CARGO"))
+        .args(["nextest", "--version"])
+        .output()
+        .is_ok_and(|output| output.status.success());
+    if !nextest_installed {
+        eprintln!("cargo-nextest is not installed, skipping");
+        return Ok(());
+    }
+
+    let project = create_cargo_project(
+        "test_nextest_rerun_dirty",
+        CargoProjectConfig {
+            init_git: true,
+            ..CargoProjectConfig::default()
+        },
+    )?;
+    let repo = project.load_git_repo()?;
+
+    project.edit(
+        "src/lib.rs",
+        "pub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n\npub fn mul(a: i32, b: i32) -> i32 {\n    a * b\n}\n",
+    )?;
+    // a test with the same name in both test binaries
+    project.edit(
+        "tests/tests.rs",
+        project.test_code(
+            "add",
+            r#"
+    #[test]
+    fn test_same_name() {
+        assert_eq!(add(1, 2), 3);
+    }
+    "#,
+        ),
+    )?;
+    project.edit(
+        "tests/other.rs",
+        project.test_code(
+            "mul",
+            r#"
+    #[test]
+    fn test_same_name() {
+        assert_eq!(mul(2, 3), 6);
+    }
+    "#,
+        ),
+    )?;
+
+    project.commit(
+        &repo,
+        "Commit 2",
+        ["src/lib.rs", "tests/tests.rs", "tests/other.rs"].iter(),
+    )?;
+
+    let index_root = project.index_root();
+
+    // the wrapper, the rerunner and `cargo difftests` itself are found
+    // in the PATH
+    let bin_dir = std::path::Path::new(env!("CARGO_BIN_EXE_cargo-difftests"))
+        .parent()
+        .unwrap();
+    let path = std::env::join_paths(std::iter::once(bin_dir.to_path_buf()).chain(
+        std::env::split_paths(&std::env::var_os("PATH").unwrap_or_default()),
+    ))?;
+
+    let output = project
+        ._internal_cargo_difftests_cmd()?
+        .args([
+            "collect-profiling-data",
+            "--test-runner",
+            "nextest",
+            "--compile-index",
+            "--index-root",
+        ])
+        .arg(&index_root)
+        .env("PATH", &path)
+        .output()?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let tests_dir = project.difftests_dir("tests", "test_same_name");
+    let other_dir = project.difftests_dir("other", "test_same_name");
+    assert!(project.exists(&tests_dir));
+    assert!(project.exists(&other_dir));
+
+    // the difftest directories of the tests that are rerun are recreated,
+    // without the marker
+    project.edit(tests_dir.join("marker"), "")?;
+    project.edit(other_dir.join("marker"), "")?;
+
+    project.edit(
+        "src/lib.rs",
+        "pub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n\npub fn mul(a: i32, b: i32) -> i32 {\n    b * a\n}\n",
+    )?;
+
+    let output = project
+        ._internal_cargo_difftests_cmd()?
+        .args([
+            "rerun-dirty-from-indexes",
+            "--algo",
+            "git-diff-hunks",
+            "--index-root",
+        ])
+        .arg(&index_root)
+        .arg("--runner")
+        .arg(env!("CARGO_BIN_EXE_cargo-difftests-nextest-rerunner"))
+        .env("PATH", &path)
+        .env(
+            "CARGO_DIFFTESTS_EXTRA_ARGS",
+            format!("--compile-index,--index-root={}", index_root.display()),
+        )
+        .output()?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    assert!(project.exists(tests_dir.join("marker")));
+    assert!(!project.exists(other_dir.join("marker")));
+    assert!(project.exists(&other_dir));
+
+    Ok(())
 }